@@ -0,0 +1,359 @@
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{fchown, MetadataExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use nix::unistd::geteuid;
+use serde::Deserialize;
+use strfmt::{strfmt, FmtError};
+use tokio::sync::Notify;
+use tokio::task;
+use tracing::{debug, error, info};
+
+use crate::command::{CommandSpec, KnockVars, Runner};
+use crate::grants::{Grant, SharedGrants};
+use crate::unix_now;
+
+/// An `[[allowlist]]` section of door's config: a file door keeps up to date
+/// with the active grants, for things like nginx `allow` includes or
+/// hosts.allow that gate on a file rather than the firewall.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct AllowlistSettings {
+    pub path: String,
+    /// formatted once per grant with the usual {variables}, plus {expires}
+    pub line: String,
+    pub header: String,
+    pub footer: String,
+    /// only grants for these doors go in the file; empty means all of them
+    pub doors: Vec<String>,
+    /// run after the file changes, e.g. `nginx -s reload`
+    pub reload: Option<CommandSpec>,
+}
+
+impl Default for AllowlistSettings {
+    fn default() -> Self {
+        AllowlistSettings {
+            path: String::new(),
+            line: "{ip}".to_string(),
+            header: String::new(),
+            footer: String::new(),
+            doors: vec![],
+            reload: None,
+        }
+    }
+}
+
+impl AllowlistSettings {
+    /// the file as it should be for these grants; the same line twice (e.g.,
+    /// an IP with grants on two doors) only goes in once
+    pub fn render(&self, grants: &[Grant]) -> Result<String, FmtError> {
+        let mut lines: Vec<String> = vec![];
+        for g in grants {
+            if !self.doors.is_empty() && !self.doors.contains(&g.vars.door) {
+                continue;
+            }
+            let mut map = g.vars.to_map();
+            map.insert("expires".to_string(), g.expires.to_string());
+            let line = strfmt(&self.line, &map)?;
+            if !lines.contains(&line) {
+                lines.push(line);
+            }
+        }
+        lines.sort();
+
+        let mut ret = String::new();
+        for part in [&self.header, &lines.join("\n"), &self.footer] {
+            if !part.is_empty() {
+                ret.push_str(part.trim_end_matches('\n'));
+                ret.push('\n');
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// tells apart the temp files of writes that overlap
+static TEMP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Replace `path` with `contents` such that readers see either the old file or
+/// the new one, never half of each. The new file gets the old one's mode, and
+/// its owner if we're root. Returns false (and doesn't touch the file) if it
+/// already had those contents.
+///
+/// This blocks; from async code, go through `spawn_blocking`.
+pub fn write_atomic(path: &str, contents: &str) -> io::Result<bool> {
+    if fs::read_to_string(path).map(|v| v == contents).unwrap_or(false) {
+        return Ok(false);
+    }
+    let p = Path::new(path);
+    let name = p
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{path} isn't a file name")))?;
+    let old = match fs::metadata(p) {
+        Ok(v) => Some(v),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    // a name nobody else has, and a file only we made (O_EXCL): anyone can
+    // leave things lying around in a directory like /etc/nginx
+    let (tmp, mut f) = loop {
+        let tmp = p.with_file_name(format!(
+            ".{}.{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id(),
+            TEMP_SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(f) => break (tmp, f),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    let res = (|| {
+        if let Some(m) = &old {
+            f.set_permissions(m.permissions())?;
+            // only root can give a file away; once privsep has dropped us
+            // to some other user, the file is ours from now on
+            let ours = f.metadata()?;
+            if (ours.uid(), ours.gid()) != (m.uid(), m.gid()) && geteuid().is_root() {
+                fchown(&f, Some(m.uid()), Some(m.gid()))?;
+            }
+        }
+        f.write_all(contents.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&tmp, p)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res.map(|_| true)
+}
+
+/// The allowlist files, and a way to tell the task that writes them that the
+/// grants have changed. Changes that arrive within `debounce` of each other
+/// get written (and reloaded) once. The files themselves can be swapped out
+/// by a config reload.
+#[derive(Debug, Clone)]
+pub struct Allowlists {
+    lists: Arc<RwLock<Vec<AllowlistSettings>>>,
+    changed: Arc<Notify>,
+    debounce: Duration,
+}
+
+impl Allowlists {
+    pub fn new(lists: Vec<AllowlistSettings>, debounce: Duration) -> Self {
+        Allowlists {
+            lists: Arc::new(RwLock::new(lists)),
+            changed: Arc::new(Notify::new()),
+            debounce,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.read().expect("allowlists lock").is_empty()
+    }
+
+    /// use a new set of files from now on (the old ones are left as they are)
+    pub fn replace(&self, lists: Vec<AllowlistSettings>) {
+        let changed = *self.lists.read().expect("allowlists lock") != lists;
+        *self.lists.write().expect("allowlists lock") = lists;
+        if changed {
+            self.changed.notify_one();
+        }
+    }
+
+    /// the grants changed; the files will catch up after the debounce
+    pub fn changed(&self) {
+        if !self.is_empty() {
+            self.changed.notify_one();
+        }
+    }
+
+    /// write every file for the current grants, and reload the ones that changed
+    pub async fn sync(&self, grants: &SharedGrants, runner: &Runner) {
+        let active = grants.lock().expect("grants lock").list();
+        let lists = self.lists.read().expect("allowlists lock").to_owned();
+
+        for list in lists.iter() {
+            let contents = match list.render(&active) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("allowlist({}) bad line template: {}", list.path, e);
+                    continue;
+                }
+            };
+            let (path, new) = (list.path.to_owned(), contents.to_owned());
+            let written = task::spawn_blocking(move || write_atomic(&path, &new))
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            match written {
+                Ok(false) => debug!("allowlist({}) unchanged", list.path),
+                Ok(true) => {
+                    info!("allowlist({}) updated, {} lines", list.path, contents.lines().count());
+                    if let Some(reload) = &list.reload {
+                        // there's no knock to speak of, so the {variables} aren't much use here
+                        let nowhere = SocketAddr::from(([0, 0, 0, 0], 0));
+                        let vars = KnockVars::new(&nowhere, &nowhere, 0, unix_now());
+                        if let Err(e) = runner.run(reload, &vars).await {
+                            error!(
+                                "allowlist({}) reload {} failed: {}",
+                                list.path,
+                                reload.describe(&vars),
+                                e
+                            );
+                        }
+                    }
+                }
+                Err(e) => error!("allowlist({}) couldn't write: {}", list.path, e),
+            }
+        }
+    }
+
+    /// keep the files in sync with the grants, forever; they're written once
+    /// right away, so nothing a previous door left behind lingers
+    pub async fn run(self, grants: SharedGrants, runner: Runner) {
+        loop {
+            self.sync(&grants, &runner).await;
+            self.changed.notified().await;
+            tokio::time::sleep(self.debounce).await;
+        }
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grants::Grants;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn grants() -> SharedGrants {
+        let g = Grants::shared(0);
+        for (ip, door) in [("10.1.2.4", "default"), ("10.1.2.3", "default"), ("10.1.2.3", "git")] {
+            let src: SocketAddr = format!("{ip}:5555").parse().unwrap();
+            let local: SocketAddr = "0.0.0.0:20022".parse().unwrap();
+            let mut vars = KnockVars::new(&src, &local, 5, 1000);
+            vars.door = door.to_string();
+            g.lock().unwrap().decide(&src, &vars, 1000);
+        }
+        g
+    }
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rknock-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn rendering() {
+        let g = grants().lock().unwrap().list();
+        let mut a = AllowlistSettings {
+            line: "allow {ip}; # {door} until {expires}".to_string(),
+            footer: "deny all;".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            a.render(&g).unwrap(),
+            "allow 10.1.2.3; # default until 1005\n\
+             allow 10.1.2.3; # git until 1005\n\
+             allow 10.1.2.4; # default until 1005\n\
+             deny all;\n"
+        );
+
+        a.line = "sshd: {ip}".to_string();
+        a.footer = String::new();
+        assert_eq!(a.render(&g).unwrap(), "sshd: 10.1.2.3\nsshd: 10.1.2.4\n");
+
+        a.doors = vec!["git".to_string()];
+        assert_eq!(a.render(&g).unwrap(), "sshd: 10.1.2.3\n");
+
+        assert_eq!(a.render(&[]).unwrap(), "");
+        a.line = "{nope}".to_string();
+        assert!(a.render(&g).is_err());
+    }
+
+    #[test]
+    fn atomic_writes() {
+        let path = temp_path("allow.conf");
+
+        assert!(write_atomic(&path, "one\n").unwrap());
+        assert!(!write_atomic(&path, "one\n").unwrap());
+        assert!(write_atomic(&path, "two\n").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two\n");
+        assert!(write_atomic("/nonexistent/dir/allow.conf", "x").is_err());
+
+        // the mode carries over, and a file sitting where the next temp
+        // file would go doesn't get written through
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let squatter = temp_path(&format!(
+            ".allow.conf.{}.{}.tmp",
+            std::process::id(),
+            TEMP_SEQ.load(Ordering::Relaxed)
+        ));
+        fs::write(&squatter, "mine\n").unwrap();
+        assert!(write_atomic(&path, "three\n").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        assert_eq!(fs::read_to_string(&squatter).unwrap(), "mine\n");
+        let dir = Path::new(&path).parent().unwrap();
+        let left = fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".allow.conf.")
+            })
+            .count();
+        assert_eq!(left, 1);
+    }
+
+    #[tokio::test]
+    async fn syncing() {
+        let path = temp_path("hosts.allow");
+        let marker = temp_path("reloaded");
+        let lists = Allowlists::new(
+            vec![AllowlistSettings {
+                path: path.to_owned(),
+                line: "sshd: {ip}".to_string(),
+                reload: Some(CommandSpec::Argv(vec!["touch".to_string(), marker.to_owned()])),
+                ..Default::default()
+            }],
+            Duration::ZERO,
+        );
+        let g = grants();
+        let runner = Runner::new(1, Duration::from_secs(5));
+
+        lists.sync(&g, &runner).await;
+        assert_eq!(fs::read_to_string(&path).unwrap(), "sshd: 10.1.2.3\nsshd: 10.1.2.4\n");
+        assert!(Path::new(&marker).exists());
+
+        g.lock().unwrap().expire(2000);
+        fs::remove_file(&marker).unwrap();
+        lists.sync(&g, &runner).await;
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert!(Path::new(&marker).exists());
+
+        fs::remove_file(&marker).unwrap();
+        lists.sync(&g, &runner).await;
+        assert!(!Path::new(&marker).exists());
+
+        let other = temp_path("other.allow");
+        lists.replace(vec![AllowlistSettings {
+            path: other.to_owned(),
+            line: "allow {ip};".to_string(),
+            ..Default::default()
+        }]);
+        lists.sync(&g, &runner).await;
+        assert_eq!(fs::read_to_string(&other).unwrap(), "");
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::events::RejectReason;
+use crate::payload::parse_duration;
+use crate::pipeline::StepResult;
+use crate::unix_now;
+
+/// The `[audit]` section of door's config: where to keep the audit log, and
+/// when to rotate it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct AuditSettings {
+    /// empty means no audit log
+    pub path: String,
+    /// start a new file when the current one would grow past this many
+    /// bytes; 0 means never
+    pub max_size: u64,
+    /// how many rotated files (path.1 is the newest) to keep
+    pub keep: usize,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        AuditSettings {
+            path: String::new(),
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+impl AuditSettings {
+    /// the log and whatever rotated files there are, oldest first
+    pub fn files(&self) -> Vec<String> {
+        let mut ret = (1..=self.keep)
+            .rev()
+            .map(|i| format!("{}.{}", self.path, i))
+            .filter(|f| fs::metadata(f).is_ok())
+            .collect::<Vec<String>>();
+        ret.push(self.path.to_owned());
+        ret
+    }
+}
+
+/// What door did with a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// a new grant, and its commands worked
+    Granted,
+    /// an existing grant was extended
+    Extended,
+    /// the IP already had a grant (within the grace period), nothing ran
+    Duplicate,
+    /// verified, but the commands failed
+    Failed,
+    /// it didn't verify (or wasn't allowed)
+    Rejected,
+    /// rate limited or banned, so it wasn't even looked at
+    Dropped,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Granted => "granted",
+            Decision::Extended => "extended",
+            Decision::Duplicate => "duplicate",
+            Decision::Failed => "failed",
+            Decision::Rejected => "rejected",
+            Decision::Dropped => "dropped",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        [
+            Decision::Granted,
+            Decision::Extended,
+            Decision::Duplicate,
+            Decision::Failed,
+            Decision::Rejected,
+            Decision::Dropped,
+        ]
+        .into_iter()
+        .find(|d| d.as_str() == s)
+        .ok_or_else(|| {
+            format!("{s:?} isn't a decision (granted, extended, duplicate, failed, rejected or dropped)")
+        })
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One line of the audit log: a datagram and what came of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub timestamp: u64,
+    pub source: String,
+    pub ip: String,
+    /// bytes
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub door: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    /// the id of the key the knock was signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub decision: Decision,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<RejectReason>,
+    /// the commands (or helper requests) that ran, with their exit statuses
+    /// and how long they took
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepResult>,
+    /// when the grant runs out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl Record {
+    pub fn new(src: &SocketAddr, size: usize, decision: Decision) -> Self {
+        Record {
+            timestamp: unix_now(),
+            source: src.to_string(),
+            ip: src.ip().to_string(),
+            size,
+            listener: None,
+            door: None,
+            identity: None,
+            key: None,
+            decision,
+            reason: None,
+            steps: vec![],
+            expires: None,
+        }
+    }
+
+    pub fn reject(src: &SocketAddr, size: usize, decision: Decision, reason: RejectReason) -> Self {
+        let mut ret = Record::new(src, size, decision);
+        ret.reason = Some(reason);
+        ret
+    }
+}
+
+/// how many records can wait for the writer before new ones are dropped
+const QUEUE: usize = 1024;
+
+/// the open log file and how big it is
+struct Writer {
+    settings: AuditSettings,
+    file: Option<File>,
+    size: u64,
+    /// so a log that can't be written complains once, not once per knock
+    failing: bool,
+}
+
+impl Writer {
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        let max = self.settings.max_size;
+        if max > 0 && self.size > 0 && self.size + line.len() as u64 > max {
+            self.file = None;
+            rotate(&self.settings.path, self.settings.keep)?;
+            info!("audit: rotated {}", self.settings.path);
+        }
+        let file = match &mut self.file {
+            Some(f) => f,
+            None => {
+                let f = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .mode(0o640)
+                    .open(&self.settings.path)?;
+                self.size = f.metadata()?.len();
+                self.file.insert(f)
+            }
+        };
+        file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn append(&mut self, line: &[u8]) {
+        match self.write(line) {
+            Ok(()) if self.failing => {
+                info!("audit: writing to {} again", self.settings.path);
+                self.failing = false;
+            }
+            Ok(()) => (),
+            Err(e) => {
+                if !self.failing {
+                    error!("audit: couldn't write to {}: {}", self.settings.path, e);
+                }
+                // try opening it again next time
+                self.file = None;
+                self.failing = true;
+            }
+        }
+    }
+}
+
+enum Msg {
+    Line(Vec<u8>),
+    /// says so once everything sent before it is written
+    Flush(SyncSender<()>),
+}
+
+/// path.1 becomes path.2 and so on (dropping the one past `keep`), and path
+/// becomes path.1
+fn rotate(path: &str, keep: usize) -> io::Result<()> {
+    let gone = |r: io::Result<()>| match r {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    };
+    if keep == 0 {
+        return gone(fs::remove_file(path));
+    }
+    for i in (1..keep).rev() {
+        gone(fs::rename(format!("{path}.{i}"), format!("{path}.{}", i + 1)))?;
+    }
+    gone(fs::rename(path, format!("{path}.1")))
+}
+
+/// The append-only audit log: one JSON object per line, one line per
+/// datagram. The file's written (and rotated) by a thread of its own, so a
+/// flood of datagrams can't hold up the main loop on the disk; if the thread
+/// falls too far behind, records are dropped and counted. Cheap to clone; the
+/// clones all write to the same file.
+#[derive(Clone)]
+pub struct AuditLog {
+    queue: Option<SyncSender<Msg>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    /// a log that goes nowhere if settings.path is empty
+    pub fn new(settings: &AuditSettings) -> Self {
+        let queue = match settings.path.is_empty() {
+            true => None,
+            false => {
+                let (tx, rx) = mpsc::sync_channel::<Msg>(QUEUE);
+                let mut w = Writer {
+                    settings: settings.to_owned(),
+                    file: None,
+                    size: 0,
+                    failing: false,
+                };
+                thread::Builder::new()
+                    .name("audit".to_string())
+                    .spawn(move || {
+                        // until every clone of the log is gone
+                        for msg in rx {
+                            match msg {
+                                Msg::Line(line) => w.append(&line),
+                                Msg::Flush(done) => drop(done.send(())),
+                            }
+                        }
+                    })
+                    .expect("threads can be started");
+                Some(tx)
+            }
+        };
+        AuditLog {
+            queue,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// hand `record` to the writer, or drop it if too many are waiting
+    pub fn write(&self, record: &Record) {
+        let queue = match &self.queue {
+            Some(q) => q,
+            None => return,
+        };
+        let mut line = serde_json::to_vec(record).expect("records serialize");
+        line.push(b'\n');
+
+        if let Err(TrySendError::Full(_)) = queue.try_send(Msg::Line(line)) {
+            if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("audit: the log's falling behind, dropping records");
+            }
+        }
+    }
+
+    /// how many records have been dropped so far, the writer being behind
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// wait for the records written so far to be in the file; this blocks
+    pub fn flush(&self) {
+        if let Some(q) = &self.queue {
+            let (tx, rx) = mpsc::sync_channel(1);
+            if q.send(Msg::Flush(tx)).is_ok() {
+                let _ = rx.recv();
+            }
+        }
+    }
+}
+
+/// "1700000000" is a unix time, and "2h" (or anything else parse_duration
+/// takes, with its unit) is that long before `now`
+pub fn parse_when(s: &str, now: u64) -> Result<u64, String> {
+    match s.trim().ends_with(|c: char| c.is_ascii_digit()) {
+        true => s.trim().parse::<u64>().map_err(|_| format!("{s:?} isn't a unix time")),
+        false => Ok(now.saturating_sub(parse_duration(s)?)),
+    }
+}
+
+/// Which records `door audit` wants; None matches anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub ip: Option<String>,
+    pub identity: Option<String>,
+    pub door: Option<String>,
+    pub decision: Option<Decision>,
+    pub reason: Option<String>,
+}
+
+impl Query {
+    pub fn matches(&self, r: &Record) -> bool {
+        self.since.is_none_or(|t| r.timestamp >= t)
+            && self.until.is_none_or(|t| r.timestamp < t)
+            && self.ip.as_ref().is_none_or(|v| *v == r.ip)
+            && self.identity.as_ref().is_none_or(|v| Some(v) == r.identity.as_ref())
+            && self.door.as_ref().is_none_or(|v| Some(v) == r.door.as_ref())
+            && self.decision.is_none_or(|v| v == r.decision)
+            && self
+                .reason
+                .as_deref()
+                .is_none_or(|v| r.reason.is_some_and(|x| x.as_str() == v))
+    }
+}
+
+/// Read the records in `files` (in order) that `query` matches, handing each
+/// to `each`. Lines that aren't records are skipped; returns how many.
+pub fn scan<F: FnMut(Record)>(files: &[String], query: &Query, mut each: F) -> io::Result<usize> {
+    let mut bad = 0;
+    for f in files {
+        let file = match File::open(f) {
+            Ok(v) => v,
+            // rotated away since we listed them
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{f}: {e}"))),
+        };
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str::<Record>(&line?) {
+                Ok(r) if query.matches(&r) => each(r),
+                Ok(_) => (),
+                Err(_) => bad += 1,
+            }
+        }
+    }
+    Ok(bad)
+}
+
+/// Counts of the records `door audit --summary` was given.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub records: u64,
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+    pub decisions: BTreeMap<Decision, u64>,
+    pub reasons: BTreeMap<String, u64>,
+    pub doors: BTreeMap<String, u64>,
+    pub identities: BTreeMap<String, u64>,
+    pub ips: BTreeMap<String, u64>,
+}
+
+impl Summary {
+    pub fn add(&mut self, r: &Record) {
+        self.records += 1;
+        self.first = Some(self.first.map_or(r.timestamp, |t| t.min(r.timestamp)));
+        self.last = Some(self.last.map_or(r.timestamp, |t| t.max(r.timestamp)));
+        *self.decisions.entry(r.decision).or_default() += 1;
+        if let Some(v) = r.reason {
+            *self.reasons.entry(v.to_string()).or_default() += 1;
+        }
+        if let Some(v) = &r.door {
+            *self.doors.entry(v.to_owned()).or_default() += 1;
+        }
+        if let Some(v) = &r.identity {
+            *self.identities.entry(v.to_owned()).or_default() += 1;
+        }
+        *self.ips.entry(r.ip.to_owned()).or_default() += 1;
+    }
+}
+
+/// the `top` biggest counts, biggest first
+fn top(counts: &BTreeMap<String, u64>, top: usize) -> Vec<(&String, &u64)> {
+    let mut ret = counts.iter().collect::<Vec<(&String, &u64)>>();
+    ret.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    ret.truncate(top);
+    ret
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "records     {}", self.records)?;
+        if let (Some(first), Some(last)) = (self.first, self.last) {
+            writeln!(f, "from        {first}")?;
+            writeln!(f, "until       {last}")?;
+        }
+        let sections = [
+            ("reasons", &self.reasons),
+            ("doors", &self.doors),
+            ("identities", &self.identities),
+            ("ips", &self.ips),
+        ];
+        if !self.decisions.is_empty() {
+            writeln!(f, "\ndecisions")?;
+            for (k, v) in self.decisions.iter() {
+                writeln!(f, "  {v:>8}  {k}")?;
+            }
+        }
+        for (name, counts) in sections {
+            if counts.is_empty() {
+                continue;
+            }
+            writeln!(f, "\n{name}")?;
+            for (k, v) in top(counts, 10) {
+                writeln!(f, "  {v:>8}  {k}")?;
+            }
+            if counts.len() > 10 {
+                writeln!(f, "  ({} more)", counts.len() - 10)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ip: &str, decision: Decision, timestamp: u64) -> Record {
+        let mut r = Record::new(&format!("{ip}:1234").parse().unwrap(), 60, decision);
+        r.timestamp = timestamp;
+        r
+    }
+
+    #[test]
+    fn queries() {
+        let mut r = record("192.0.2.7", Decision::Granted, 1000);
+        r.identity = Some("alice".to_string());
+        r.door = Some("default".to_string());
+
+        assert!(Query::default().matches(&r));
+        let q = Query {
+            since: Some(1000),
+            until: Some(1001),
+            ip: Some("192.0.2.7".to_string()),
+            identity: Some("alice".to_string()),
+            door: Some("default".to_string()),
+            decision: Some(Decision::Granted),
+            reason: None,
+        };
+        assert!(q.matches(&r));
+        assert!(!Query {
+            since: Some(1001),
+            ..q.clone()
+        }
+        .matches(&r));
+        assert!(!Query {
+            until: Some(1000),
+            ..q.clone()
+        }
+        .matches(&r));
+        assert!(!Query {
+            identity: Some("bob".to_string()),
+            ..q.clone()
+        }
+        .matches(&r));
+        assert!(!Query {
+            decision: Some(Decision::Failed),
+            ..q.clone()
+        }
+        .matches(&r));
+        assert!(!Query {
+            reason: Some("banned".to_string()),
+            ..q.clone()
+        }
+        .matches(&r));
+
+        let r = Record::reject(
+            &"192.0.2.8:1".parse().unwrap(),
+            3,
+            Decision::Rejected,
+            RejectReason::BadFormat,
+        );
+        assert!(Query {
+            reason: Some("bad_format".to_string()),
+            ..Default::default()
+        }
+        .matches(&r));
+
+        assert_eq!(parse_when("1700000000", 5), Ok(1700000000));
+        assert_eq!(parse_when("2h", 10000), Ok(2800));
+        assert!(parse_when("soon", 5).is_err());
+        assert_eq!(Decision::parse("dropped"), Ok(Decision::Dropped));
+        assert!(Decision::parse("maybe").is_err());
+    }
+
+    #[test]
+    fn writing_and_rotating() {
+        let dir = std::env::temp_dir().join(format!("rknock-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log").to_string_lossy().to_string();
+
+        let line = serde_json::to_string(&record("192.0.2.7", Decision::Granted, 1)).unwrap();
+        let settings = AuditSettings {
+            path: path.to_owned(),
+            // two lines to a file
+            max_size: 2 * (line.len() as u64 + 1),
+            keep: 2,
+        };
+        let log = AuditLog::new(&settings);
+        assert!(log.is_enabled());
+        assert!(!AuditLog::new(&AuditSettings::default()).is_enabled());
+        for t in 1..=7 {
+            log.write(&record("192.0.2.7", Decision::Granted, t));
+        }
+        log.flush();
+        assert_eq!(log.dropped(), 0);
+        // 7 → path, 5 and 6 → path.1, 3 and 4 → path.2, and 1 and 2 are gone
+        assert_eq!(
+            settings.files(),
+            vec![format!("{path}.2"), format!("{path}.1"), path.to_owned()]
+        );
+
+        fs::write(format!("{path}.1"), "not json\n").unwrap();
+        let mut seen = vec![];
+        let bad = scan(&settings.files(), &Query::default(), |r| seen.push(r.timestamp)).unwrap();
+        assert_eq!((seen, bad), (vec![3, 4, 7], 1));
+
+        let mut summary = Summary::default();
+        let query = Query {
+            since: Some(4),
+            ..Default::default()
+        };
+        scan(&settings.files(), &query, |r| summary.add(&r)).unwrap();
+        summary.add(&Record::reject(
+            &"192.0.2.9:1".parse().unwrap(),
+            3,
+            Decision::Rejected,
+            RejectReason::BadFormat,
+        ));
+        assert_eq!(summary.records, 3);
+        assert_eq!(summary.decisions[&Decision::Granted], 2);
+        assert_eq!(summary.reasons["bad_format"], 1);
+        assert_eq!(summary.ips["192.0.2.7"], 2);
+        assert!(summary.to_string().contains("bad_format"));
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use strfmt::{strfmt, FmtError};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+use crate::exec::Exec;
+
+/// how many commands may wait for a slot unless the runner's told otherwise
+pub const DEFAULT_MAX_QUEUED: usize = 64;
+
+/// What door runs after a verified knock.
+///
+/// A plain string is the old form: it's formatted and handed to `sh -c`, so
+/// quoting is entirely the config author's problem. An array is executed
+/// directly; each element is formatted on its own and no shell is involved.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum CommandSpec {
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+impl CommandSpec {
+    /// the program and arguments we'd run for these vars
+    pub fn render(&self, vars: &KnockVars) -> Result<(String, Vec<String>), FmtError> {
+        let map = vars.to_map();
+        match self {
+            CommandSpec::Shell(s) => Ok(("sh".to_string(), vec!["-c".to_string(), strfmt(s, &map)?])),
+            CommandSpec::Argv(v) => {
+                let mut args = v
+                    .iter()
+                    .map(|a| strfmt(a, &map))
+                    .collect::<Result<Vec<String>, FmtError>>()?;
+                if args.is_empty() {
+                    return Err(FmtError::Invalid("empty argv command".to_string()));
+                }
+                let prog = args.remove(0);
+                Ok((prog, args))
+            }
+        }
+    }
+
+    /// a loggable version of the rendered command
+    pub fn describe(&self, vars: &KnockVars) -> String {
+        match (self, self.render(vars)) {
+            (_, Err(e)) => format!("<{e}>"),
+            (CommandSpec::Shell(_), Ok((_, mut args))) => args.pop().unwrap_or_default(),
+            (CommandSpec::Argv(_), Ok((prog, args))) => format!("{:?}", [vec![prog], args].concat()),
+        }
+    }
+
+    /// build (but don't spawn) the process, run as `exec` says, with the
+    /// KNOCK_* environment set; the child leads its own process group so a
+    /// timeout can take out the whole lot (e.g. sudo and the nft under it)
+    pub fn build(&self, vars: &KnockVars, exec: &Exec) -> Result<Command, FmtError> {
+        let (prog, args) = self.render(vars)?;
+        let mut cmd = Command::new(prog);
+        exec.apply(&mut cmd);
+        cmd.args(args)
+            .envs(vars.env())
+            .current_dir("/")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true);
+        Ok(cmd)
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Format(FmtError),
+    Spawn(std::io::Error),
+    Timeout(Duration),
+    Failed(Output),
+    /// this many were already waiting for a slot
+    QueueFull(usize),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Format(e) => write!(f, "couldn't format command: {e}"),
+            RunError::Spawn(e) => write!(f, "couldn't run command: {e}"),
+            RunError::Timeout(d) => write!(f, "killed after {}ms", d.as_millis()),
+            RunError::QueueFull(n) => write!(f, "not run, {n} commands are already waiting"),
+            RunError::Failed(o) => write!(
+                f,
+                "{}\n  stdout: {}\n  stderr: {}",
+                o.status, // e.g., "exit status: 1"
+                String::from_utf8_lossy(&o.stdout),
+                String::from_utf8_lossy(&o.stderr),
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/// Runs door commands without blocking the runtime: at most `limit` at a
+/// time, at most `max_queued` more waiting their turn (any past that are
+/// refused straight away) and none of them for longer than `timeout`.
+#[derive(Debug, Clone)]
+pub struct Runner {
+    slots: Arc<Semaphore>,
+    /// a slot or a place in the queue, one per command that's been let in
+    places: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+    refused: Arc<AtomicU64>,
+    timeout: Duration,
+    delay: Duration,
+    exec: Arc<Exec>,
+}
+
+impl Runner {
+    pub fn new(limit: usize, timeout: Duration) -> Self {
+        Runner {
+            slots: Arc::new(Semaphore::new(limit.max(1))),
+            places: Arc::new(Semaphore::new(limit.max(1) + DEFAULT_MAX_QUEUED)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queued: DEFAULT_MAX_QUEUED,
+            refused: Arc::new(AtomicU64::new(0)),
+            timeout,
+            delay: Duration::ZERO,
+            exec: Arc::new(Exec::default()),
+        }
+    }
+
+    /// run commands as `exec` says; the queue (and its limit) is still shared
+    /// with the runner this came from
+    pub fn with_exec(mut self, exec: Exec) -> Self {
+        self.exec = Arc::new(exec);
+        self
+    }
+
+    /// refuse commands once this many are waiting for a slot
+    pub fn with_max_queued(mut self, max_queued: usize) -> Self {
+        let limit = self.places.available_permits() - self.max_queued;
+        self.places = Arc::new(Semaphore::new(limit + max_queued));
+        self.max_queued = max_queued;
+        self
+    }
+
+    /// sleep this long before each command; only useful for debugging the queue
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// how many commands are waiting for a slot
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// how many commands were refused because the queue was full
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    /// Take a slot or a place in the queue without waiting, for a command
+    /// that's going to be run (with run_in) later, e.g. from a task that
+    /// hasn't been spawned yet. When there's neither, that's counted as a
+    /// refusal.
+    pub fn place(&self) -> Result<Place, RunError> {
+        match self.places.clone().try_acquire_owned() {
+            Ok(v) => Ok(Place { _permit: v }),
+            Err(_) => {
+                self.refused.fetch_add(1, Ordering::Relaxed);
+                Err(RunError::QueueFull(self.max_queued))
+            }
+        }
+    }
+
+    pub async fn run(&self, command: &CommandSpec, vars: &KnockVars) -> Result<Output, RunError> {
+        self.run_with_input(command, vars, None).await
+    }
+
+    /// like run(), but with `input` (if any) written to the command's stdin
+    pub async fn run_with_input(
+        &self,
+        command: &CommandSpec,
+        vars: &KnockVars,
+        input: Option<&[u8]>,
+    ) -> Result<Output, RunError> {
+        let place = self.place().inspect_err(|_| {
+            warn!(
+                "command queue is full ({} waiting), not running this one",
+                self.max_queued
+            )
+        })?;
+        self.run_in(place, command, vars, input).await
+    }
+
+    /// like run_with_input(), in a place that's already been taken
+    pub async fn run_in(
+        &self,
+        _place: Place,
+        command: &CommandSpec,
+        vars: &KnockVars,
+        input: Option<&[u8]>,
+    ) -> Result<Output, RunError> {
+        let mut cmd = command.build(vars, &self.exec).map_err(RunError::Format)?;
+        if input.is_some() {
+            cmd.stdin(Stdio::piped());
+        }
+
+        let _permit = match self.slots.try_acquire() {
+            Ok(v) => v,
+            Err(_) => {
+                let depth = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("command queue depth {} (all slots busy)", depth);
+                let permit = self.slots.acquire().await.expect("the semaphore is never closed");
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                permit
+            }
+        };
+
+        if !self.delay.is_zero() {
+            debug!("sleep({})", self.delay.as_millis());
+            tokio::time::sleep(self.delay).await;
+        }
+
+        let mut child = cmd.spawn().map_err(RunError::Spawn)?;
+        let pid = child.id();
+        let stdin = child.stdin.take();
+
+        let finish = async move {
+            if let (Some(mut stdin), Some(input)) = (stdin, input) {
+                // a command that doesn't bother reading its input isn't an error
+                let _ = stdin.write_all(input).await;
+            }
+            child.wait_with_output().await
+        };
+
+        match tokio::time::timeout(self.timeout, finish).await {
+            Ok(Ok(output)) if output.status.success() => Ok(output),
+            Ok(Ok(output)) => Err(RunError::Failed(output)),
+            Ok(Err(e)) => Err(RunError::Spawn(e)),
+            Err(_) => {
+                // the child itself dies with the dropped future (kill_on_drop),
+                // but anything it forked is still in its process group
+                if let Some(pid) = pid {
+                    match killpg(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+                        // nothing else was left in it
+                        Ok(()) | Err(Errno::ESRCH) => (),
+                        // e.g. door isn't root and the group's led by sudo
+                        Err(e) => warn!("couldn't kill process group {} after a timeout: {}", pid, e),
+                    }
+                }
+                Err(RunError::Timeout(self.timeout))
+            }
+        }
+    }
+}
+
+/// A runner's slot or place in its queue, held from before a command's task
+/// is spawned until the command's done.
+#[derive(Debug)]
+pub struct Place {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Everything we know about a verified knock that a command might care about.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnockVars {
+    pub ip: String,
+    pub port: u16,
+    pub family: String,
+    pub identity: String,
+    pub door: String,
+    /// the tag of the listener the knock arrived on
+    pub listener: String,
+    pub duration: u64,
+    pub timestamp: u64,
+    pub src_port: u16,
+    /// {ip} as a network: just the address (/32 or /128) for a knock, the
+    /// whole banned prefix (e.g. 2001:db8:1:2::/64) for a ban command
+    pub prefix: String,
+}
+
+impl KnockVars {
+    /// `src` is where the knock came from, `local` is the address it arrived on
+    pub fn new(src: &SocketAddr, local: &SocketAddr, duration: u64, timestamp: u64) -> Self {
+        KnockVars {
+            ip: src.ip().to_string(),
+            port: local.port(),
+            family: if src.is_ipv4() { "ipv4" } else { "ipv6" }.to_string(),
+            identity: "anonymous".to_string(),
+            door: "default".to_string(),
+            listener: "default".to_string(),
+            duration,
+            timestamp,
+            src_port: src.port(),
+            prefix: format!("{}/{}", src.ip(), if src.is_ipv4() { 32 } else { 128 }),
+        }
+    }
+
+    pub fn to_map(&self) -> HashMap<String, String> {
+        HashMap::from([
+            ("ip".to_string(), self.ip.to_owned()),
+            ("port".to_string(), self.port.to_string()),
+            ("family".to_string(), self.family.to_owned()),
+            ("identity".to_string(), self.identity.to_owned()),
+            ("door".to_string(), self.door.to_owned()),
+            ("listener".to_string(), self.listener.to_owned()),
+            ("duration".to_string(), self.duration.to_string()),
+            ("timestamp".to_string(), self.timestamp.to_string()),
+            ("src_port".to_string(), self.src_port.to_string()),
+            ("prefix".to_string(), self.prefix.to_owned()),
+        ])
+    }
+
+    /// the same vars as KNOCK_IP, KNOCK_PORT, etc, for the child's environment
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut ret = self
+            .to_map()
+            .into_iter()
+            .map(|(k, v)| (format!("KNOCK_{}", k.to_uppercase()), v))
+            .collect::<Vec<(String, String)>>();
+        ret.sort();
+        ret
+    }
+}
+
+/// what the tests knock with: from `ip`:5555 to 0.0.0.0:20022, at 1234
+#[cfg(test)]
+pub fn test_vars(ip: &str, duration: u64) -> KnockVars {
+    let src = SocketAddr::new(ip.parse().expect("an IP"), 5555);
+    let local: SocketAddr = "0.0.0.0:20022".parse().expect("an address");
+    KnockVars::new(&src, &local, duration, 1234)
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_form_uses_sh() {
+        let c = CommandSpec::Shell("echo {ip} {{ {duration}s }}".to_string());
+        let (prog, args) = c.render(&test_vars("10.1.2.3", 5)).unwrap();
+
+        assert_eq!(prog, "sh");
+        assert_eq!(args, vec!["-c", "echo 10.1.2.3 { 5s }"]);
+    }
+
+    #[test]
+    fn argv_form_formats_each_arg() {
+        let c = CommandSpec::Argv(vec![
+            "nft".to_string(),
+            "add element inet firewall knock {{ {ip} }}".to_string(),
+            "{family}:{port}:{src_port}".to_string(),
+        ]);
+        let (prog, args) = c.render(&test_vars("10.1.2.3", 5)).unwrap();
+
+        assert_eq!(prog, "nft");
+        assert_eq!(
+            args,
+            vec!["add element inet firewall knock { 10.1.2.3 }", "ipv4:20022:5555"]
+        );
+    }
+
+    #[test]
+    fn argv_form_rejects_nonsense() {
+        assert!(CommandSpec::Argv(vec![]).render(&test_vars("10.1.2.3", 5)).is_err());
+        assert!(CommandSpec::Argv(vec!["{nope}".to_string()])
+            .render(&test_vars("10.1.2.3", 5))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn runner_runs_things() {
+        let r = Runner::new(2, Duration::from_secs(5));
+        let c = CommandSpec::Argv(vec!["sh".to_string(), "-c".to_string(), "echo $KNOCK_IP".to_string()]);
+        let output = r.run(&c, &test_vars("10.1.2.3", 5)).await.unwrap();
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "10.1.2.3\n");
+        assert!(matches!(
+            r.run(&CommandSpec::Shell("exit 3".to_string()), &test_vars("10.1.2.3", 5))
+                .await,
+            Err(RunError::Failed(_))
+        ));
+        assert!(matches!(
+            r.run(
+                &CommandSpec::Argv(vec!["/nonexistent/nope".to_string()]),
+                &test_vars("10.1.2.3", 5)
+            )
+            .await,
+            Err(RunError::Spawn(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn runner_kills_slow_things() {
+        let r = Runner::new(1, Duration::from_millis(100));
+        let c = CommandSpec::Shell("sleep 5; sleep 5".to_string());
+        let start = std::time::Instant::now();
+
+        assert!(matches!(
+            r.run(&c, &test_vars("10.1.2.3", 5)).await,
+            Err(RunError::Timeout(_))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn runner_queues_things() {
+        let r = Runner::new(1, Duration::from_secs(5));
+        let c = CommandSpec::Shell("sleep 0.2".to_string());
+        let (r1, r2) = (r.clone(), r.clone());
+        let (c1, c2) = (c.clone(), c.clone());
+        let start = std::time::Instant::now();
+
+        let a = tokio::spawn(async move { r1.run(&c1, &test_vars("10.1.2.3", 5)).await });
+        let b = tokio::spawn(async move { r2.run(&c2, &test_vars("10.1.2.3", 5)).await });
+        assert!(a.await.unwrap().is_ok());
+        assert!(b.await.unwrap().is_ok());
+
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(r.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn runner_refuses_past_the_queue() {
+        let r = Runner::new(1, Duration::from_secs(5)).with_max_queued(1);
+        let c = CommandSpec::Shell("sleep 0.3".to_string());
+        let (r1, r2) = (r.clone(), r.clone());
+        let (c1, c2) = (c.clone(), c.clone());
+
+        let a = tokio::spawn(async move { r1.run(&c1, &test_vars("10.1.2.3", 5)).await });
+        let b = tokio::spawn(async move { r2.run(&c2, &test_vars("10.1.2.3", 5)).await });
+        while r.queue_depth() < 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // one running, one waiting: no room for a third
+        assert!(matches!(
+            r.run(&c, &test_vars("10.1.2.3", 5)).await,
+            Err(RunError::QueueFull(1))
+        ));
+        assert_eq!(r.refused(), 1);
+        assert!(a.await.unwrap().is_ok());
+        assert!(b.await.unwrap().is_ok());
+        assert_eq!(r.queue_depth(), 0);
+    }
+
+    #[test]
+    fn env_is_prefixed() {
+        let env = test_vars("10.1.2.3", 5).env();
+
+        assert!(env.contains(&("KNOCK_IP".to_string(), "10.1.2.3".to_string())));
+        assert!(env.contains(&("KNOCK_SRC_PORT".to_string(), "5555".to_string())));
+        assert!(env.contains(&("KNOCK_IDENTITY".to_string(), "anonymous".to_string())));
+        assert!(env.contains(&("KNOCK_LISTENER".to_string(), "default".to_string())));
+        assert!(env.contains(&("KNOCK_PREFIX".to_string(), "10.1.2.3/32".to_string())));
+        assert_eq!(env.len(), 10);
+    }
+}
@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+/// The `[keepalive]` section of door's config.
+///
+/// When it's enabled, door looks at the kernel's conntrack table every
+/// `interval` seconds and, for each grant that would otherwise run out before
+/// the next look, pushes its expiry `extend` seconds out if the IP still has a
+/// live connection. Once the connection is gone the grant just runs out, so it's
+/// revoked no more than `interval + extend` seconds after that.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct KeepaliveSettings {
+    pub enabled: bool,
+    /// `/proc/net/nf_conntrack`, or anything in the same format (the output of
+    /// `conntrack -L` works too)
+    pub path: String,
+    /// seconds between looks
+    pub interval: u64,
+    /// seconds to extend by; 0 means the grant's own duration
+    pub extend: u64,
+    /// only connections to these (destination) ports count; empty means any
+    pub ports: Vec<u16>,
+    /// never keep a grant alive longer than this many seconds in total; 0 means
+    /// for as long as the connection lasts
+    pub max_lifetime: u64,
+}
+
+impl Default for KeepaliveSettings {
+    fn default() -> Self {
+        KeepaliveSettings {
+            enabled: false,
+            path: "/proc/net/nf_conntrack".to_string(),
+            interval: 5,
+            extend: 0,
+            ports: vec![],
+            max_lifetime: 86400,
+        }
+    }
+}
+
+/// One conntrack entry, in the original direction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conn {
+    pub proto: String,
+    /// tcp has a state, most other things don't
+    pub state: Option<String>,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
+}
+
+/// tcp states that mean the connection is on its way out
+const DYING: &[&str] = &["FIN_WAIT", "CLOSE_WAIT", "LAST_ACK", "TIME_WAIT", "CLOSE"];
+
+impl Conn {
+    /// parse a line of /proc/net/nf_conntrack, or of `conntrack -L` (which is
+    /// the same minus the address family in front)
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut tokens = line.split_whitespace().peekable();
+        if matches!(tokens.peek(), Some(&"ipv4") | Some(&"ipv6")) {
+            tokens.next();
+            tokens.next();
+        }
+        let proto = tokens.next()?.to_string();
+        tokens.next()?.parse::<u8>().ok()?; // protocol number
+        tokens.next()?.parse::<u64>().ok()?; // seconds until the entry times out
+
+        let mut state = None;
+        let (mut src, mut dst, mut sport, mut dport) = (None, None, None, None);
+        for t in tokens {
+            match t.split_once('=') {
+                // the first of each is the original direction, the second the reply
+                Some(("src", v)) if src.is_none() => src = v.parse::<IpAddr>().ok(),
+                Some(("dst", v)) if dst.is_none() => dst = v.parse::<IpAddr>().ok(),
+                Some(("sport", v)) if sport.is_none() => sport = v.parse::<u16>().ok(),
+                Some(("dport", v)) if dport.is_none() => dport = v.parse::<u16>().ok(),
+                Some(_) => (),
+                None if t.starts_with('[') => (),
+                None if src.is_none() => state = Some(t.to_string()),
+                None => (),
+            }
+        }
+
+        Some(Conn {
+            proto,
+            state,
+            src: src?,
+            dst: dst?,
+            sport,
+            dport,
+        })
+    }
+
+    pub fn alive(&self) -> bool {
+        match &self.state {
+            Some(s) => !DYING.contains(&s.as_str()),
+            None => true,
+        }
+    }
+}
+
+/// parse a whole table, skipping lines that aren't connections
+pub fn parse(table: &str) -> Vec<Conn> {
+    table.lines().filter_map(Conn::parse).collect()
+}
+
+pub fn read(path: &str) -> io::Result<Vec<Conn>> {
+    Ok(parse(&std::fs::read_to_string(path)?))
+}
+
+/// like read(), but from a file that's already open (starting over at the
+/// top each time); the table is usually only readable by root, so door opens
+/// it before it gives that up
+pub fn read_from(f: &mut File) -> io::Result<Vec<Conn>> {
+    let mut table = String::new();
+    f.seek(SeekFrom::Start(0))?;
+    f.read_to_string(&mut table)?;
+    Ok(parse(&table))
+}
+
+/// whether `ip` has a live connection (to one of `ports`, if there are any)
+pub fn active(conns: &[Conn], ip: IpAddr, ports: &[u16]) -> bool {
+    conns
+        .iter()
+        .any(|c| c.src == ip && c.alive() && (ports.is_empty() || c.dport.is_some_and(|p| ports.contains(&p))))
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROC: &str = include_str!("../tests/fixtures/nf_conntrack");
+    const TOOL: &str = include_str!("../tests/fixtures/conntrack-L");
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn proc_table() {
+        let conns = parse(PROC);
+        assert_eq!(conns.len(), 6);
+
+        assert_eq!(
+            conns[0],
+            Conn {
+                proto: "tcp".to_string(),
+                state: Some("ESTABLISHED".to_string()),
+                src: ip("203.0.113.7"),
+                dst: ip("192.0.2.1"),
+                sport: Some(51234),
+                dport: Some(22),
+            }
+        );
+        assert_eq!(conns[2].state, None);
+        assert_eq!(conns[3].proto, "icmp");
+        assert_eq!(conns[3].dport, None);
+        assert_eq!(conns[4].src, ip("2001:db8::7"));
+
+        assert!(active(&conns, ip("203.0.113.7"), &[]));
+        assert!(active(&conns, ip("203.0.113.7"), &[22, 443]));
+        assert!(!active(&conns, ip("203.0.113.7"), &[443]));
+        assert!(!active(&conns, ip("198.51.100.9"), &[])); // TIME_WAIT
+        assert!(active(&conns, ip("198.51.100.20"), &[51820])); // udp
+        assert!(active(&conns, ip("2001:db8::7"), &[443]));
+        assert!(!active(&conns, ip("203.0.113.50"), &[])); // we started that one
+    }
+
+    #[test]
+    fn conntrack_tool() {
+        let conns = parse(TOOL);
+        assert_eq!(conns.len(), 3);
+
+        assert!(active(&conns, ip("203.0.113.7"), &[22]));
+        assert!(active(&conns, ip("198.51.100.20"), &[]));
+        assert!(!active(&conns, ip("198.51.100.9"), &[])); // CLOSE
+    }
+
+    #[test]
+    fn junk() {
+        assert_eq!(Conn::parse(""), None);
+        assert_eq!(Conn::parse("tcp 6 nope ESTABLISHED src=1.2.3.4"), None);
+        assert_eq!(Conn::parse("tcp 6 10 ESTABLISHED src=1.2.3.4 sport=1 dport=2"), None);
+        assert!(read("/nonexistent/nf_conntrack").is_err());
+    }
+
+    #[test]
+    fn reread() {
+        let mut f = File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/nf_conntrack")).unwrap();
+        assert_eq!(read_from(&mut f).unwrap().len(), 6);
+        assert_eq!(read_from(&mut f).unwrap().len(), 6);
+    }
+}
@@ -0,0 +1,364 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+
+use nix::unistd::{Group, Uid, User};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+use crate::grants::Grant;
+
+/// where the control socket goes unless the config says otherwise
+pub const DEFAULT_SOCKET: &str = "/run/rknock/door.sock";
+
+/// The `[control]` section of door's config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ControlSettings {
+    pub enabled: bool,
+    pub socket: String,
+    /// the socket's permissions
+    pub mode: u32,
+    /// the socket's group; its members may use it
+    pub group: Option<String>,
+    /// other users who may use it (root, and whoever started door, always can)
+    pub users: Vec<String>,
+}
+
+impl Default for ControlSettings {
+    fn default() -> Self {
+        ControlSettings {
+            enabled: false,
+            socket: DEFAULT_SOCKET.to_string(),
+            mode: 0o600,
+            group: None,
+            users: vec![],
+        }
+    }
+}
+
+/// One line from a `door ctl` (or anything else that speaks the protocol).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum CtlRequest {
+    /// the active grants
+    Grants,
+    /// take away an IP's grants (just the one door's, with door)
+    Revoke { ip: String, door: Option<String> },
+    /// let an IP in without a knock; the door's policy still applies
+    Grant {
+        ip: String,
+        door: Option<String>,
+        identity: Option<String>,
+        duration: Option<u64>,
+    },
+    /// counters and gauges
+    Stats,
+    /// what SIGHUP does
+    Reload,
+    /// how many nonces door remembers
+    ReplayCache,
+}
+
+/// One line back; `data` depends on the request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CtlReply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl CtlReply {
+    pub fn ok<T: Serialize>(data: T) -> Self {
+        CtlReply {
+            ok: true,
+            error: None,
+            data: Some(serde_json::to_value(data).expect("replies serialize")),
+        }
+    }
+
+    pub fn err<E: ToString>(error: E) -> Self {
+        CtlReply {
+            ok: false,
+            error: Some(error.to_string()),
+            data: None,
+        }
+    }
+}
+
+/// A grant, the way `door ctl grants` shows it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrantInfo {
+    pub door: String,
+    pub ip: String,
+    pub identity: String,
+    pub listener: String,
+    pub granted: u64,
+    pub refreshed: u64,
+    pub expires: u64,
+}
+
+impl From<&Grant> for GrantInfo {
+    fn from(g: &Grant) -> Self {
+        GrantInfo {
+            door: g.vars.door.to_owned(),
+            ip: g.vars.ip.to_owned(),
+            identity: g.vars.identity.to_owned(),
+            listener: g.vars.listener.to_owned(),
+            granted: g.granted,
+            refreshed: g.refreshed,
+            expires: g.expires,
+        }
+    }
+}
+
+/// what the main loop gets: a request, and where to send the reply
+pub type Call = (CtlRequest, oneshot::Sender<CtlReply>);
+
+/// Who may use a socket (the control socket, or the event stream), as uids
+/// and gids (worked out when it's bound, so the listener doesn't need
+/// /etc/passwd later).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Access {
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+}
+
+impl Access {
+    /// root, whoever we're running as, `users`, and `group`'s members
+    pub fn new(group: Option<&str>, users: &[String]) -> Result<Self, String> {
+        let mut ret = Access {
+            uids: vec![0, Uid::effective().as_raw()],
+            gids: vec![],
+        };
+        for name in users.iter() {
+            match User::from_name(name) {
+                Ok(Some(u)) => ret.uids.push(u.uid.as_raw()),
+                _ => return Err(format!("there's no user {name:?}")),
+            }
+        }
+        if let Some(name) = group {
+            let g = match Group::from_name(name) {
+                Ok(Some(g)) => g,
+                _ => return Err(format!("there's no group {name:?}")),
+            };
+            ret.gids.push(g.gid.as_raw());
+            // members who have it as a supplementary group
+            for m in g.mem.iter() {
+                if let Ok(Some(u)) = User::from_name(m) {
+                    ret.uids.push(u.uid.as_raw());
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn allows(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+/// bind a unix socket at `socket` (replacing a stale one) with `mode`, and
+/// `group` if there is one
+pub fn bind_socket(
+    socket: &str,
+    mode: u32,
+    group: Option<&str>,
+) -> Result<std::os::unix::net::UnixListener, String> {
+    let path = Path::new(socket);
+    if let Ok(m) = fs::symlink_metadata(path) {
+        if !m.file_type().is_socket() {
+            return Err(format!("{socket} is there already and isn't a socket"));
+        }
+        fs::remove_file(path).map_err(|e| format!("couldn't remove the old {socket}: {e}"))?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path).map_err(|e| format!("{socket}: {e}"))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|e| format!("{socket}: {e}"))?;
+    if let Some(name) = group {
+        let g = match Group::from_name(name) {
+            Ok(Some(g)) => g,
+            _ => return Err(format!("there's no group {name:?}")),
+        };
+        std::os::unix::fs::chown(path, None, Some(g.gid.as_raw())).map_err(|e| format!("{socket}: {e}"))?;
+    }
+    Ok(listener)
+}
+
+/// bind the control socket, and work out who gets to use it
+pub fn bind(settings: &ControlSettings) -> Result<(std::os::unix::net::UnixListener, Access), String> {
+    let access = Access::new(settings.group.as_deref(), &settings.users)?;
+    let listener = bind_socket(&settings.socket, settings.mode, settings.group.as_deref())?;
+    Ok((listener, access))
+}
+
+async fn connection(stream: UnixStream, access: &Access, calls: &mpsc::Sender<Call>) -> io::Result<()> {
+    let cred = stream.peer_cred()?;
+    if !access.allows(cred.uid(), cred.gid()) {
+        warn!(
+            "control: uid {} (pid {:?}) isn't allowed to use the socket",
+            cred.uid(),
+            cred.pid()
+        );
+        let mut stream = stream;
+        let line = serde_json::to_string(&CtlReply::err("not allowed")).expect("replies serialize");
+        return stream.write_all(format!("{line}\n").as_bytes()).await;
+    }
+
+    let (read, mut write) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<CtlRequest>(&line) {
+            Ok(req) => {
+                info!("control: uid {} asked for {}", cred.uid(), line.trim());
+                let (tx, rx) = oneshot::channel();
+                if calls.send((req, tx)).await.is_err() {
+                    return Ok(());
+                }
+                rx.await.unwrap_or_else(|_| CtlReply::err("door's shutting down"))
+            }
+            Err(e) => CtlReply::err(format!("couldn't make sense of that: {e}")),
+        };
+        let line = serde_json::to_string(&reply).expect("replies serialize");
+        write.write_all(format!("{line}\n").as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// take requests on the control socket and hand them to the main loop
+pub async fn serve(listener: UnixListener, access: Access, calls: mpsc::Sender<Call>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((v, _)) => v,
+            Err(e) => {
+                warn!("control: couldn't accept: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let (access, calls) = (access.clone(), calls.clone());
+        tokio::spawn(async move {
+            if let Err(e) = connection(stream, &access, &calls).await {
+                debug!("control: {}", e);
+            }
+        });
+    }
+}
+
+/// send one request to door and wait for the reply
+pub fn call(socket: &str, request: &CtlRequest, timeout: Duration) -> Result<CtlReply, String> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)
+        .map_err(|e| format!("couldn't connect to {socket}: {e}"))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| format!("{socket}: {e}"))?;
+    let line = serde_json::to_string(request).expect("requests serialize");
+    // door may have said no (and hung up) before we got this far, so read
+    // its reply either way
+    let sent = stream.write_all(format!("{line}\n").as_bytes());
+
+    let mut reply = String::new();
+    let read = BufReader::new(stream).read_line(&mut reply);
+    match (sent, read) {
+        (_, Ok(n)) if n > 0 => {
+            serde_json::from_str(&reply).map_err(|e| format!("couldn't make sense of door's reply: {e}"))
+        }
+        (Err(e), _) => Err(format!("couldn't send to {socket}: {e}")),
+        (Ok(()), Err(e)) => Err(format!("no reply from {socket}: {e}")),
+        (Ok(()), Ok(_)) => Err(format!("{socket} hung up without a reply")),
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol() {
+        let req: CtlRequest = serde_json::from_str(r#"{"cmd":"revoke","ip":"192.0.2.7"}"#).unwrap();
+        assert_eq!(
+            req,
+            CtlRequest::Revoke {
+                ip: "192.0.2.7".to_string(),
+                door: None
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<CtlRequest>(r#"{"cmd":"replay_cache"}"#).unwrap(),
+            CtlRequest::ReplayCache
+        );
+        assert!(serde_json::from_str::<CtlRequest>(r#"{"cmd":"rm -rf"}"#).is_err());
+        assert_eq!(
+            serde_json::to_string(&CtlReply::err("nope")).unwrap(),
+            r#"{"ok":false,"error":"nope"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&CtlReply::ok(5)).unwrap(),
+            r#"{"ok":true,"data":5}"#
+        );
+
+        let access = Access::new(None, &[]).unwrap();
+        assert!(access.allows(0, 0));
+        assert!(access.allows(Uid::effective().as_raw(), 12345));
+        assert!(!access.allows(54321, 54321));
+        assert!(Access::new(None, &["no-such-user-here".to_string()]).is_err());
+        assert!(Access::new(Some("no-such-group-here"), &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("rknock-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let settings = ControlSettings {
+            enabled: true,
+            socket: dir.join("door.sock").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        // a stale socket gets replaced
+        drop(bind(&settings).unwrap());
+        let (listener, access) = bind(&settings).unwrap();
+        let mode = fs::metadata(&settings.socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        listener.set_nonblocking(true).unwrap();
+        let (tx, mut rx) = mpsc::channel::<Call>(1);
+        tokio::spawn(serve(UnixListener::from_std(listener).unwrap(), access, tx));
+        tokio::spawn(async move {
+            while let Some((req, reply)) = rx.recv().await {
+                let _ = reply.send(match req {
+                    CtlRequest::ReplayCache => CtlReply::ok(7),
+                    _ => CtlReply::err("not here"),
+                });
+            }
+        });
+
+        let socket = settings.socket.to_owned();
+        let replies = tokio::task::spawn_blocking(move || {
+            (
+                call(&socket, &CtlRequest::ReplayCache, Duration::from_secs(5)),
+                call(&socket, &CtlRequest::Stats, Duration::from_secs(5)),
+            )
+        })
+        .await
+        .unwrap();
+        assert_eq!(replies.0.unwrap(), CtlReply::ok(7));
+        assert_eq!(replies.1.unwrap().error.as_deref(), Some("not here"));
+
+        fs::write(dir.join("not-a-socket"), "").unwrap();
+        assert!(bind(&ControlSettings {
+            socket: dir.join("not-a-socket").to_string_lossy().to_string(),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
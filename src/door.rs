@@ -1,15 +1,22 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::process::{Command, ExitCode, Stdio};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-extern crate strfmt;
-use strfmt::strfmt;
-
-extern crate log;
-use env_logger::Env;
-use log::{debug, error, info, LevelFilter};
-use syslog::{BasicLogger, Facility, Formatter3164};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing::field::Empty;
+use tracing::{debug, error, info, info_span, instrument, warn, Span};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry};
 
 extern crate lru;
 use lru::LruCache;
@@ -18,151 +25,1730 @@ use clap::{arg, crate_authors, crate_version, value_parser, App, ArgAction, Valu
 use config::Config;
 
 use tokio::net::UdpSocket;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, Notify};
 use tokio::task;
 
-use rlib::{config_filez, grok_setting, is_default, read_from_file_sometimes, HMACFrobnicator};
+use rlib::allowlist::{AllowlistSettings, Allowlists};
+use rlib::audit::{self, AuditLog, AuditSettings, Query, Record, Summary};
+use rlib::command::{CommandSpec, KnockVars, Runner};
+use rlib::conntrack::{self, Conn, KeepaliveSettings};
+use rlib::control::{self, Access, ControlSettings, CtlReply, CtlRequest, GrantInfo};
+use rlib::doors::{door_hint, Backend, Door, DoorSection, Doors, DEFAULT_DOOR};
+use rlib::events::{Event, EventKind, Hooks, RejectReason};
+use rlib::exec::{Exec, RunAs};
+use rlib::grants::{Decision, Grant, Grants, SharedGrants};
+use rlib::keyring::{Key, Keyring, DEFAULT_SECRET};
+use rlib::listeners::{wants_v6only, Listen};
+use rlib::metrics::{self, Metrics, MetricsSettings};
+use rlib::nft::NftSettings;
+use rlib::payload::{parse_duration, valid_token, Ack, Payload};
+use rlib::pipeline::{Action, Pipeline, PipelineResult};
+use rlib::policy::DoorPolicy;
+use rlib::preflight::Preflight;
+use rlib::privsep::{self, Helper, HelperClient, PrivsepSettings, Request, Rules};
+use rlib::ratelimit::{Limiter, RateLimitSettings, Verdict};
+use rlib::revocation::Revocations;
+use rlib::stream::{self, EventStream, StreamSettings};
+use rlib::trace::{self, OtlpHandle, TracingSettings};
+use rlib::watch::FileWatcher;
+use rlib::{config_filez, grok_setting, is_default, try_read_from_file_sometimes, unix_now, HMACFrobnicator};
 
-async fn allow_ip(src: &String, command: &str) {
-    let vars = HashMap::from([("ip".to_string(), src.to_string())]);
-    let cmd = strfmt(command, &vars).unwrap();
+#[derive(Clone)]
+struct Settings {
+    verbose: bool,
+    syslog: bool,
+    compact: bool,
+    listen: Vec<Listen>,
+    doors: Doors,
+    hooks: Hooks,
+    rate_limit: RateLimitSettings,
+    keepalive: KeepaliveSettings,
+    allowlists: Vec<AllowlistSettings>,
+    allowlist_debounce: u64,
+    nft: NftSettings,
+    privsep: PrivsepSettings,
+    print_ruleset: bool,
+    preflight: bool,
+    no_preflight: bool,
+    ack: bool,
+    grace: u64,
+    command_timeout: u64,
+    max_commands: usize,
+    max_queued: usize,
+    shutdown_timeout: u64,
+    revoke_on_shutdown: bool,
+    revocations: Revocations,
+    audit: AuditSettings,
+    metrics: MetricsSettings,
+    control: ControlSettings,
+    events: StreamSettings,
+    tracing: TracingSettings,
+    /// door audit: the records it wants, and whether to sum them up
+    audit_query: Option<(Query, bool)>,
+    /// the directories of the config files and any @files (secrets,
+    /// commands), which a reload reads again
+    readable: Vec<String>,
+}
 
-    debug!("exec({}) ip={}", cmd, src);
+/// everything a spawned grant needs, cheap enough to clone for each one
+#[derive(Clone)]
+struct Ctx {
+    hooks: Hooks,
+    runner: Runner,
+    hook_runner: Runner,
+    grants: SharedGrants,
+    allowlists: Allowlists,
+    audit: AuditLog,
+    metrics: Metrics,
+    events: EventStream,
+    /// where spans go, if they go anywhere
+    tracer: Option<SdkTracerProvider>,
+    /// with privsep on, pipelines and bans go through the helper
+    helper: Option<HelperClient>,
+    inflight: InFlight,
+}
 
-    let debug_sleep = std::time::Duration::from_millis(
-        std::env::var("KNOCK_DOOR_DEBUG_DELAY")
-            .unwrap_or_else(|_| "0".to_string())
-            .parse::<u64>()
-            .unwrap_or(0),
+/// What we keep about one datagram while it's handled: its audit record, and
+/// its span, which takes the record's outcome with it when it ends.
+struct Knock {
+    record: Record,
+    span: Span,
+}
+
+impl Knock {
+    fn new(src: &SocketAddr, size: usize, listener: &str) -> Self {
+        let mut record = Record::new(src, size, audit::Decision::Rejected);
+        record.listener = Some(listener.to_owned());
+        let span = info_span!(
+            "datagram",
+            src = %src,
+            listener,
+            size,
+            door = Empty,
+            identity = Empty,
+            key = Empty,
+            decision = Empty,
+            reason = Empty,
+            otel.status_code = Empty,
+            otel.status_message = Empty,
+        );
+        Knock { record, span }
+    }
+}
+
+impl Drop for Knock {
+    fn drop(&mut self) {
+        let r = &self.record;
+        for (key, value) in [("door", &r.door), ("identity", &r.identity), ("key", &r.key)] {
+            if let Some(v) = value {
+                self.span.record(key, v.as_str());
+            }
+        }
+        self.span.record("decision", r.decision.as_str());
+        if let Some(reason) = r.reason {
+            self.span.record("reason", reason.to_string());
+        }
+        let failed = match r.decision {
+            audit::Decision::Rejected => Some(r.reason.map(|v| v.to_string()).unwrap_or_default()),
+            audit::Decision::Failed => Some("the pipeline failed".to_string()),
+            _ => None,
+        };
+        if let Some(why) = failed {
+            self.span.record("otel.status_code", "ERROR");
+            self.span.record("otel.status_message", why);
+        }
+    }
+}
+
+/// The sockets door serves besides its listeners, bound before privsep
+/// locks it down.
+struct Services {
+    scrapes: Option<std::net::TcpListener>,
+    ctl: Option<(std::os::unix::net::UnixListener, Access)>,
+    events: Option<(std::os::unix::net::UnixListener, Access)>,
+    /// not a socket, but where the OTLP exporter goes once it's safe to start
+    otlp: OtlpHandle,
+}
+
+/// The grant, extend and ban tasks that haven't finished yet, so shutdown
+/// can wait for them.
+#[derive(Clone, Default)]
+struct InFlight {
+    count: Arc<AtomicUsize>,
+    done: Arc<Notify>,
+}
+
+impl InFlight {
+    fn spawn<F: Future<Output = ()> + Send + 'static>(&self, f: F) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        let me = self.clone();
+        task::spawn(async move {
+            f.await;
+            me.count.fetch_sub(1, Ordering::SeqCst);
+            me.done.notify_waiters();
+        });
+    }
+
+    fn len(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    async fn wait(&self) {
+        loop {
+            // made before the check, so a task finishing in between still wakes us
+            let done = self.done.notified();
+            if self.len() == 0 {
+                return;
+            }
+            done.await;
+        }
+    }
+}
+
+impl Ctx {
+    /// run (or extend) a door's pipeline, in the helper if there is one
+    async fn pipeline(&self, door: &Door, vars: &KnockVars, extend: bool) -> PipelineResult {
+        let vars = vars.to_owned();
+        let res = match (&self.helper, extend) {
+            (Some(h), false) => h.request(Request::Grant { vars }).await.into_result(),
+            (Some(h), true) => h.request(Request::Extend { vars }).await.into_result(),
+            (None, false) => door.pipeline.run(&vars, &door.runner(&self.runner)).await,
+            (None, true) => door.pipeline.extend(&vars, &door.runner(&self.runner)).await,
+        };
+        self.metrics.commands(&res.steps);
+        res
+    }
+
+    /// run a door's undo commands for a grant
+    async fn revoke(&self, door: &Door, vars: &KnockVars) -> PipelineResult {
+        let res = match &self.helper {
+            Some(h) => h.request(Request::Revoke { vars: vars.to_owned() }).await.into_result(),
+            None => door.pipeline.revoke(vars, &door.runner(&self.runner)).await,
+        };
+        self.metrics.commands(&res.steps);
+        res
+    }
+
+    /// tell the subscribers and the hooks about something that happened
+    fn event(&self, event: Event, vars: &KnockVars) {
+        self.events.publish(&event);
+        self.hooks.fire(event, vars, &self.hook_runner);
+    }
+}
+
+/// a bound listen spec
+struct Listener {
+    spec: Listen,
+    socket: Arc<UdpSocket>,
+    local: SocketAddr,
+    v6only: bool,
+    /// the task reading the socket
+    task: task::JoinHandle<()>,
+}
+
+/// The listeners, by an id that's never reused, so a packet that was heard
+/// just before a reload stopped its listener can be told apart.
+struct Listeners {
+    map: HashMap<u64, Listener>,
+    next: u64,
+    /// where the listeners send what they hear
+    tx: mpsc::Sender<(u64, SocketAddr, Vec<u8>)>,
+}
+
+impl Listeners {
+    fn new(tx: mpsc::Sender<(u64, SocketAddr, Vec<u8>)>) -> Self {
+        Listeners {
+            map: HashMap::new(),
+            next: 0,
+            tx,
+        }
+    }
+
+    /// start reading a bound socket, handing what it hears to the main loop
+    fn add(&mut self, spec: Listen, v6only: bool, socket: std::net::UdpSocket) {
+        let socket = Arc::new(UdpSocket::from_std(socket).expect("the runtime takes bound sockets"));
+        let local = socket.local_addr().expect("bound sockets have addresses");
+        let (id, name, tx, sock) = (self.next, spec.describe(), self.tx.clone(), socket.clone());
+        self.next += 1;
+
+        info!("listening to {}", name);
+        let task = task::spawn(async move {
+            let mut buf = [0; 256];
+            loop {
+                match sock.recv_from(&mut buf).await {
+                    Ok((amt, src)) => {
+                        if tx.send((id, src, buf[..amt].to_vec())).await.is_err() {
+                            break;
+                        }
+                    }
+                    // e.g. ECONNREFUSED from an ICMP error about an ack we
+                    // sent; nothing wrong with the socket itself
+                    Err(e) => {
+                        warn!("couldn't read from {}: {}", name, e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        });
+        self.map.insert(
+            id,
+            Listener {
+                spec,
+                socket,
+                local,
+                v6only,
+                task,
+            },
+        );
+    }
+
+    /// a listener that's bound the way `spec` would be, other than the ones in `taken`
+    fn find(&self, spec: &Listen, v6only: bool, taken: &HashMap<u64, Listen>) -> Option<u64> {
+        let mut ids = self.map.keys().copied().collect::<Vec<u64>>();
+        ids.sort();
+        ids.into_iter().find(|id| {
+            let l = &self.map[id];
+            !taken.contains_key(id)
+                && l.spec.addr == spec.addr
+                && l.spec.interface == spec.interface
+                && l.v6only == v6only
+        })
+    }
+
+    /// the first listener's address (bans and expiries aren't about any one
+    /// listener, but the commands get a {port})
+    fn first_local(&self) -> SocketAddr {
+        let id = self.map.keys().min().expect("there's always a listener");
+        self.map[id].local
+    }
+}
+
+/// where to reply to a knock that asked for an ack: the socket it came in on,
+/// signed with the key it used
+#[derive(Clone)]
+struct AckTo {
+    nonce: String,
+    socket: Arc<UdpSocket>,
+    key: HMACFrobnicator,
+}
+
+/// tell the knock how it went; `granted` is None when it didn't
+fn send_ack(to: &AckTo, src: &SocketAddr, granted: Option<u64>) {
+    let ack = Ack {
+        nonce: to.nonce.to_owned(),
+        granted,
+    };
+    let msg = to.key.to_owned().sign(&ack.encode());
+
+    match to.socket.try_send_to(msg.as_bytes(), *src) {
+        Ok(_) => debug!("ack({}) → {}", ack.encode(), src),
+        Err(e) => warn!("couldn't ack {}: {}", src, e),
+    }
+}
+
+#[instrument(
+    name = "action",
+    skip_all,
+    parent = &knock.span,
+    fields(src = %src, door = %door.name, identity = %vars.identity, extend, steps, otel.status_code)
+)]
+async fn allow_ip(
+    src: SocketAddr,
+    vars: &KnockVars,
+    decision: Decision,
+    ack: Option<AckTo>,
+    door: &Door,
+    ctx: &Ctx,
+    mut knock: Knock,
+) {
+    debug!(
+        "pipeline({} actions) ip={} door={} {:?}",
+        door.pipeline.actions.len(),
+        vars.ip,
+        door.name,
+        decision
     );
 
-    debug!("sleep({})", debug_sleep.as_millis());
-    if debug_sleep.as_millis() > 0 {
-        // I can't think of anything that would make this useful outside debugs
-        // but decided to leave KNOCK_DOOR_DEBUG_DELAY exposed regardless.
-        std::thread::sleep(debug_sleep);
-    }
-
-    let child = Command::new("sh")
-        .arg("-c")
-        .arg(&cmd)
-        .current_dir("/")
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .expect("failed to fork child process");
-
-    let output = child.wait_with_output().expect("failed to wait for child");
-
-    if !output.status.success() {
-        error!(
-            "fail({}) {}\n  stdout: {}\n  stderr: {}",
-            &cmd,
-            output.status, // e.g., "exit status: 1"
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr),
+    let extend = matches!(decision, Decision::Extend { .. });
+    let span = Span::current();
+    span.record("extend", extend);
+    let res = ctx.pipeline(door, vars, extend).await;
+    span.record("steps", res.steps.len());
+    if !res.success {
+        span.record("otel.status_code", "ERROR");
+    }
+    let record = &mut knock.record;
+    record.steps = res.steps.to_owned();
+    if !res.success {
+        error!("failed to allow {} ({} steps run)", vars.ip, res.steps.len());
+        record.decision = audit::Decision::Failed;
+        ctx.audit.write(record);
+        ctx.grants
+            .lock()
+            .expect("grants lock")
+            .revert(&vars.door, src.ip(), decision);
+        ctx.allowlists.changed();
+        if let Some(to) = ack {
+            send_ack(&to, &src, None);
+        }
+        ctx.event(
+            Event::knock(EventKind::CommandFailure, &src, vars).with_steps(res.steps),
+            vars,
         );
         return;
     }
 
-    info!("allowed {}", src);
+    info!("allowed {} door={} for {}s", vars.ip, door.name, vars.duration);
+    record.decision = match extend {
+        true => audit::Decision::Extended,
+        false => audit::Decision::Granted,
+    };
+    record.expires = ctx
+        .grants
+        .lock()
+        .expect("grants lock")
+        .get(&vars.door, src.ip())
+        .map(|g| g.expires);
+    ctx.audit.write(record);
+    ctx.allowlists.changed();
+    if let Some(to) = ack {
+        send_ack(&to, &src, Some(vars.duration));
+    }
+    ctx.event(Event::knock(EventKind::Grant, &src, vars).with_steps(res.steps), vars);
+}
+
+/// read the conntrack table (`table`, if it's already open) off the
+/// runtime's threads, since a busy one can be big, and send back what's in it
+fn read_conntrack(path: &str, table: &Arc<Mutex<Option<File>>>, tx: &mpsc::Sender<io::Result<Vec<Conn>>>) {
+    let (path, table, tx) = (path.to_owned(), table.clone(), tx.clone());
+    task::spawn(async move {
+        let conns = task::spawn_blocking(move || match table.lock().expect("table lock").as_mut() {
+            Some(f) => conntrack::read_from(f),
+            None => conntrack::read(&path),
+        })
+        .await;
+        if let Ok(v) = conns {
+            let _ = tx.send(v).await;
+        }
+    });
+}
+
+/// extend the grants that are about to run out if their IPs still have a
+/// connection going, going by `conns` from read_conntrack()
+fn keepalive(ka: &KeepaliveSettings, conns: io::Result<Vec<Conn>>, doors: &Doors, ctx: &Ctx) {
+    let conns = match conns {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("keepalive: couldn't read {}: {}", ka.path, e);
+            return;
+        }
+    };
+    let now = unix_now();
+    let soon = ctx.grants.lock().expect("grants lock").expiring(now + ka.interval + 1);
+
+    for g in soon {
+        let ip = g.src.ip();
+        if !conntrack::active(&conns, ip, &ka.ports) {
+            continue;
+        }
+        let door = match doors.get(&g.vars.door) {
+            Some(d) => d.clone(),
+            None => continue,
+        };
+        let mut expires = now
+            + match ka.extend {
+                0 => g.vars.duration,
+                v => v,
+            };
+        if ka.max_lifetime > 0 {
+            expires = expires.min(g.granted + ka.max_lifetime);
+        }
+        if expires <= g.expires {
+            debug!("keepalive: {} door={} is at its max_lifetime", g.vars.ip, g.vars.door);
+            continue;
+        }
+        let decision = match ctx
+            .grants
+            .lock()
+            .expect("grants lock")
+            .renew(&g.vars.door, ip, expires, now)
+        {
+            Some(d) => d,
+            None => continue,
+        };
+
+        info!(
+            "keepalive: {} door={} is still connected, extending to {}",
+            g.vars.ip, g.vars.door, expires
+        );
+        ctx.allowlists.changed();
+        let mut vars = g.vars.to_owned();
+        vars.duration = expires - now;
+        let ctx = ctx.clone();
+
+        ctx.inflight.clone().spawn(async move {
+            let res = ctx.pipeline(&door, &vars, true).await;
+            if !res.success {
+                error!(
+                    "keepalive: failed to extend {} ({} steps run)",
+                    vars.ip,
+                    res.steps.len()
+                );
+                ctx.grants.lock().expect("grants lock").revert(&vars.door, ip, decision);
+                ctx.allowlists.changed();
+                ctx.event(
+                    Event::knock(EventKind::CommandFailure, &g.src, &vars).with_steps(res.steps),
+                    &vars,
+                );
+            }
+        });
+    }
 }
 
+#[instrument(skip_all, parent = &knock.span, fields(src = %src_wp, door, identity, key))]
 async fn process_payload(
-    amt: usize,
     src_wp: &String,
     buf: &[u8],
-    hf: &mut HMACFrobnicator,
+    doors: &Doors,
+    revocations: &Revocations,
+    listener: &Listen,
     nonce_cache: &mut LruCache<String, bool>,
-) -> bool {
+    knock: &mut Knock,
+) -> Result<(Arc<Door>, Payload, String, Key), RejectReason> {
+    let span = Span::current();
+    let record = &mut knock.record;
+    let decode = info_span!("decode").entered();
     let msg = String::from_utf8_lossy(buf);
 
-    debug!("{} sent {} bytes, {:?}", src_wp, amt, msg); // {:?} has its own quotes
-
-    match hf.verify(&msg) {
-        Ok(snonce) => {
-            if nonce_cache.get(&snonce).is_some() {
-                debug!("rejecting reused nonce");
-                // Arguably, an attacker could flood this cache with valid
-                // nonces and roll this one right off so it could be reused;
-                // but ... then in that case they can generate valid nonces, so
-                // who really cares if they can flood this cache?
-                return false;
-            }
-            nonce_cache.put(snonce.to_owned(), true);
-
-            let epos = snonce.find('$').unwrap_or(snonce.len());
-            let tnonce = snonce[..epos].to_string();
-            match tnonce.parse::<u64>() {
-                Ok(inonce) => {
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("systemtime fucked")
-                        .as_secs();
-                    if inonce != now && inonce != (now - 1) {
-                        debug!("invalid nonce(!now)");
-                        return false;
-                    }
-                }
-                Err(_) => {
-                    debug!("invalid nonce(!u64)");
-                    return false;
-                }
+    debug!("{} sent {} bytes, {:?}", src_wp, buf.len(), msg); // {:?} has its own quotes
+
+    let door = doors
+        .get(&door_hint(&msg))
+        .filter(|d| listener.serves(&d.name))
+        .ok_or(RejectReason::UnknownDoor)?;
+    record.door = Some(door.name.to_owned());
+    span.record("door", door.name.as_str());
+    drop(decode);
+
+    let verify = info_span!("verify").entered();
+    let now = unix_now();
+    let (snonce, key) = door.keys.verify(&msg, now)?;
+    record.key = Some(key.id.to_owned());
+    span.record("key", key.id.as_str());
+    if nonce_cache.get(&snonce).is_some() {
+        // Arguably, an attacker could flood this cache with valid
+        // nonces and roll this one right off so it could be reused;
+        // but ... then in that case they can generate valid nonces, so
+        // who really cares if they can flood this cache?
+        return Err(RejectReason::ReusedNonce);
+    }
+    nonce_cache.put(snonce.to_owned(), true);
+
+    let payload = Payload::parse(&snonce)?;
+    record.identity = payload.identity.to_owned();
+    if let Some(i) = &payload.identity {
+        span.record("identity", i.as_str());
+    }
+    if !listener.fresh(payload.timestamp, now) {
+        return Err(RejectReason::StaleTimestamp);
+    }
+    drop(verify);
+
+    let _policy = info_span!("policy").entered();
+    if !key.signs_for(payload.identity.as_deref()) {
+        warn!(
+            "{} door={} key={} isn't for identity={}",
+            src_wp,
+            door.name,
+            key.id,
+            payload.identity.as_deref().unwrap_or("-")
+        );
+        return Err(RejectReason::IdentityNotAllowed);
+    }
+    if !door.policy.allows(payload.identity.as_deref()) {
+        return Err(RejectReason::IdentityNotAllowed);
+    }
+    if let Some(r) = revocations.check(&door.name, &key.id, payload.identity.as_deref(), now) {
+        warn!(
+            "{} REVOKED door={} identity={} key={}: {}",
+            src_wp,
+            door.name,
+            payload.identity.as_deref().unwrap_or("-"),
+            key.id,
+            r.reason
+        );
+        return Err(RejectReason::Revoked);
+    }
+
+    info!(
+        "{} VERIFIED door={} listener={} key={}",
+        src_wp, door.name, listener.tag, key.id
+    );
+    // so we can tell who's still on an old key when it's time to rotate
+    if let Some(newest) = door.keys.current(now).filter(|k| k.id != key.id) {
+        info!(
+            "{} identity={} is still using key {} (the newest is {})",
+            src_wp,
+            payload.identity.as_deref().unwrap_or("-"),
+            key.id,
+            newest.id
+        );
+    }
+    Ok((door.clone(), payload, snonce, key.to_owned()))
+}
+
+/// feed an nft script to `nft -f -`
+async fn nft_script(nft: &NftSettings, script: &str, runner: &Runner) -> bool {
+    let nowhere = SocketAddr::from(([0, 0, 0, 0], 0));
+    let vars = KnockVars::new(&nowhere, &nowhere, 0, unix_now());
+
+    match runner
+        .run_with_input(&nft.load_command(), &vars, Some(script.as_bytes()))
+        .await
+    {
+        Ok(_) => true,
+        Err(e) => {
+            error!("nft -f failed: {}", e);
+            false
+        }
+    }
+}
+
+/// check every command door might run, without waiting for a knock to find
+/// out they're broken
+async fn preflight(settings: &Settings, runner: &Runner) -> Preflight {
+    let mut p = Preflight::new();
+
+    let mut doors = settings.doors.values().collect::<Vec<&Arc<Door>>>();
+    doors.sort_by_key(|d| d.name.to_owned());
+    for door in doors {
+        for (idx, a) in door.pipeline.actions.iter().enumerate() {
+            let what = format!("door {} {}", door.name, a.label(idx));
+            p.command(&what, &a.command, &door.name);
+            if let Some(c) = &a.undo {
+                p.command(&format!("{what} undo"), c, &door.name);
+            }
+            if let Some(c) = &a.extend {
+                p.command(&format!("{what} extend"), c, &door.name);
+            }
+            if let Some(c) = &a.check {
+                p.check(&format!("{what} check"), c, None, &door.name, &door.runner(runner))
+                    .await;
+            }
+        }
+    }
+
+    let h = &settings.hooks;
+    for (name, hook) in [
+        ("on_reject", &h.on_reject),
+        ("on_verify", &h.on_verify),
+        ("on_grant", &h.on_grant),
+        ("on_expire", &h.on_expire),
+        ("on_command_failure", &h.on_command_failure),
+    ] {
+        if let Some(c) = hook {
+            p.command(&format!("hook {name}"), c, DEFAULT_DOOR);
+        }
+    }
+    if let Some(c) = &settings.rate_limit.ban_command {
+        p.command("rate_limit ban_command", c, DEFAULT_DOOR);
+    }
+    if let Some(c) = &settings.rate_limit.unban_command {
+        p.command("rate_limit unban_command", c, DEFAULT_DOOR);
+    }
+    for a in settings.allowlists.iter() {
+        p.template(&format!("allowlist {} line", a.path), &a.line, &["expires"]);
+        if let Some(c) = &a.reload {
+            p.command(&format!("allowlist {} reload", a.path), c, DEFAULT_DOOR);
+        }
+    }
+    if settings.nft.bootstrap {
+        let ruleset = settings.nft.ruleset();
+        p.check(
+            "nft ruleset",
+            &settings.nft.check_command(),
+            Some(ruleset.as_bytes()),
+            DEFAULT_DOOR,
+            runner,
+        )
+        .await;
+    }
+
+    p
+}
+
+/// what preflight didn't like, on one line
+fn preflight_problems(p: &Preflight) -> String {
+    p.problems()
+        .iter()
+        .map(|f| format!("{}: {}", f.what, f.problem.as_deref().unwrap_or_default()))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// preflight (unless it's off) and set up the nft table (if door's doing
+/// that); this is the helper's job with privsep on
+async fn setup(settings: &Settings, runner: &Runner) -> Result<(), ExitCode> {
+    if !settings.no_preflight {
+        let p = preflight(settings, runner).await;
+        if !p.ok() {
+            for f in p.problems() {
+                error!("preflight: {}: {}", f.what, f.problem.as_deref().unwrap_or_default());
             }
+            error!("not starting; fix the above or run with --no-preflight");
+            return Err(ExitCode::from(29));
         }
-        Err(_) => {
-            debug!("invalid signature");
-            return false;
+        debug!("preflight: {} things checked, all ok", p.findings.len());
+    }
+
+    if settings.nft.bootstrap {
+        if !nft_script(&settings.nft, &settings.nft.ruleset(), runner).await {
+            error!("couldn't set up nft table inet {}", settings.nft.table);
+            return Err(ExitCode::from(28));
         }
-    };
+        info!("set up nft table inet {}", settings.nft.table);
+    }
+    Ok(())
+}
+
+/// undo setup() on the way out
+async fn teardown(settings: &Settings, runner: &Runner) -> ExitCode {
+    if settings.nft.bootstrap && settings.nft.teardown {
+        if !nft_script(&settings.nft, &settings.nft.teardown_script(), runner).await {
+            error!("couldn't remove nft table inet {}", settings.nft.table);
+            return ExitCode::from(28);
+        }
+        info!("removed nft table inet {}", settings.nft.table);
+    }
+    ExitCode::from(0)
+}
+
+/// door --preflight: say how it went and exit
+#[tokio::main]
+async fn preflight_report(settings: &Settings) -> ExitCode {
+    let runner = Runner::new(
+        settings.max_commands,
+        std::time::Duration::from_secs(settings.command_timeout),
+    )
+    .with_max_queued(settings.max_queued);
+    let p = preflight(settings, &runner).await;
+
+    for f in p.findings.iter() {
+        match &f.problem {
+            None => println!("ok    {}", f.what),
+            Some(e) => println!("FAIL  {}: {}", f.what, e),
+        }
+    }
+    match p.ok() {
+        true => ExitCode::from(0),
+        false => ExitCode::from(29),
+    }
+}
 
-    info!("{} VERIFIED", src_wp);
-    true
+/// door audit: print the matching records (or a summary of them) and exit
+fn audit_report(settings: &AuditSettings, query: &Query, summary: bool) -> ExitCode {
+    use std::io::Write;
+
+    if settings.path.is_empty() {
+        eprintln!("there's no audit log; set [audit] path in the config, or use --file");
+        return ExitCode::from(27);
+    }
+    let mut sum = Summary::default();
+    let mut out = std::io::stdout().lock();
+    let res = audit::scan(&settings.files(), query, |r| match summary {
+        true => sum.add(&r),
+        false => {
+            // e.g. a closed pipe; the rest would go nowhere too
+            let _ = writeln!(out, "{}", serde_json::to_string(&r).expect("records serialize"));
+        }
+    });
+    match res {
+        Ok(bad) => {
+            if summary {
+                print!("{sum}");
+            }
+            if bad > 0 {
+                eprintln!("skipped {bad} lines that weren't audit records");
+            }
+            ExitCode::from(0)
+        }
+        Err(e) => {
+            eprintln!("couldn't read the audit log: {e}");
+            ExitCode::from(27)
+        }
+    }
+}
+
+/// the privileged half of door with privsep on: set up, tell the listener
+/// it can go ahead (or what to exit with), then do what it asks until it
+/// goes away, and tear down
+#[tokio::main]
+async fn helper_main(settings: &Settings, stream: UnixStream) -> ExitCode {
+    use std::io::Write;
+
+    // signals are for the listener; the helper goes when it does, after it's
+    // done whatever it was asked to do
+    let mut sigterm = signal(SignalKind::terminate()).expect("signal handlers can be installed");
+    let mut sigint = signal(SignalKind::interrupt()).expect("signal handlers can be installed");
+    let mut sighup = signal(SignalKind::hangup()).expect("signal handlers can be installed");
+    task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sigterm.recv() => (),
+                _ = sigint.recv() => (),
+                _ = sighup.recv() => (),
+            }
+            debug!("helper: ignoring the signal, waiting on the listener");
+        }
+    });
+
+    let runner = Runner::new(
+        settings.max_commands,
+        std::time::Duration::from_secs(settings.command_timeout),
+    )
+    .with_max_queued(settings.max_queued);
+    let mut stream = stream;
+    // the listener gets "ready" or nothing; when it's nothing it takes our
+    // exit code for its own
+    if let Err(code) = setup(settings, &runner).await {
+        return code;
+    }
+    if stream.write_all(b"ready\n").is_err() {
+        return ExitCode::from(28);
+    }
+
+    stream.set_nonblocking(true).expect("sockets can be non-blocking");
+    let stream = tokio::net::UnixStream::from_std(stream).expect("the runtime takes unix sockets");
+    // the listener asks for a reload after it's read the new config itself;
+    // the helper reads (and preflights) it again rather than take its word
+    let preflight_runner = runner.to_owned();
+    let helper = Helper::new(
+        settings.doors.to_owned(),
+        settings.rate_limit.to_owned(),
+        settings.keepalive.extend,
+        runner.to_owned(),
+    )
+    .with_reloader(Arc::new(move || {
+        let runner = preflight_runner.to_owned();
+        Box::pin(async move {
+            let new = get_args().map_err(|e| format!("{e:?}"))?;
+            if !new.no_preflight {
+                let p = preflight(&new, &runner).await;
+                if !p.ok() {
+                    return Err(format!("preflight: {}", preflight_problems(&p)));
+                }
+            }
+            Ok(Rules {
+                doors: new.doors,
+                rate_limit: new.rate_limit,
+                max_extend: new.keepalive.extend,
+            })
+        })
+    }));
+    info!("helper: ready");
+    Arc::new(helper).serve(stream).await;
+
+    teardown(settings, &runner).await
 }
 
 #[tokio::main]
 async fn listen_to_msgs(
-    listen: String,
-    hf: &mut HMACFrobnicator,
-    command: &str,
+    settings: &Settings,
+    sockets: Vec<std::net::UdpSocket>,
+    helper: Option<UnixStream>,
+    table: Option<File>,
+    services: Services,
     nonce_cache: &mut LruCache<String, bool>,
-) {
-    let mut buf = [0; 256];
-    let socket = UdpSocket::bind(listen.as_str()).await.expect("couldn't bind to socket");
+) -> ExitCode {
+    let debug_delay = std::time::Duration::from_millis(
+        std::env::var("KNOCK_DOOR_DEBUG_DELAY")
+            .unwrap_or_else(|_| "0".to_string())
+            .parse::<u64>()
+            .unwrap_or(0),
+    );
+    // I can't think of anything that would make the delay useful outside
+    // debugs but decided to leave KNOCK_DOOR_DEBUG_DELAY exposed regardless.
+    let mut settings = settings.to_owned();
+    let timeout = std::time::Duration::from_secs(settings.command_timeout);
+    let mut ctx = Ctx {
+        hooks: settings.hooks.to_owned(),
+        runner: Runner::new(settings.max_commands, timeout)
+            .with_max_queued(settings.max_queued)
+            .with_delay(debug_delay),
+        // hooks get their own queue so a pile of on_reject hooks can't hold up a grant
+        hook_runner: Runner::new(settings.max_commands, timeout).with_max_queued(settings.max_queued),
+        grants: Grants::shared(settings.grace),
+        allowlists: Allowlists::new(
+            settings.allowlists.to_owned(),
+            std::time::Duration::from_millis(settings.allowlist_debounce),
+        ),
+        audit: AuditLog::new(&settings.audit),
+        metrics: Metrics::new(),
+        events: match settings.events.enabled {
+            true => EventStream::new(settings.events.buffer),
+            false => EventStream::default(),
+        },
+        // here rather than in main, since the exporter has threads, and
+        // there mustn't be any when the helper's forked off
+        tracer: match settings.tracing.otlp.as_str() {
+            "" => None,
+            url => match (settings.tracing.provider()).and_then(|p| trace::plug_in(&services.otlp, &p).map(|_| p))
+            {
+                Ok(p) => {
+                    info!("sending spans to {}", url);
+                    Some(p)
+                }
+                Err(e) => {
+                    error!("tracing: couldn't send spans to {}: {}", url, e);
+                    None
+                }
+            },
+        },
+        inflight: InFlight::default(),
+        helper: helper.map(|h| {
+            h.set_nonblocking(true).expect("sockets can be non-blocking");
+            HelperClient::new(tokio::net::UnixStream::from_std(h).expect("the runtime takes unix sockets"))
+        }),
+    };
+
+    // with privsep on, the helper has done this already
+    if ctx.helper.is_none() {
+        if let Err(code) = setup(&settings, &ctx.runner).await {
+            return code;
+        }
+    }
+
+    // each listener gets a task that hands what it hears to the loop below,
+    // which owns the nonce cache and the rate limiter
+    let (tx, mut rx) = mpsc::channel::<(u64, SocketAddr, Vec<u8>)>(64);
+    let mut listeners = Listeners::new(tx);
+    for (idx, (spec, socket)) in settings.listen.iter().zip(sockets).enumerate() {
+        listeners.add(spec.to_owned(), wants_v6only(&settings.listen, idx), socket);
+    }
+
+    task::spawn(ctx.allowlists.clone().run(ctx.grants.clone(), ctx.runner.clone()));
+    if let Some(l) = services.scrapes {
+        l.set_nonblocking(true).expect("sockets can be non-blocking");
+        let l = tokio::net::TcpListener::from_std(l).expect("the runtime takes bound sockets");
+        task::spawn(metrics::serve(l, ctx.metrics.clone(), settings.metrics.stale_after));
+    }
+    // the control socket's requests are handled here in the loop, like datagrams
+    let (ctl_tx, mut ctl_rx) = mpsc::channel::<control::Call>(8);
+    if let Some((l, access)) = services.ctl {
+        l.set_nonblocking(true).expect("sockets can be non-blocking");
+        let l = tokio::net::UnixListener::from_std(l).expect("the runtime takes bound sockets");
+        task::spawn(control::serve(l, access, ctl_tx));
+    }
+    if let Some((l, access)) = services.events {
+        l.set_nonblocking(true).expect("sockets can be non-blocking");
+        let l = tokio::net::UnixListener::from_std(l).expect("the runtime takes bound sockets");
+        task::spawn(stream::serve(l, access, ctx.events.clone()));
+    }
 
-    // we use listen.as_str() above so we don't "move" listen to the bind()
-    // if we did, we'd get an error about using listen after move on the next line
-    info!("listening to {}", listen);
+    let mut limiter = Limiter::new(settings.rate_limit.to_owned());
+    let mut sigterm = signal(SignalKind::terminate()).expect("signal handlers can be installed");
+    let mut sigint = signal(SignalKind::interrupt()).expect("signal handlers can be installed");
+    let mut sighup = signal(SignalKind::hangup()).expect("signal handlers can be installed");
+    let mut watcher = watch_files(&settings);
+    let mut ticker = tokio::time::interval(std::time::Duration::from_secs(1));
+    let mut keepalive_ticker = tokio::time::interval(std::time::Duration::from_secs(settings.keepalive.interval));
+    let table = Arc::new(Mutex::new(table));
+    let (conns_tx, mut conns_rx) = mpsc::channel(1);
 
     loop {
-        let (amt, src_addr) = socket.recv_from(&mut buf).await.expect("couldn't read from buffer");
+        let (id, src_addr, buf) = tokio::select! {
+            r = rx.recv() => match r {
+                Some(v) => v,
+                None => {
+                    error!("no listeners left");
+                    break;
+                }
+            },
+            _ = sigterm.recv() => {
+                info!("SIGTERM, shutting down");
+                break;
+            }
+            _ = sigint.recv() => {
+                info!("SIGINT, shutting down");
+                break;
+            }
+            _ = sighup.recv() => {
+                info!("SIGHUP, reloading the config");
+                let _ = reload_and_watch(
+                    &mut settings, &mut listeners, &mut ctx, &mut limiter, &mut watcher, &mut keepalive_ticker,
+                ).await;
+                continue;
+            }
+            Some((req, reply)) = ctl_rx.recv() => {
+                if req != CtlRequest::Reload {
+                    control(req, reply, &settings, &listeners, &ctx, &limiter, nonce_cache);
+                    continue;
+                }
+                info!("control: reloading the config");
+                let res = reload_and_watch(
+                    &mut settings, &mut listeners, &mut ctx, &mut limiter, &mut watcher, &mut keepalive_ticker,
+                ).await;
+                let _ = reply.send(match res {
+                    Ok(()) => CtlReply::ok("reloaded"),
+                    Err(e) => CtlReply::err(e),
+                });
+                continue;
+            }
+            files = watcher.changed() => {
+                reload_secrets(&mut settings.doors, &settings.revocations, &files);
+                if settings.revocations.file().is_some_and(|f| files.iter().any(|v| v == f)) {
+                    let keys = settings.doors.values().map(|d| (d.name.as_str(), &d.keys));
+                    match settings.revocations.reload().and_then(|v| v.enforceable(keys).map(|_| v)) {
+                        Ok(v) => {
+                            info!("reloaded the revocation list, {} entries", v.len());
+                            settings.revocations = v;
+                        }
+                        Err(e) => error!("couldn't reload the revocation list, keeping the old one: {}", e),
+                    }
+                }
+                continue;
+            }
+            _ = ticker.tick() => {
+                let expired = ctx.grants.lock().expect("grants lock").expire(unix_now());
+                if !expired.is_empty() {
+                    ctx.allowlists.changed();
+                }
+                for g in expired {
+                    debug!("expired {} door={}", g.vars.ip, g.vars.door);
+                    ctx.event(Event::knock(EventKind::Expire, &g.src, &g.vars), &g.vars);
+                }
+                for prefix in limiter.expire(Instant::now()) {
+                    info!("unbanned {}", prefix);
+                    ban_command(limiter.settings(), true, prefix, &listeners.first_local(), 0, &ctx);
+                }
+                let grants = ctx.grants.lock().expect("grants lock").len();
+                ctx.metrics.gauges(grants, nonce_cache.len(), limiter.tracked(), limiter.banned());
+                ctx.metrics.refused(ctx.runner.refused() + ctx.hook_runner.refused());
+                ctx.metrics.audit_dropped(ctx.audit.dropped());
+                ctx.metrics.heartbeat();
+                continue;
+            }
+            _ = keepalive_ticker.tick(), if settings.keepalive.enabled => {
+                read_conntrack(&settings.keepalive.path, &table, &conns_tx);
+                continue;
+            }
+            Some(conns) = conns_rx.recv() => {
+                keepalive(&settings.keepalive, conns, &settings.doors, &ctx);
+                continue;
+            }
+        };
+        // a listener a reload just stopped
+        let listener = match listeners.map.get(&id) {
+            Some(v) => v,
+            None => continue,
+        };
+        let local_addr = listener.local;
         let src_with_port = src_addr.to_string();
-        let src = src_with_port[..src_with_port.find(':').unwrap()].to_string();
+        ctx.metrics.received();
+        let mut knock = Knock::new(&src_addr, buf.len(), &listener.spec.tag);
+
+        // rate limits come before we spend any time on sha256 and parsing;
+        // these don't fire on_reject hooks, that'd just amplify a flood
+        let dropped = match limiter.check(src_addr.ip(), Instant::now()) {
+            Verdict::Allow => None,
+            Verdict::Limited => Some(RejectReason::RateLimited),
+            Verdict::Banned => Some(RejectReason::Banned),
+        };
+        if let Some(reason) = dropped {
+            debug!("{} dropped: {}", src_with_port, reason);
+            ctx.metrics.dropped(reason);
+            knock.record.decision = audit::Decision::Dropped;
+            knock.record.reason = Some(reason);
+            ctx.audit.write(&knock.record);
+            continue;
+        }
+
+        match process_payload(
+            &src_with_port,
+            &buf,
+            &settings.doors,
+            &settings.revocations,
+            &listener.spec,
+            nonce_cache,
+            &mut knock,
+        )
+        .await
+        {
+            Ok((door, payload, nonce, key)) => {
+                ctx.metrics.verified();
+                let identity = payload.identity.as_deref();
+                let duration = door.policy.grant_duration(payload.duration, identity);
+                let mut vars = KnockVars::new(&src_addr, &local_addr, duration, payload.timestamp);
+                vars.door = door.name.to_owned();
+                vars.listener = listener.spec.tag.to_owned();
+                if let Some(i) = identity {
+                    vars.identity = i.to_string();
+                }
+                if payload.duration.is_some() {
+                    debug!("{} asked for {:?}s, gets {}s", vars.ip, payload.duration, duration);
+                }
+                let ack = match settings.ack && payload.ack {
+                    true => Some(AckTo {
+                        nonce,
+                        socket: listener.socket.clone(),
+                        key: key.hf,
+                    }),
+                    false => None,
+                };
+
+                let now = unix_now();
+                let decision = ctx.grants.lock().expect("grants lock").decide(&src_addr, &vars, now);
+                match decision {
+                    Decision::New => info!("new grant for {} door={}", vars.ip, vars.door),
+                    Decision::Duplicate { expires } => {
+                        info!(
+                            "{} already allowed door={} until {}, not running anything (dedup)",
+                            vars.ip, vars.door, expires
+                        );
+                        if let Some(to) = ack {
+                            send_ack(&to, &src_addr, Some(expires.saturating_sub(unix_now())));
+                        }
+                        knock.record.decision = audit::Decision::Duplicate;
+                        knock.record.expires = Some(expires);
+                        ctx.audit.write(&knock.record);
+                        continue;
+                    }
+                    Decision::Extend { previous, expires } => {
+                        info!(
+                            "extending grant for {} door={} from {} to {}",
+                            vars.ip, vars.door, previous, expires
+                        );
+                        // an earlier grant can outlast what was asked for this
+                        // time, and the extend commands and ack go by the grant
+                        vars.duration = expires - now;
+                    }
+                }
+
+                let ctx = ctx.clone();
+
+                ctx.event(Event::knock(EventKind::Verify, &src_addr, &vars), &vars);
+                ctx.inflight
+                    .clone()
+                    .spawn(async move { allow_ip(src_addr, &vars, decision, ack, &door, &ctx, knock).await });
+            }
+            Err(reason) => {
+                debug!("{} rejected: {}", src_with_port, reason);
+                knock.record.reason = Some(reason);
+                ctx.audit.write(&knock.record);
+                ctx.metrics.rejected(reason);
+                let mut vars = KnockVars::new(&src_addr, &local_addr, 0, unix_now());
+                vars.listener = listener.spec.tag.to_owned();
+                ctx.event(Event::reject(&src_addr, reason), &vars);
+
+                if let Some(prefix) = limiter.strike(src_addr.ip(), Instant::now()) {
+                    let ban_time = limiter.settings().ban_time;
+                    warn!("banned {} for {}s after too many invalid knocks", prefix, ban_time);
+                    ban_command(limiter.settings(), false, prefix, &local_addr, ban_time, &ctx);
+                }
+            }
+        }
+    }
+
+    shut_down(&settings, &ctx, &mut sigterm, &mut sigint).await;
+    let audit = ctx.audit.clone();
+    let _ = task::spawn_blocking(move || audit.flush()).await;
+    if let Some(p) = ctx.tracer.take() {
+        // send whatever spans are left
+        let _ = task::spawn_blocking(move || p.shutdown()).await;
+    }
+    if settings.control.enabled {
+        // with privsep on, landlock won't let us; the next start clears it up
+        let _ = std::fs::remove_file(&settings.control.socket);
+    }
+    if settings.events.enabled {
+        let _ = std::fs::remove_file(&settings.events.socket);
+    }
+
+    match ctx.helper {
+        // the helper tears down once it sees we're gone
+        Some(_) => ExitCode::from(0),
+        None => teardown(&settings, &ctx.runner).await,
+    }
+}
+
+/// reload() and, if it worked, watch the new config's files
+async fn reload_and_watch(
+    settings: &mut Settings,
+    listeners: &mut Listeners,
+    ctx: &mut Ctx,
+    limiter: &mut Limiter,
+    watcher: &mut FileWatcher,
+    keepalive_ticker: &mut tokio::time::Interval,
+) -> Result<(), String> {
+    let interval = settings.keepalive.interval;
+    let res = reload(settings, listeners, ctx, limiter).await;
+    match &res {
+        Ok(()) => {
+            info!("reloaded the config");
+            *watcher = watch_files(settings);
+        }
+        Err(e) => error!("not reloading, still using the old config: {}", e),
+    }
+    if settings.keepalive.interval != interval {
+        *keepalive_ticker = tokio::time::interval(Duration::from_secs(settings.keepalive.interval));
+    }
+    res
+}
+
+/// answer a control socket request (other than a reload, which needs more of
+/// the loop); the ones that run commands reply once they're done
+fn control(
+    req: CtlRequest,
+    reply: tokio::sync::oneshot::Sender<CtlReply>,
+    settings: &Settings,
+    listeners: &Listeners,
+    ctx: &Ctx,
+    limiter: &Limiter,
+    nonce_cache: &LruCache<String, bool>,
+) {
+    let answer = match req {
+        CtlRequest::Grants => {
+            let grants = ctx.grants.lock().expect("grants lock").list();
+            CtlReply::ok(grants.iter().map(GrantInfo::from).collect::<Vec<GrantInfo>>())
+        }
+        CtlRequest::Stats => {
+            let grants = ctx.grants.lock().expect("grants lock").len();
+            let mut doors = settings.doors.keys().cloned().collect::<Vec<String>>();
+            doors.sort();
+            CtlReply::ok(serde_json::json!({
+                "grants": grants,
+                "in_flight": ctx.inflight.len(),
+                "replay_cache": nonce_cache.len(),
+                "rate_limited_sources": limiter.tracked(),
+                "banned_sources": limiter.banned(),
+                "listeners": listeners.map.values().map(|l| l.spec.describe()).collect::<Vec<String>>(),
+                "doors": doors,
+                "revocations": settings.revocations.len(),
+                "counters": ctx.metrics.counters(),
+            }))
+        }
+        CtlRequest::ReplayCache => CtlReply::ok(serde_json::json!({
+            "size": nonce_cache.len(),
+            "capacity": nonce_cache.cap(),
+        })),
+        CtlRequest::Reload => unreachable!("the loop does reloads itself"),
+        CtlRequest::Revoke { ip, door } => match ip.parse::<IpAddr>() {
+            Ok(ip) => return ctl_revoke(ip, door.as_deref(), reply, settings, ctx),
+            Err(_) => CtlReply::err(format!("{ip:?} isn't an IP")),
+        },
+        CtlRequest::Grant {
+            ip,
+            door,
+            identity,
+            duration,
+        } => match ctl_vars(
+            &ip,
+            door.as_deref(),
+            identity,
+            duration,
+            settings,
+            &listeners.first_local(),
+        ) {
+            Ok((door, vars)) => return ctl_grant(door, vars, reply, ctx),
+            Err(e) => CtlReply::err(e),
+        },
+    };
+    let _ = reply.send(answer);
+}
+
+/// door ctl revoke: take away `ip`'s grants (on `door`, or all of them) and
+/// run the undo commands
+fn ctl_revoke(
+    ip: IpAddr,
+    door: Option<&str>,
+    reply: tokio::sync::oneshot::Sender<CtlReply>,
+    settings: &Settings,
+    ctx: &Ctx,
+) {
+    let gone = {
+        let mut grants = ctx.grants.lock().expect("grants lock");
+        grants
+            .list()
+            .into_iter()
+            .filter(|g| g.src.ip() == ip && door.is_none_or(|d| d == g.vars.door))
+            .filter_map(|g| grants.remove(&g.vars.door, ip))
+            .collect::<Vec<Grant>>()
+    };
+    if gone.is_empty() {
+        let _ = reply.send(CtlReply::err(format!("{ip} has no grants")));
+        return;
+    }
+    ctx.allowlists.changed();
+    let gone = gone
+        .into_iter()
+        .filter_map(|g| settings.doors.get(&g.vars.door).map(|d| (d.clone(), g)))
+        .collect::<Vec<(Arc<Door>, Grant)>>();
+
+    let ctx2 = ctx.clone();
+    ctx.inflight.spawn(async move {
+        let mut revoked = vec![];
+        for (door, g) in gone {
+            let res = ctx2.revoke(&door, &g.vars).await;
+            match res.success {
+                true => info!("control: revoked {} door={}", g.vars.ip, g.vars.door),
+                false => error!("control: failed to revoke {} door={}", g.vars.ip, g.vars.door),
+            }
+            revoked.push(serde_json::json!({
+                "door": g.vars.door,
+                "ip": g.vars.ip,
+                "success": res.success,
+                "steps": res.steps,
+            }));
+            ctx2.event(Event::knock(EventKind::Expire, &g.src, &g.vars), &g.vars);
+        }
+        let _ = reply.send(CtlReply::ok(revoked));
+    });
+}
+
+/// the door and vars for door ctl grant, if the door's policy allows it
+fn ctl_vars(
+    ip: &str,
+    door: Option<&str>,
+    identity: Option<String>,
+    duration: Option<u64>,
+    settings: &Settings,
+    local: &SocketAddr,
+) -> Result<(Arc<Door>, KnockVars), String> {
+    let ip = ip.parse::<IpAddr>().map_err(|_| format!("{ip:?} isn't an IP"))?;
+    let name = door.unwrap_or(DEFAULT_DOOR);
+    let door = settings
+        .doors
+        .get(name)
+        .ok_or_else(|| format!("there's no door {name:?}"))?;
+    if let Some(i) = identity.as_deref().filter(|i| !valid_token(i)) {
+        return Err(format!("{i:?} isn't a valid identity"));
+    }
+    if !door.policy.allows(identity.as_deref()) {
+        return Err(format!(
+            "door {} doesn't let {} in",
+            door.name,
+            identity.as_deref().unwrap_or("anonymous knocks")
+        ));
+    }
+
+    let src = SocketAddr::new(ip, 0);
+    let duration = door.policy.grant_duration(duration, identity.as_deref());
+    let mut vars = KnockVars::new(&src, local, duration, unix_now());
+    vars.door = door.name.to_owned();
+    vars.listener = "ctl".to_string();
+    if let Some(i) = identity {
+        vars.identity = i;
+    }
+    Ok((door.clone(), vars))
+}
+
+/// door ctl grant: run the door's pipeline for vars.ip, as a knock would
+fn ctl_grant(door: Arc<Door>, vars: KnockVars, reply: tokio::sync::oneshot::Sender<CtlReply>, ctx: &Ctx) {
+    let src = SocketAddr::new(vars.ip.parse().expect("checked by ctl_vars"), 0);
+    let decision = ctx.grants.lock().expect("grants lock").decide(&src, &vars, unix_now());
+    if let Decision::Duplicate { expires } = decision {
+        let _ = reply.send(CtlReply::ok(
+            serde_json::json!({ "door": vars.door, "ip": vars.ip, "expires": expires }),
+        ));
+        return;
+    }
+
+    let ctx2 = ctx.clone();
+    ctx.inflight.spawn(async move {
+        let ctx = ctx2;
+        let res = ctx
+            .pipeline(&door, &vars, matches!(decision, Decision::Extend { .. }))
+            .await;
+        if !res.success {
+            error!("control: failed to allow {} door={}", vars.ip, vars.door);
+            ctx.grants
+                .lock()
+                .expect("grants lock")
+                .revert(&vars.door, src.ip(), decision);
+            ctx.allowlists.changed();
+            let _ = reply.send(CtlReply {
+                data: Some(serde_json::json!({ "steps": res.steps })),
+                ..CtlReply::err("the commands failed")
+            });
+            return;
+        }
+        info!("control: allowed {} door={} for {}s", vars.ip, vars.door, vars.duration);
+        ctx.allowlists.changed();
+        let expires = ctx
+            .grants
+            .lock()
+            .expect("grants lock")
+            .get(&vars.door, src.ip())
+            .map(|g| g.expires);
+        let _ = reply.send(CtlReply::ok(serde_json::json!({
+            "door": vars.door,
+            "ip": vars.ip,
+            "duration": vars.duration,
+            "expires": expires,
+            "steps": res.steps,
+        })));
+        ctx.event(Event::knock(EventKind::Grant, &src, &vars).with_steps(res.steps), &vars);
+    });
+}
+
+/// re-read the config and, if it all checks out, switch to it: the new
+/// doors, secrets, commands, hooks, limits and listeners. The grants, bans
+/// and nonce cache carry over, and so do the sockets of listeners that are
+/// still bound the same way. Anything that's wrong leaves the old config as
+/// it was.
+async fn reload(
+    settings: &mut Settings,
+    listeners: &mut Listeners,
+    ctx: &mut Ctx,
+    limiter: &mut Limiter,
+) -> Result<(), String> {
+    let mut new = get_args().map_err(|e| format!("{e:?}"))?;
+    warn_about_default_secret(&new.doors);
+
+    // these were used up at startup (or belong to the helper); they keep
+    // their old values until door restarts
+    for (what, changed) in [
+        ("privsep", new.privsep != settings.privsep),
+        ("nft", new.nft != settings.nft),
+        ("syslog", new.syslog != settings.syslog),
+        ("verbose", new.verbose != settings.verbose),
+        ("command_timeout", new.command_timeout != settings.command_timeout),
+        ("max_commands", new.max_commands != settings.max_commands),
+        ("max_queued", new.max_queued != settings.max_queued),
+        ("audit", new.audit != settings.audit),
+        ("metrics", new.metrics != settings.metrics),
+        ("control", new.control != settings.control),
+        ("events", new.events != settings.events),
+        ("tracing", new.tracing != settings.tracing),
+        ("compact", new.compact != settings.compact),
+        (
+            "allowlist_debounce_ms",
+            new.allowlist_debounce != settings.allowlist_debounce,
+        ),
+    ] {
+        if changed {
+            warn!("reload: {} only changes when door restarts", what);
+        }
+    }
+    new.privsep = settings.privsep.to_owned();
+    new.nft = settings.nft.to_owned();
+    new.syslog = settings.syslog;
+    new.verbose = settings.verbose;
+    new.command_timeout = settings.command_timeout;
+    new.max_commands = settings.max_commands;
+    new.max_queued = settings.max_queued;
+    new.audit = settings.audit.to_owned();
+    new.metrics = settings.metrics.to_owned();
+    new.control = settings.control.to_owned();
+    new.events = settings.events.to_owned();
+    new.tracing = settings.tracing.to_owned();
+    new.compact = settings.compact;
+    new.allowlist_debounce = settings.allowlist_debounce;
+    new.readable = settings.readable.to_owned();
+
+    // with privsep on, the helper checks the commands it'd be running
+    if ctx.helper.is_none() && !new.no_preflight {
+        let p = preflight(&new, &ctx.runner).await;
+        if !p.ok() {
+            return Err(format!("preflight: {}", preflight_problems(&p)));
+        }
+    }
+
+    // keep the sockets we have where we can, and bind the rest before
+    // committing to anything
+    let mut kept: HashMap<u64, Listen> = HashMap::new();
+    let mut bound = vec![];
+    for (idx, spec) in new.listen.iter().enumerate() {
+        let v6only = wants_v6only(&new.listen, idx);
+        match listeners.find(spec, v6only, &kept) {
+            Some(id) => {
+                kept.insert(id, spec.to_owned());
+            }
+            None => {
+                let socket = spec
+                    .bind(v6only)
+                    .map_err(|e| format!("couldn't bind to {}: {}", spec.describe(), e))?;
+                bound.push((spec.to_owned(), v6only, socket));
+            }
+        }
+    }
+
+    if let Some(h) = &ctx.helper {
+        let reply = h.request(Request::Reload).await;
+        if !reply.success {
+            return Err(format!(
+                "the helper wouldn't: {}",
+                reply.error.as_deref().unwrap_or("it didn't say why")
+            ));
+        }
+    }
+
+    // and we're committed
+    listeners.map.retain(|id, l| match kept.remove(id) {
+        Some(spec) => {
+            l.spec = spec;
+            true
+        }
+        None => {
+            info!("stopped listening to {}", l.spec.describe());
+            l.task.abort();
+            false
+        }
+    });
+    for (spec, v6only, socket) in bound {
+        listeners.add(spec, v6only, socket);
+    }
+    ctx.hooks = new.hooks.to_owned();
+    ctx.allowlists.replace(new.allowlists.to_owned());
+    ctx.grants.lock().expect("grants lock").set_grace(new.grace);
+    limiter.reconfigure(new.rate_limit.to_owned());
+    *settings = new;
+    Ok(())
+}
+
+/// watch the doors' secret and keyring files, and the revocation list, so
+/// they can be swapped out without a reload
+fn watch_files(settings: &Settings) -> FileWatcher {
+    let mut files = settings
+        .doors
+        .values()
+        .filter_map(|d| d.keys.file())
+        .chain(settings.revocations.file())
+        .map(|f| f.to_string())
+        .collect::<Vec<String>>();
+    files.sort();
+    files.dedup();
+
+    FileWatcher::new(&files).unwrap_or_else(|e| {
+        warn!("not watching the secret files or revocation list: {}", e);
+        FileWatcher::new(&[]).expect("watching nothing works")
+    })
+}
+
+/// the secret (or keyring) `files` changed: read them again and give the
+/// doors that use them the new keys, unless they can't be read (or are empty,
+/// or don't make sense, or would make a revoked identity knock-able), in
+/// which case the doors keep the keys they had
+fn reload_secrets(doors: &mut Doors, revocations: &Revocations, files: &[String]) {
+    for file in files {
+        for door in doors.values_mut() {
+            if door.keys.file() != Some(file) {
+                continue;
+            }
+            let keys = match door.keys.reload() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("couldn't reload door {}'s keys, keeping the old ones: {}", door.name, e);
+                    continue;
+                }
+            };
+            if door.keys == keys {
+                debug!("{} changed, but not door {}'s keys", file, door.name);
+                continue;
+            }
+            if let Err(e) = revocations.enforceable([(door.name.as_str(), &keys)]) {
+                error!("couldn't reload door {}'s keys, keeping the old ones: {}", door.name, e);
+                continue;
+            }
+            let mut d = (**door).clone();
+            d.keys = keys;
+            *door = Arc::new(d);
+            let ids = door.keys.keys().iter().map(|k| k.id.as_str()).collect::<Vec<&str>>();
+            info!("{} changed, door {} has new keys: {}", file, door.name, ids.join(", "));
+        }
+    }
+}
+
+/// we've stopped listening; give the grants (and whatever else) that are
+/// still going up to shutdown_timeout to finish, revoke the active grants if
+/// we're meant to, and bring the allowlists up to date. Another signal means
+/// don't wait.
+async fn shut_down(settings: &Settings, ctx: &Ctx, sigterm: &mut Signal, sigint: &mut Signal) {
+    let deadline = tokio::time::sleep(Duration::from_secs(settings.shutdown_timeout));
+    tokio::pin!(deadline);
+
+    let finish = async {
+        if ctx.inflight.len() > 0 {
+            info!("waiting for {} actions still in flight", ctx.inflight.len());
+        }
+        ctx.inflight.wait().await;
+
+        if settings.revoke_on_shutdown {
+            let grants = ctx.grants.lock().expect("grants lock").list();
+            let mut revoking = task::JoinSet::new();
+            for g in grants {
+                if let Some(door) = settings.doors.get(&g.vars.door) {
+                    let (door, ctx) = (door.clone(), ctx.clone());
+                    revoking.spawn(async move {
+                        let res = ctx.revoke(&door, &g.vars).await;
+                        match res.success {
+                            true => info!("revoked {} door={}", g.vars.ip, g.vars.door),
+                            false => error!("failed to revoke {} door={}", g.vars.ip, g.vars.door),
+                        }
+                    });
+                }
+            }
+            while revoking.join_next().await.is_some() {}
+            ctx.grants.lock().expect("grants lock").expire(u64::MAX);
+        }
+    };
+
+    tokio::select! {
+        _ = finish => (),
+        _ = &mut deadline => warn!("gave up on {} actions after {}s", ctx.inflight.len(), settings.shutdown_timeout),
+        _ = sigterm.recv() => warn!("SIGTERM again, not waiting"),
+        _ = sigint.recv() => warn!("SIGINT again, not waiting"),
+    }
+
+    // the allowlist task may be sitting on a change (debounce), or the
+    // grants just went
+    ctx.allowlists.sync(&ctx.grants, &ctx.runner).await;
+}
+
+/// run the ban (or unban) command, if any, for `prefix`
+fn ban_command(rl: &RateLimitSettings, unban: bool, prefix: IpAddr, local: &SocketAddr, duration: u64, ctx: &Ctx) {
+    let command = match (unban, &rl.ban_command, &rl.unban_command) {
+        (false, Some(c), _) | (true, _, Some(c)) => c.to_owned(),
+        _ => return,
+    };
+    let vars = rl.ban_vars(prefix, local, duration, unix_now());
+    let ctx = ctx.clone();
+
+    ctx.inflight.clone().spawn(async move {
+        let port = vars.port;
+        let res = match &ctx.helper {
+            Some(h) if unban => h.request(Request::Unban { prefix, port }).await.into_result(),
+            Some(h) => h.request(Request::Ban { prefix, port, duration }).await.into_result(),
+            None => match ctx.hook_runner.run(&command, &vars).await {
+                Ok(_) => return,
+                Err(e) => {
+                    error!("fail({}) {}", command.describe(&vars), e);
+                    return;
+                }
+            },
+        };
+        if !res.success {
+            error!("fail({}) in the helper", command.describe(&vars));
+        }
+    });
+}
+
+/// bind every listener; this happens before anything else so door can give
+/// up root (with privsep) as soon as it has its sockets
+fn bind_all(listen: &[Listen]) -> Vec<std::net::UdpSocket> {
+    listen
+        .iter()
+        .enumerate()
+        .map(|(idx, spec)| {
+            spec.bind(wants_v6only(listen, idx))
+                .unwrap_or_else(|e| panic!("couldn't bind to {}: {}", spec.describe(), e))
+        })
+        .collect()
+}
+
+/// fork off the privileged helper, wait for it to be ready, then lock
+/// ourselves down and listen; the helper goes once we're done with it
+fn privsep_main(
+    settings: &Settings,
+    sockets: Vec<std::net::UdpSocket>,
+    table: Option<File>,
+    services: Services,
+    nonce_cache: &mut LruCache<String, bool>,
+) -> ExitCode {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
 
-        if process_payload(amt, &src_with_port, &buf[..amt], hf, nonce_cache).await {
-            let a = src.to_owned();
-            let b = command.to_owned();
+    let (ours, theirs) = UnixStream::pair().expect("socketpairs can be made");
 
-            task::spawn(async move { allow_ip(&a, &b).await });
+    // nothing's started any threads yet, so this is a safe place to fork
+    let child = match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            drop((sockets, table, services, ours));
+            return helper_main(settings, theirs);
+        }
+        Ok(ForkResult::Parent { child }) => child,
+        Err(e) => {
+            error!("privsep: couldn't fork the helper: {}", e);
+            return ExitCode::from(28);
+        }
+    };
+    drop(theirs);
+
+    let helper_status = || match waitpid(child, None) {
+        Ok(WaitStatus::Exited(_, code)) => ExitCode::from(code as u8),
+        _ => ExitCode::from(28),
+    };
+
+    // "ready", or EOF if setup failed; a byte at a time so nothing after the
+    // newline gets eaten
+    let mut status = vec![];
+    let mut byte = [0u8; 1];
+    let mut ours = ours;
+    while byte[0] != b'\n' {
+        match ours.read(&mut byte) {
+            Ok(1) => status.push(byte[0]),
+            _ => break,
         }
     }
+    if status != b"ready\n" {
+        error!("privsep: the helper didn't start");
+        return helper_status();
+    }
+
+    let writable = settings
+        .allowlists
+        .iter()
+        .map(|a| &a.path)
+        .chain(Some(&settings.audit.path).filter(|p| !p.is_empty()))
+        .filter_map(|p| std::path::Path::new(p).parent())
+        .map(|p| match p.to_string_lossy().to_string() {
+            d if d.is_empty() => ".".to_string(),
+            d => d,
+        })
+        .collect::<Vec<String>>();
+    let readable = settings
+        .readable
+        .iter()
+        .filter(|d| std::path::Path::new(d).exists())
+        .cloned()
+        .collect::<Vec<String>>();
+    if let Err(e) = privsep::lock_down(&settings.privsep, &readable, &writable) {
+        error!("privsep: {}", e);
+        return ExitCode::from(28);
+    }
+
+    let code = listen_to_msgs(settings, sockets, Some(ours), table, services, nonce_cache);
+    match helper_status() {
+        c if c == ExitCode::from(0) => code,
+        c => c,
+    }
 }
 
-fn get_args() -> Result<(bool, bool, String, String, String), Box<dyn Error>> {
-    let matches = App::new("door") .version(crate_version!()) .author(crate_authors!(", "))
+/// door's command line; `configs` are the default config files
+fn app<'a>(configs: &'a [&'a str]) -> App<'a> {
+    App::new("door") .version(crate_version!()) .author(crate_authors!(", "))
         .about("Watches the doors and listens for the secret codes")
         .arg(arg!(syslog: -S --syslog "log events and info to syslog instead of stdout").action(ArgAction::SetTrue))
         .arg(arg!(verbose: -v --verbose "print DEBUG level events instead of INFO").action(ArgAction::SetTrue))
+        .arg(
+            arg!(compact: --compact "print shorter log lines, with the fields of the spans they happen in at the end")
+                .action(ArgAction::SetTrue)
+        )
         .arg(
             arg!(config: -C --config <CONFIG> "read this config file for settings")
             .value_parser(value_parser!(String))
             .multiple(true)
             .required(false) // I hate this:
-            .default_values(&config_filez("KNOCK_DOOR").iter().map(|a| a.as_str()).collect::<Vec<&str>>())
+            .default_values(configs)
         )
         .arg(
-            arg!(listen: -l --listen <ADDRINFO> "the IP and port on which to listen")
+            arg!(listen: -l --listen <ADDRINFO> "the IP and port on which to listen, optionally followed by \
+            @interface to only hear knocks that arrive on that interface; can be given more than once. In the \
+            config file, listen may be a list, and each listener may be a table with a tag (passed to the command \
+            as {listener}), a timestamp window (seconds) and a list of the doors it serves.")
                 .value_parser(value_parser!(String))
+                .multiple(true)
                 .required(false)
                 .default_value("0.0.0.0:20022")
         )
@@ -173,26 +1759,178 @@ fn get_args() -> Result<(bool, bool, String, String, String), Box<dyn Error>> {
                  can also be set in the environment variable KNOCK_DOOR_SECRET.")
             .value_parser(value_parser!(String))
             .required(false)
-            .default_value("secret")
+            .default_value(DEFAULT_SECRET)
+        )
+        .arg(
+            arg!(keyring: -K --keyring <FILE> "a keyring file to use instead of the secret: a TOML file of [[key]] \
+            tables, each with an id, a secret and optionally not_before and not_after (unix times). Knocks signed \
+            with any key that's valid at the time are accepted. The file is watched, so rotating keys is a matter \
+            of editing it.")
+            .value_parser(value_parser!(String))
+            .required(false)
+            .default_value("")
         )
         .arg(
             arg!(command: -c --command <SHELL_COMMAND> "The command to execute after a verified message is received. \
             Can also be set via KNOCK_DOOR_COMMAND. Note that the source IP will be passed via format!() \
             to this command string, so brace characters must be escaped (doubled) and the command should contain \
-            {ip} if applicable to the command. A leading '@' character
-            indicates the this value is a file from which to read the
-            command.")
+            {ip} if applicable to the command. The other variables are {port}, {family}, {identity}, {door}, \
+            {listener}, {duration}, {timestamp} and {src_port}; they're also exported to the command as KNOCK_IP, KNOCK_PORT, \
+            etc. A leading '@' character indicates the this value is a file from which to read the command. \
+            In a config file, the command may instead be an array, which is executed directly (no shell) with \
+            each argument formatted separately.")
             .value_parser(value_parser!(String))
             .required(false)
-            .default_value("sudo nft add element inet firewall knock {{ {ip} timeout 5s }}")
+            .default_value("sudo nft add element inet firewall knock {{ {ip} timeout {duration}s }}")
+        )
+        .arg(
+            arg!(duration: -d --duration <SECONDS> "how long a verified knock should keep the door open; \
+            passed to the command as {duration}")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .default_value("5")
+        )
+        .arg(
+            arg!(max_duration: --"max-duration" <SECONDS> "knocks can ask for a longer (or shorter) grant than \
+            --duration, but no longer than this; 0 means no longer than --duration. This can be set per identity \
+            in the config file's identity_max_duration table.")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .default_value("0")
+        )
+        .arg(
+            arg!(print_ruleset: --"print-ruleset" "print the nftables ruleset door would set up with nft.bootstrap \
+            (see the [nft] section of the config) and exit")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(
+            arg!(preflight: --preflight "check the configured commands (their templates, that their programs are \
+            installed, and any check-mode commands, e.g. with nft -c) and exit; door also does this at startup, \
+            and won't start if anything's wrong")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(
+            arg!(no_preflight: --"no-preflight" "don't check the configured commands at startup")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(
+            arg!(ack: --ack "when a knock asks, tell it (in a signed reply) whether and for how long it was \
+            granted")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(
+            arg!(grace: --grace <SECONDS> "knocks from an IP that already has a grant are ignored if the grant \
+            was made (or extended) less than this long ago; after that, they extend it")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .default_value("2")
+        )
+        .arg(
+            arg!(command_timeout: --"command-timeout" <SECONDS> "kill the command (and anything it started) if \
+            it's still running after this long")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .default_value("10")
+        )
+        .arg(
+            arg!(shutdown_timeout: --"shutdown-timeout" <SECONDS> "on SIGTERM or SIGINT, stop listening and wait \
+            this long for the commands already running to finish; a second signal means don't wait")
+            .value_parser(value_parser!(u64))
+            .required(false)
+            .default_value("10")
+        )
+        .arg(
+            arg!(revoke_on_shutdown: --"revoke-on-shutdown" "on the way out, run the undo commands (see \
+            [[actions]]) for every active grant")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(
+            arg!(max_commands: --"max-commands" <COUNT> "run at most this many commands at once; the rest \
+            wait in a queue")
+            .value_parser(value_parser!(usize))
+            .required(false)
+            .default_value("4")
+        )
+        .arg(
+            arg!(max_queued: --"max-queued" <COUNT> "let at most this many commands wait for one of the \
+            max-commands slots; any more are refused (and counted) rather than queued")
+            .value_parser(value_parser!(usize))
+            .required(false)
+            .default_value("64")
+        )
+        .subcommand(
+            App::new("audit")
+                .about("read the audit log (see [audit] in the config), print the records that match as JSON \
+                lines, or sum them up; the rotated files are read too, oldest first")
+                .arg(arg!(file: -f --file <FILE> "read this audit log instead of the configured one").required(false))
+                .arg(arg!(since: --since <WHEN> "only records from this unix time on, or from this long ago \
+                (e.g., 2h or 7d)").required(false))
+                .arg(arg!(until: --until <WHEN> "only records from before this unix time, or before this long \
+                ago").required(false))
+                .arg(arg!(ip: --ip <IP> "only knocks from this IP").required(false))
+                .arg(arg!(identity: --identity <IDENTITY> "only knocks from this identity").required(false))
+                .arg(arg!(door: --door <DOOR> "only knocks on this door").required(false))
+                .arg(arg!(decision: --decision <DECISION> "only granted, extended, duplicate, failed, rejected \
+                or dropped knocks").required(false))
+                .arg(arg!(reason: --reason <REASON> "only knocks rejected (or dropped) for this reason, e.g. \
+                bad_signature or rate_limited").required(false))
+                .arg(arg!(summary: --summary "count the matching records by decision, reason, door, identity \
+                and IP instead of printing them").action(ArgAction::SetTrue))
+        )
+        .subcommand(
+            App::new("ctl")
+                .about("ask a running door (see [control] in the config) about its grants and such; the replies \
+                are JSON")
+                .arg(
+                    arg!(socket: --socket <PATH> "door's control socket")
+                    .value_parser(value_parser!(String))
+                    .required(false)
+                    .default_value(control::DEFAULT_SOCKET)
+                )
+                .subcommand_required(true)
+                .subcommand(App::new("grants").about("list the active grants"))
+                .subcommand(
+                    App::new("revoke")
+                        .about("take away an IP's grants, running the undo commands")
+                        .arg(arg!(ip: <IP> "the IP"))
+                        .arg(arg!(door: --door <DOOR> "just this door's grant").required(false))
+                )
+                .subcommand(
+                    App::new("grant")
+                        .about("let an IP in without a knock (the door's identities and durations still apply)")
+                        .arg(arg!(ip: <IP> "the IP"))
+                        .arg(arg!(door: --door <DOOR> "the door (default: the default door)").required(false))
+                        .arg(arg!(identity: --identity <IDENTITY> "who it's for").required(false))
+                        .arg(arg!(duration: --for <DURATION> "how long for, e.g. 90, 90s, 5m or 1h").required(false))
+                )
+                .subcommand(App::new("stats").about("show counters and gauges"))
+                .subcommand(App::new("reload").about("reload the config, as SIGHUP does"))
+                .subcommand(App::new("replay-cache").about("show how many nonces door remembers"))
         )
-        .get_matches();
+}
+
+/// anyone can knock on a door that still has the built-in secret
+fn warn_about_default_secret(doors: &Doors) {
+    for d in doors.values().filter(|d| d.keys.has_secret(DEFAULT_SECRET)) {
+        warn!(
+            "door {} uses the default secret {DEFAULT_SECRET:?}, set a secret (or a keyring) of its own",
+            d.name
+        );
+    }
+}
+
+fn get_args() -> Result<Settings, Box<dyn Error>> {
+    let configs = config_filez("KNOCK_DOOR");
+    let configs = configs.iter().map(|a| a.as_str()).collect::<Vec<&str>>();
+    let matches = app(&configs).get_matches();
 
     let filez = matches.get_many::<String>("config").expect("defaulted by clap");
     let def = is_default!(matches, "config");
     let mut config = Config::builder();
+    let mut config_files = vec![];
     for item in filez {
         config = config.add_source(config::File::with_name(item).required(!def));
+        config_files.push(item.to_owned());
     }
     config = config.add_source(config::Environment::with_prefix("KNOCK_DOOR"));
 
@@ -200,22 +1938,397 @@ fn get_args() -> Result<(bool, bool, String, String, String), Box<dyn Error>> {
 
     let verbose: bool = grok_setting!(matches, settings, "verbose", bool);
     let syslog: bool = grok_setting!(matches, settings, "syslog", bool);
+    let compact: bool = grok_setting!(matches, settings, "compact", bool);
     let key: String = grok_setting!(matches, settings, "secret", String);
-    let listen: String = grok_setting!(matches, settings, "listen", String);
-    let command: String = read_from_file_sometimes(&grok_setting!(matches, settings, "command", String));
+    let keyring: String = grok_setting!(matches, settings, "keyring", String);
+    let duration: u64 = grok_setting!(matches, settings, "duration", u64);
+    let max_duration: u64 = grok_setting!(matches, settings, "max_duration", u64);
+    let ack: bool = grok_setting!(matches, settings, "ack", bool);
+    let grace: u64 = grok_setting!(matches, settings, "grace", u64);
+    let command_timeout: u64 = grok_setting!(matches, settings, "command_timeout", u64);
+    let max_commands: usize = grok_setting!(matches, settings, "max_commands", usize);
+    let max_queued: usize = grok_setting!(matches, settings, "max_queued", usize);
+    let shutdown_timeout: u64 = grok_setting!(matches, settings, "shutdown_timeout", u64);
+    let revoke_on_shutdown: bool = grok_setting!(matches, settings, "revoke_on_shutdown", bool);
+
+    // the command is either a string (for sh -c) or an array (argv, no shell);
+    // grok_setting!() can only deal with whatever type clap has, so do it by hand
+    let nft = match settings.get::<NftSettings>("nft") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => NftSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    if nft.bootstrap && nft.ports.is_empty() {
+        return Err("nft.bootstrap needs some ports to protect".into());
+    }
+    let privsep = match settings.get::<PrivsepSettings>("privsep") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => PrivsepSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let print_ruleset: bool = grok_setting!(matches, settings, "print_ruleset", bool);
+    let preflight: bool = grok_setting!(matches, settings, "preflight", bool);
+    let no_preflight: bool = grok_setting!(matches, settings, "no_preflight", bool);
+
+    // the command is either a string (for sh -c) or an array (argv, no shell);
+    // grok_setting!() can only deal with whatever type clap has, so do it by hand.
+    // When door sets up its own nft table, the default command uses that.
+    let command = match matches.value_source("command") {
+        Some(ValueSource::DefaultValue) => match settings.get::<CommandSpec>("command") {
+            Ok(v) => v,
+            Err(_) if nft.bootstrap => nft.command(),
+            Err(_) => CommandSpec::Shell(matches.get_one::<String>("command").expect("works").to_owned()),
+        },
+        _ => CommandSpec::Shell(matches.get_one::<String>("command").expect("works").to_owned()),
+    };
+    // anything that came from an @file
+    let mut at_files = vec![];
+    if let CommandSpec::Shell(v) = &command {
+        at_files.push(v.to_owned());
+    }
+    let command = match command {
+        CommandSpec::Shell(v) => CommandSpec::Shell(try_read_from_file_sometimes(&v)?),
+        v => v,
+    };
+
+    let mut action = Action::new(command);
+    action.check = match settings.get::<CommandSpec>("check_command") {
+        Ok(v) => Some(v),
+        Err(config::ConfigError::NotFound(_)) => None,
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    // [[actions]] in the config replace the single command with a pipeline,
+    // and a door that only keeps allowlist files doesn't have one at all
+    let backend = match settings.get::<Backend>("backend") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => Backend::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let pipeline = match settings.get::<Vec<Action>>("actions") {
+        _ if backend == Backend::Allowlist => Pipeline::new(vec![]),
+        Ok(v) if !v.is_empty() => Pipeline::new(v),
+        Ok(_) | Err(config::ConfigError::NotFound(_)) => Pipeline::new(vec![action]),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    // listen is a string or a table, or a list of them, in the config; the
+    // command line only does strings
+    let listen = match matches.value_source("listen") {
+        Some(ValueSource::DefaultValue) => match settings.get::<Vec<Listen>>("listen") {
+            Ok(v) => v,
+            Err(_) => match settings.get::<Listen>("listen") {
+                Ok(v) => vec![v],
+                Err(config::ConfigError::NotFound(_)) => vec![Listen::parse("0.0.0.0:20022")?],
+                Err(e) => return Err(Box::new(e)),
+            },
+        },
+        _ => matches
+            .get_many::<String>("listen")
+            .expect("works")
+            .map(|v| Listen::parse(v))
+            .collect::<Result<Vec<Listen>, String>>()?,
+    };
+    if listen.is_empty() {
+        return Err("door needs somewhere to listen".into());
+    }
+
+    let hooks = match settings.get::<Hooks>("hooks") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => Hooks::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    let rate_limit = match settings.get::<RateLimitSettings>("rate_limit") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => RateLimitSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    let revocations = match settings.get::<String>("revocations") {
+        Ok(v) => Revocations::load(&v)?,
+        Err(config::ConfigError::NotFound(_)) => Revocations::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    // it's a plain path, like the config files
+    if let Some(f) = revocations.file() {
+        config_files.push(f.to_string());
+    }
+
+    let mut audit = match settings.get::<AuditSettings>("audit") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => AuditSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let audit_query = match matches.subcommand() {
+        Some(("audit", m)) => {
+            let now = unix_now();
+            let arg = |name: &str| m.get_one::<String>(name).cloned();
+            if let Some(f) = arg("file") {
+                audit.path = f;
+            }
+            let query = Query {
+                since: arg("since").map(|v| audit::parse_when(&v, now)).transpose()?,
+                until: arg("until").map(|v| audit::parse_when(&v, now)).transpose()?,
+                ip: arg("ip"),
+                identity: arg("identity"),
+                door: arg("door"),
+                decision: arg("decision").map(|v| audit::Decision::parse(&v)).transpose()?,
+                reason: arg("reason"),
+            };
+            Some((query, m.get_flag("summary")))
+        }
+        _ => None,
+    };
+
+    let metrics = match settings.get::<MetricsSettings>("metrics") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => MetricsSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    let control = match settings.get::<ControlSettings>("control") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => ControlSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    let events = match settings.get::<StreamSettings>("events") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => StreamSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    let tracing = match settings.get::<TracingSettings>("tracing") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => TracingSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    if !tracing.otlp.is_empty() {
+        tracing.endpoint().map_err(|e| format!("tracing.otlp: {e}"))?;
+    }
+
+    let keepalive = match settings.get::<KeepaliveSettings>("keepalive") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => KeepaliveSettings::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    if keepalive.interval == 0 {
+        return Err("keepalive.interval has to be at least a second".into());
+    }
+
+    let allowlists = match settings.get::<Vec<AllowlistSettings>>("allowlist") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => vec![],
+        Err(e) => return Err(Box::new(e)),
+    };
+    if allowlists.iter().any(|a| a.path.is_empty()) {
+        return Err("every [[allowlist]] needs a path".into());
+    }
+    let allowlist_debounce = match settings.get::<u64>("allowlist_debounce_ms") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => 500,
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    let policy = DoorPolicy {
+        duration,
+        max_duration,
+        identity_max_duration: match settings.get("identity_max_duration") {
+            Ok(v) => v,
+            Err(config::ConfigError::NotFound(_)) => Default::default(),
+            Err(e) => return Err(Box::new(e)),
+        },
+        identities: match settings.get("identities") {
+            Ok(v) => v,
+            Err(config::ConfigError::NotFound(_)) => Default::default(),
+            Err(e) => return Err(Box::new(e)),
+        },
+    };
 
-    Ok((verbose, syslog, key, listen, command))
+    // the top level settings are the default door, and [doors.<name>]
+    // sections are the named ones, which inherit whatever they don't set
+    // run_as_user and friends are top level settings too
+    let run_as = settings.clone().try_deserialize::<RunAs>()?;
+    let default_door = Door {
+        name: DEFAULT_DOOR.to_string(),
+        keys: match keyring.as_str() {
+            "" => Keyring::secret(&key)?,
+            k => Keyring::load(k)?,
+        },
+        pipeline,
+        policy,
+        exec: Exec::new(&run_as)?,
+        run_as,
+    };
+    let sections = match settings.get::<HashMap<String, DoorSection>>("doors") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => Default::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let mut doors: Doors = HashMap::new();
+    at_files.push(key);
+    // keyrings are plain paths, like the config files
+    if !keyring.is_empty() {
+        config_files.push(keyring);
+    }
+    for (name, section) in sections.iter() {
+        if !valid_token(name) {
+            return Err(format!("{name:?} isn't a valid door name").into());
+        }
+        at_files.extend(section.secret.iter().cloned());
+        config_files.extend(section.keyring.iter().cloned());
+        if let Some(CommandSpec::Shell(v)) = &section.command {
+            at_files.push(v.to_owned());
+        }
+        doors.insert(name.to_owned(), Arc::new(section.resolve(name, &default_door)?));
+    }
+    // with named doors, the top level settings are only a door of their own
+    // if they're asked to be
+    let default_enabled = match settings.get::<bool>("default_door") {
+        Ok(v) => v,
+        Err(config::ConfigError::NotFound(_)) => sections.is_empty(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    if default_enabled {
+        doors
+            .entry(DEFAULT_DOOR.to_string())
+            .or_insert_with(|| Arc::new(default_door));
+    }
+    for d in doors.values().filter(|d| d.pipeline.actions.is_empty()) {
+        if !allowlists
+            .iter()
+            .any(|a| a.doors.is_empty() || a.doors.contains(&d.name))
+        {
+            return Err(format!(
+                "door {} has backend = \"allowlist\", but no [[allowlist]] takes its grants",
+                d.name
+            )
+            .into());
+        }
+    }
+    revocations.enforceable(doors.values().map(|d| (d.name.as_str(), &d.keys)))?;
+    if doors.is_empty() {
+        return Err("default_door is off and there are no [doors.<name>], so there's nothing to knock on".into());
+    }
+    for l in listen.iter() {
+        if let Some(d) = l.doors.iter().find(|d| !doors.contains_key(d.as_str())) {
+            return Err(format!("listener {} serves {:?}, which isn't a door", l.describe(), d).into());
+        }
+    }
+
+    // the directories, rather than the files, since editors tend to replace
+    // a file rather than write to it
+    let mut readable = config_files
+        .iter()
+        .map(|v| v.as_str())
+        .chain(at_files.iter().filter_map(|v| v.strip_prefix('@')))
+        .map(|v| match std::path::Path::new(v).parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_string_lossy().to_string(),
+            _ => ".".to_string(),
+        })
+        .collect::<Vec<String>>();
+    readable.sort();
+    readable.dedup();
+
+    Ok(Settings {
+        verbose,
+        syslog,
+        compact,
+        listen,
+        doors,
+        hooks,
+        rate_limit,
+        keepalive,
+        allowlists,
+        allowlist_debounce,
+        nft,
+        privsep,
+        print_ruleset,
+        preflight,
+        no_preflight,
+        ack,
+        grace,
+        command_timeout,
+        max_commands,
+        max_queued,
+        shutdown_timeout,
+        revoke_on_shutdown,
+        revocations,
+        audit,
+        metrics,
+        control,
+        events,
+        tracing,
+        audit_query,
+        readable,
+    })
+}
+
+/// door ctl: send the request, print the reply
+fn ctl_main(m: &clap::ArgMatches) -> ExitCode {
+    let socket = m.get_one::<String>("socket").expect("defaulted by clap");
+    let arg = |m: &clap::ArgMatches, name: &str| m.get_one::<String>(name).cloned();
+    let request = match m.subcommand() {
+        Some(("grants", _)) => CtlRequest::Grants,
+        Some(("revoke", m)) => CtlRequest::Revoke {
+            ip: arg(m, "ip").expect("required by clap"),
+            door: arg(m, "door"),
+        },
+        Some(("grant", m)) => CtlRequest::Grant {
+            ip: arg(m, "ip").expect("required by clap"),
+            door: arg(m, "door"),
+            identity: arg(m, "identity"),
+            duration: match arg(m, "duration").map(|v| parse_duration(&v)).transpose() {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::from(27);
+                }
+            },
+        },
+        Some(("stats", _)) => CtlRequest::Stats,
+        Some(("reload", _)) => CtlRequest::Reload,
+        Some(("replay-cache", _)) => CtlRequest::ReplayCache,
+        _ => unreachable!("clap wants a subcommand"),
+    };
+
+    // long enough for a reload's preflight, or a pipeline or two
+    match control::call(socket, &request, Duration::from_secs(60)) {
+        Ok(reply) => {
+            if let Some(data) = &reply.data {
+                println!("{}", serde_json::to_string_pretty(data).expect("replies serialize"));
+            }
+            match reply.error {
+                None => ExitCode::from(0),
+                Some(e) => {
+                    eprintln!("door says: {e}");
+                    ExitCode::from(1)
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(27)
+        }
+    }
 }
 
 fn main() -> ExitCode {
-    let (verbose, syslog, key_str, listen, command) = match get_args() {
+    // door ctl doesn't need (or, as some other user, get to read) the config
+    let configs = config_filez("KNOCK_DOOR");
+    let configs = configs.iter().map(|a| a.as_str()).collect::<Vec<&str>>();
+    if let Some(("ctl", m)) = app(&configs).get_matches().subcommand() {
+        return ctl_main(m);
+    }
+
+    let settings = match get_args() {
         Ok(v) => v,
         Err(error) => {
             eprintln!("error building config: {error:?}");
             return ExitCode::from(27);
         }
     };
-    let mut hf = HMACFrobnicator::new(&key_str);
     let mut nonce_cache: LruCache<String, bool> = LruCache::new(100);
 
     /*
@@ -235,35 +2348,114 @@ fn main() -> ExitCode {
      *
      */
 
-    if syslog {
-        let formatter = Formatter3164 {
-            facility: Facility::LOG_DAEMON,
-            process: "knock-door".into(),
-            hostname: None,
-            pid: 0,
-        };
-
-        let logger = syslog::unix(formatter).unwrap();
+    if settings.print_ruleset {
+        print!("{}", settings.nft.ruleset());
+        return ExitCode::from(0);
+    }
 
-        log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-            .map(|()| {
-                log::set_max_level(match verbose {
-                    true => LevelFilter::Debug,
-                    false => LevelFilter::Info,
-                })
-            })
-            .expect("logging setup failure");
-    } else {
-        let env = Env::default()
-            // TODO: KNOCK_DOOR_LOG_LEVEL and LOG_STYLE should probably be available via configs...
-            // do we even need Env::default? what about env_logger itself?
-            .filter_or("KNOCK_DOOR_LOG_LEVEL", if verbose { "debug" } else { "info" })
-            .write_style_or("KNOCK_DOOR_LOG_STYLE", "always");
+    if settings.preflight {
+        return preflight_report(&settings);
+    }
 
-        env_logger::init_from_env(env);
+    if let Some((query, summary)) = &settings.audit_query {
+        return audit_report(&settings.audit, query, *summary);
     }
 
-    listen_to_msgs(listen, &mut hf, &command, &mut nonce_cache);
+    // spans go to the registry, where the OTLP exporter can be plugged in
+    // later, and so do the log lines, on their way to stderr or syslog
+    let (otlp_layer, otlp) = trace::slot();
+    let writer = match settings.syslog {
+        true => match trace::Syslog::new("knock-door") {
+            Ok(v) => BoxMakeWriter::new(v),
+            Err(e) => {
+                eprintln!("couldn't connect to syslog: {e}");
+                return ExitCode::from(27);
+            }
+        },
+        false => BoxMakeWriter::new(std::io::stderr),
+    };
+    // TODO: KNOCK_DOOR_LOG_LEVEL and LOG_STYLE should probably be available via configs...
+    let filter = EnvFilter::try_from_env("KNOCK_DOOR_LOG_LEVEL")
+        .unwrap_or_else(|_| EnvFilter::new(if settings.verbose { "debug" } else { "info" }));
+    let ansi = std::env::var("KNOCK_DOOR_LOG_STYLE").map_or(true, |v| v != "never");
+    // syslog has the time and level of its own
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi && !settings.syslog)
+        .with_level(!settings.syslog);
+    let fmt = match (settings.syslog, settings.compact) {
+        (true, true) => fmt.without_time().compact().boxed(),
+        (true, false) => fmt.without_time().boxed(),
+        (false, true) => fmt.compact().boxed(),
+        (false, false) => fmt.boxed(),
+    };
+    // the filter's only for the log lines; the collector gets every span
+    Registry::default()
+        .with(otlp_layer)
+        .with(fmt.with_filter(filter))
+        .init();
+    warn_about_default_secret(&settings.doors);
 
-    ExitCode::from(0)
+    let sockets = bind_all(&settings.listen);
+    // the conntrack table's usually only readable by root, so open it while
+    // we still are (which matters with privsep)
+    let table = match settings.keepalive.enabled {
+        true => File::open(&settings.keepalive.path)
+            .map_err(|e| warn!("keepalive: couldn't open {}: {}", settings.keepalive.path, e))
+            .ok(),
+        false => None,
+    };
+
+    let scrapes = match settings.metrics.listen.as_str() {
+        "" => None,
+        addr => match std::net::TcpListener::bind(addr) {
+            Ok(v) => {
+                info!("serving metrics on {}", addr);
+                Some(v)
+            }
+            Err(e) => {
+                error!("couldn't listen for metrics scrapes on {}: {}", addr, e);
+                return ExitCode::from(27);
+            }
+        },
+    };
+
+    let ctl = match settings.control.enabled {
+        true => match control::bind(&settings.control) {
+            Ok(v) => {
+                info!("control socket at {}", settings.control.socket);
+                Some(v)
+            }
+            Err(e) => {
+                error!("couldn't set up the control socket: {}", e);
+                return ExitCode::from(27);
+            }
+        },
+        false => None,
+    };
+
+    let events = match settings.events.enabled {
+        true => match stream::bind(&settings.events) {
+            Ok(v) => {
+                info!("event stream at {}", settings.events.socket);
+                Some(v)
+            }
+            Err(e) => {
+                error!("couldn't set up the event socket: {}", e);
+                return ExitCode::from(27);
+            }
+        },
+        false => None,
+    };
+
+    let services = Services {
+        scrapes,
+        ctl,
+        events,
+        otlp,
+    };
+    match settings.privsep.enabled {
+        true => privsep_main(&settings, sockets, table, services, &mut nonce_cache),
+        false => listen_to_msgs(&settings, sockets, None, table, services, &mut nonce_cache),
+    }
 }
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::command::{CommandSpec, Runner};
+use crate::exec::{Exec, RunAs};
+use crate::keyring::Keyring;
+use crate::payload::Payload;
+use crate::pipeline::{Action, Pipeline};
+use crate::policy::DoorPolicy;
+use crate::try_read_from_file_sometimes;
+
+/// knocks that don't name a door get this one, which is configured by the
+/// top level settings
+pub const DEFAULT_DOOR: &str = "default";
+
+/// How a door lets people in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// run its command (or actions)
+    #[default]
+    Command,
+    /// nothing to run, its grants only go in the `[[allowlist]]` files
+    Allowlist,
+}
+
+/// One service a door process guards, with its own secret, actions and policy.
+#[derive(Clone)]
+pub struct Door {
+    pub name: String,
+    pub keys: Keyring,
+    pub pipeline: Pipeline,
+    pub policy: DoorPolicy,
+    /// who the pipeline runs as, as configured and as looked up
+    pub run_as: RunAs,
+    pub exec: Exec,
+}
+
+impl Door {
+    /// `runner`, but running things as this door's commands should be
+    pub fn runner(&self, runner: &Runner) -> Runner {
+        runner.to_owned().with_exec(self.exec.to_owned())
+    }
+}
+
+pub type Doors = HashMap<String, Arc<Door>>;
+
+/// A `[doors.<name>]` section of door's config. Anything left out is
+/// inherited from the top level settings (i.e., the default door).
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct DoorSection {
+    pub backend: Option<Backend>,
+    pub secret: Option<String>,
+    /// a keyring file, which takes the place of secret
+    pub keyring: Option<String>,
+    pub command: Option<CommandSpec>,
+    /// a check-mode version of command, for preflight
+    pub check_command: Option<CommandSpec>,
+    pub actions: Option<Vec<Action>>,
+    pub duration: Option<u64>,
+    pub max_duration: Option<u64>,
+    pub identity_max_duration: Option<HashMap<String, u64>>,
+    /// only these identities may use this door; empty means anyone with the secret
+    pub identities: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub run_as: RunAs,
+}
+
+impl DoorSection {
+    pub fn resolve(&self, name: &str, defaults: &Door) -> Result<Door, String> {
+        let pipeline = match (&self.actions, &self.command) {
+            _ if self.backend == Some(Backend::Allowlist) => Pipeline::new(vec![]),
+            (Some(a), _) if !a.is_empty() => Pipeline::new(a.to_owned()),
+            (_, Some(c)) => {
+                let mut action = Action::new(match c {
+                    CommandSpec::Shell(s) => CommandSpec::Shell(
+                        try_read_from_file_sometimes(s).map_err(|e| format!("door {name} command: {e}"))?,
+                    ),
+                    v => v.to_owned(),
+                });
+                action.check = self.check_command.to_owned();
+                Pipeline::new(vec![action])
+            }
+            _ if self.backend == Some(Backend::Command) && defaults.pipeline.actions.is_empty() => {
+                return Err(format!("door {name} has backend = \"command\", but no command"));
+            }
+            _ => defaults.pipeline.to_owned(),
+        };
+        let mut policy = defaults.policy.to_owned();
+        if let Some(v) = self.duration {
+            policy.duration = v;
+        }
+        if let Some(v) = self.max_duration {
+            policy.max_duration = v;
+        }
+        if let Some(v) = &self.identity_max_duration {
+            policy.identity_max_duration = v.to_owned();
+        }
+        if let Some(v) = &self.identities {
+            policy.identities = v.to_owned();
+        }
+
+        let run_as = self.run_as.or(&defaults.run_as);
+        let exec = Exec::new(&run_as).map_err(|e| format!("door {name}: {e}"))?;
+
+        Ok(Door {
+            name: name.to_string(),
+            keys: match (&self.keyring, &self.secret) {
+                (Some(k), _) => Keyring::load(k).map_err(|e| format!("door {name} keyring: {e}"))?,
+                (None, Some(s)) => Keyring::secret(s).map_err(|e| format!("door {name} secret: {e}"))?,
+                (None, None) => defaults.keys.to_owned(),
+            },
+            pipeline,
+            policy,
+            run_as,
+            exec,
+        })
+    }
+}
+
+/// Which door a (not yet verified!) message says it's for. We need this to
+/// know which secret to check it with; a liar just fails verification.
+pub fn door_hint(msg: &str) -> String {
+    msg.split_once(':')
+        .and_then(|(m, _)| Payload::parse(m).ok())
+        .and_then(|p| p.door)
+        .unwrap_or_else(|| DEFAULT_DOOR.to_string())
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HMACFrobnicator;
+
+    fn default_door() -> Door {
+        Door {
+            name: DEFAULT_DOOR.to_string(),
+            keys: Keyring::secret("secret").unwrap(),
+            pipeline: Pipeline::new(vec![Action::new(CommandSpec::Shell("true".to_string()))]),
+            policy: DoorPolicy {
+                duration: 5,
+                max_duration: 60,
+                ..Default::default()
+            },
+            run_as: RunAs {
+                clear_env: Some(true),
+                ..Default::default()
+            },
+            exec: Exec::default(),
+        }
+    }
+
+    #[test]
+    fn sections_inherit() {
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                [doors.git]
+                secret = "other secret"
+                max_duration = 600
+                identities = [ "alice", "bob" ]
+
+                [doors.web]
+                command = [ "web-allow", "{ip}" ]
+                run_as_user = "root"
+
+                [doors.bad]
+                run_as_user = "definitely-not-a-user"
+
+                [doors.nokey]
+                secret = "@/nonexistent/secret"
+
+                [doors.noring]
+                keyring = "/nonexistent/keyring.toml"
+
+                [doors.files]
+                backend = "allowlist"
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let sections = settings.get::<HashMap<String, DoorSection>>("doors").unwrap();
+        let d = default_door();
+
+        let git = sections["git"].resolve("git", &d).unwrap();
+        assert_eq!(git.name, "git");
+        assert_eq!(git.pipeline, d.pipeline);
+        assert_eq!(git.policy.duration, 5);
+        assert_eq!(git.policy.max_duration, 600);
+        assert_eq!(git.policy.identities, vec!["alice", "bob"]);
+        assert!(git
+            .keys
+            .verify(&HMACFrobnicator::new("other secret").sign("7"), 0)
+            .is_ok());
+
+        let web = sections["web"].resolve("web", &d).unwrap();
+        assert_eq!(web.policy, d.policy);
+        assert_eq!(
+            web.pipeline.actions[0].command,
+            CommandSpec::Argv(vec!["web-allow".to_string(), "{ip}".to_string()])
+        );
+        assert!(web.keys == d.keys);
+
+        assert_eq!(git.run_as, d.run_as);
+        assert_eq!(web.run_as.run_as_user.as_deref(), Some("root"));
+        assert_eq!(web.run_as.clear_env, Some(true));
+        assert!(web.exec.env.is_some());
+        assert!(sections["bad"].resolve("bad", &d).is_err());
+        assert!(sections["nokey"].resolve("nokey", &d).is_err());
+        assert!(sections["noring"].resolve("noring", &d).is_err());
+
+        let files = sections["files"].resolve("files", &d).unwrap();
+        assert!(files.pipeline.actions.is_empty());
+        let cmd = DoorSection {
+            backend: Some(Backend::Command),
+            ..Default::default()
+        };
+        assert_eq!(cmd.resolve("cmd", &d).unwrap().pipeline, d.pipeline);
+        assert!(cmd.resolve("cmd", &files).is_err());
+    }
+
+    #[test]
+    fn hints() {
+        assert_eq!(door_hint("7$abc$door=git:sig"), "git");
+        assert_eq!(door_hint("7$abc:sig"), DEFAULT_DOOR);
+        assert_eq!(door_hint("nonsense"), DEFAULT_DOOR);
+    }
+}
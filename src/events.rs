@@ -0,0 +1,240 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::command::{CommandSpec, KnockVars, Runner};
+use crate::pipeline::StepResult;
+use crate::unix_now;
+
+/// Why door didn't like a datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    BadFormat,
+    BadSignature,
+    ReusedNonce,
+    BadTimestamp,
+    StaleTimestamp,
+    RateLimited,
+    Banned,
+    UnknownDoor,
+    IdentityNotAllowed,
+    /// signed with a key that isn't valid (yet, or any more)
+    ExpiredKey,
+    /// the key or identity is on the revocation list
+    Revoked,
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::BadFormat => "bad_format",
+            RejectReason::BadSignature => "bad_signature",
+            RejectReason::ReusedNonce => "reused_nonce",
+            RejectReason::BadTimestamp => "bad_timestamp",
+            RejectReason::StaleTimestamp => "stale_timestamp",
+            RejectReason::RateLimited => "rate_limited",
+            RejectReason::Banned => "banned",
+            RejectReason::UnknownDoor => "unknown_door",
+            RejectReason::IdentityNotAllowed => "identity_not_allowed",
+            RejectReason::ExpiredKey => "expired_key",
+            RejectReason::Revoked => "revoked",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Reject,
+    Verify,
+    Grant,
+    Expire,
+    CommandFailure,
+}
+
+/// The document a hook gets on stdin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    pub event: EventKind,
+    pub timestamp: u64,
+    pub source: String,
+    pub ip: String,
+    pub src_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<RejectReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub door: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepResult>,
+}
+
+impl Event {
+    pub fn new(event: EventKind, src: &SocketAddr) -> Self {
+        Event {
+            event,
+            timestamp: unix_now(),
+            source: src.to_string(),
+            ip: src.ip().to_string(),
+            src_port: src.port(),
+            reason: None,
+            identity: None,
+            door: None,
+            duration: None,
+            steps: vec![],
+        }
+    }
+
+    pub fn reject(src: &SocketAddr, reason: RejectReason) -> Self {
+        let mut ret = Event::new(EventKind::Reject, src);
+        ret.reason = Some(reason);
+        ret
+    }
+
+    /// an event about a verified knock
+    pub fn knock(event: EventKind, src: &SocketAddr, vars: &KnockVars) -> Self {
+        let mut ret = Event::new(event, src);
+        ret.identity = Some(vars.identity.to_owned());
+        ret.door = Some(vars.door.to_owned());
+        ret.duration = Some(vars.duration);
+        ret
+    }
+
+    pub fn with_steps(mut self, steps: Vec<StepResult>) -> Self {
+        self.steps = steps;
+        self
+    }
+}
+
+/// Commands to run when things happen; each gets the event as JSON on stdin.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+pub struct Hooks {
+    pub on_reject: Option<CommandSpec>,
+    pub on_verify: Option<CommandSpec>,
+    pub on_grant: Option<CommandSpec>,
+    pub on_expire: Option<CommandSpec>,
+    pub on_command_failure: Option<CommandSpec>,
+}
+
+impl Hooks {
+    pub fn get(&self, kind: EventKind) -> Option<&CommandSpec> {
+        match kind {
+            EventKind::Reject => self.on_reject.as_ref(),
+            EventKind::Verify => self.on_verify.as_ref(),
+            EventKind::Grant => self.on_grant.as_ref(),
+            EventKind::Expire => self.on_expire.as_ref(),
+            EventKind::CommandFailure => self.on_command_failure.as_ref(),
+        }
+    }
+
+    /// Run the hook for this event (if there is one) in the background. Its
+    /// place in the runner's queue is taken first, so a flood of events (say,
+    /// rejects from all over) can't pile up tasks: once the queue's full,
+    /// hooks are dropped, and counted as refused, until it drains.
+    pub fn fire(&self, event: Event, vars: &KnockVars, runner: &Runner) {
+        let command = match self.get(event.event) {
+            Some(c) => c.to_owned(),
+            None => return,
+        };
+        let place = match runner.place() {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("hook({:?}) ip={} dropped: {}", event.event, vars.ip, e);
+                return;
+            }
+        };
+        let vars = vars.to_owned();
+        let runner = runner.clone();
+
+        tokio::spawn(async move {
+            let doc = serde_json::to_vec(&event).expect("events always serialize");
+            let name = serde_json::to_string(&event.event).expect("events always serialize");
+            match runner.run_in(place, &command, &vars, Some(&doc)).await {
+                Ok(_) => debug!("hook({}) ip={} ok", name, vars.ip),
+                Err(e) => warn!("hook({}) ip={} fail {}", name, vars.ip, e),
+            }
+        });
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_json() {
+        let src: SocketAddr = "10.1.2.3:5555".parse().unwrap();
+        let mut ev = Event::reject(&src, RejectReason::StaleTimestamp);
+        ev.timestamp = 7;
+
+        assert_eq!(
+            serde_json::to_string(&ev).unwrap(),
+            r#"{"event":"reject","timestamp":7,"source":"10.1.2.3:5555","ip":"10.1.2.3","src_port":5555,"reason":"stale_timestamp"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn hooks_get_json_on_stdin() {
+        let src: SocketAddr = "10.1.2.3:5555".parse().unwrap();
+        let local: SocketAddr = "0.0.0.0:20022".parse().unwrap();
+        let vars = KnockVars::new(&src, &local, 5, 1234);
+        let runner = Runner::new(1, std::time::Duration::from_secs(5));
+        let out = std::env::temp_dir().join(format!("rknock-hook-test-{}", std::process::id()));
+        let hooks = Hooks {
+            on_grant: Some(CommandSpec::Argv(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("cat > {}", out.display()),
+            ])),
+            ..Default::default()
+        };
+
+        hooks.fire(Event::knock(EventKind::Grant, &src, &vars), &vars, &runner);
+        hooks.fire(Event::reject(&src, RejectReason::BadSignature), &vars, &runner);
+
+        let mut doc = serde_json::Value::Null;
+        for _ in 0..100 {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            if let Ok(v) = serde_json::from_str(&std::fs::read_to_string(&out).unwrap_or_default()) {
+                doc = v;
+                break;
+            }
+        }
+        let _ = std::fs::remove_file(&out);
+
+        assert_eq!(doc["event"], "grant");
+        assert_eq!(doc["identity"], "anonymous");
+        assert_eq!(doc["duration"], 5);
+    }
+
+    #[tokio::test]
+    async fn hooks_are_dropped_when_the_queue_is_full() {
+        let src: SocketAddr = "10.1.2.3:5555".parse().unwrap();
+        let local: SocketAddr = "0.0.0.0:20022".parse().unwrap();
+        let vars = KnockVars::new(&src, &local, 0, 1234);
+        let runner = Runner::new(1, std::time::Duration::from_secs(5)).with_max_queued(1);
+        let hooks = Hooks {
+            on_reject: Some(CommandSpec::Shell("sleep 0.2".to_string())),
+            ..Default::default()
+        };
+
+        // one runs, one waits, the rest never get a task
+        for _ in 0..10 {
+            hooks.fire(Event::reject(&src, RejectReason::BadSignature), &vars, &runner);
+        }
+        assert_eq!(runner.refused(), 8);
+    }
+}
@@ -0,0 +1,418 @@
+use std::ffi::CString;
+use std::io;
+
+use nix::libc;
+use nix::unistd::{getgrouplist, Gid, Group, Uid, User};
+use serde::Deserialize;
+use tokio::process::Command;
+
+/// what PATH is when the environment's cleared and PATH isn't in env_allow
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Linux capabilities by name, for ambient_capabilities
+const CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// "CAP_NET_ADMIN", "cap_net_admin" or "net_admin" → 12
+pub fn capability(name: &str) -> Option<u32> {
+    let name = name.to_ascii_uppercase();
+    let name = match name.starts_with("CAP_") {
+        true => name,
+        false => format!("CAP_{name}"),
+    };
+    CAPABILITIES.iter().position(|c| *c == name).map(|v| v as u32)
+}
+
+/// How a door's commands are run: as whom, and with what environment. In the
+/// config these are the run_as_user, run_as_group, run_as_groups, clear_env,
+/// env_allow and ambient_capabilities settings, at the top level or in a
+/// `[doors.<name>]` section (which inherits whichever it leaves out).
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct RunAs {
+    pub run_as_user: Option<String>,
+    /// defaults to run_as_user's primary group
+    pub run_as_group: Option<String>,
+    /// supplementary groups; defaults to run_as_user's (from /etc/group and
+    /// friends), or none
+    pub run_as_groups: Option<Vec<String>>,
+    /// start commands with an empty environment (plus the KNOCK_* vars);
+    /// unset means yes if any of the others are set
+    pub clear_env: Option<bool>,
+    /// with clear_env, the variables to pass along from door's environment
+    pub env_allow: Option<Vec<String>>,
+    /// e.g. CAP_NET_ADMIN, so nft works without sudo after run_as_user
+    pub ambient_capabilities: Option<Vec<String>>,
+}
+
+impl RunAs {
+    /// these settings, with anything unset taken from `defaults`
+    pub fn or(&self, defaults: &RunAs) -> RunAs {
+        RunAs {
+            run_as_user: self.run_as_user.to_owned().or_else(|| defaults.run_as_user.to_owned()),
+            run_as_group: self
+                .run_as_group
+                .to_owned()
+                .or_else(|| defaults.run_as_group.to_owned()),
+            run_as_groups: self
+                .run_as_groups
+                .to_owned()
+                .or_else(|| defaults.run_as_groups.to_owned()),
+            clear_env: self.clear_env.or(defaults.clear_env),
+            env_allow: self.env_allow.to_owned().or_else(|| defaults.env_allow.to_owned()),
+            ambient_capabilities: self
+                .ambient_capabilities
+                .to_owned()
+                .or_else(|| defaults.ambient_capabilities.to_owned()),
+        }
+    }
+}
+
+fn group_id(name: &str) -> Result<Gid, String> {
+    match Group::from_name(name) {
+        Ok(Some(g)) => Ok(g.gid),
+        Ok(None) => Err(format!("there's no group {name}")),
+        Err(e) => Err(format!("couldn't look up group {name}: {e}")),
+    }
+}
+
+/// RunAs with the names looked up, ready to apply to a command.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Exec {
+    pub uid: Option<Uid>,
+    pub gid: Option<Gid>,
+    /// only set along with gid
+    pub groups: Vec<Gid>,
+    /// Some(the whole environment) if it's cleared
+    pub env: Option<Vec<(String, String)>>,
+    pub capabilities: Vec<u32>,
+}
+
+impl Exec {
+    /// look everything up now, so a typo stops door starting rather than
+    /// every knock
+    pub fn new(r: &RunAs) -> Result<Self, String> {
+        let user = match &r.run_as_user {
+            Some(name) => match User::from_name(name) {
+                Ok(Some(u)) => Some(u),
+                Ok(None) => return Err(format!("there's no user {name}")),
+                Err(e) => return Err(format!("couldn't look up user {name}: {e}")),
+            },
+            None => None,
+        };
+        let gid = match (&r.run_as_group, &user) {
+            (Some(g), _) => Some(group_id(g)?),
+            (None, Some(u)) => Some(u.gid),
+            (None, None) => None,
+        };
+        let groups = match (&r.run_as_groups, &user, gid) {
+            (Some(names), _, Some(_)) => names
+                .iter()
+                .map(|g| group_id(g))
+                .collect::<Result<Vec<Gid>, String>>()?,
+            (Some(_), _, None) => return Err("run_as_groups needs run_as_user or run_as_group".to_string()),
+            (None, Some(u), Some(g)) => {
+                let name = CString::new(u.name.as_str()).map_err(|e| e.to_string())?;
+                getgrouplist(&name, g).map_err(|e| format!("couldn't list {}'s groups: {}", u.name, e))?
+            }
+            (None, _, Some(g)) => vec![g],
+            (None, _, None) => vec![],
+        };
+
+        // door's environment (secrets and all) has no business reaching
+        // commands that are meant to run with less than door has, so unless
+        // it's asked to, it's only env_allow
+        let switching = user.is_some()
+            || gid.is_some()
+            || r.env_allow.is_some()
+            || r.ambient_capabilities.as_ref().is_some_and(|c| !c.is_empty());
+        let env = match r.clear_env.unwrap_or(switching) {
+            true => {
+                let allow = r.env_allow.to_owned().unwrap_or_default();
+                let mut env = std::env::vars()
+                    .filter(|(k, _)| allow.contains(k))
+                    .collect::<Vec<(String, String)>>();
+                if !env.iter().any(|(k, _)| k == "PATH") {
+                    env.push(("PATH".to_string(), DEFAULT_PATH.to_string()));
+                }
+                env.sort();
+                Some(env)
+            }
+            false => None,
+        };
+
+        let capabilities = r
+            .ambient_capabilities
+            .to_owned()
+            .unwrap_or_default()
+            .iter()
+            .map(|c| capability(c).ok_or_else(|| format!("{c} isn't a capability")))
+            .collect::<Result<Vec<u32>, String>>()?;
+
+        Ok(Exec {
+            uid: user.map(|u| u.uid),
+            gid,
+            groups,
+            env,
+            capabilities,
+        })
+    }
+
+    /// set `cmd` up to run this way; this comes before the KNOCK_* vars go in,
+    /// since clearing the environment would clear those too
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(env) = &self.env {
+            cmd.env_clear().envs(env.iter().map(|(k, v)| (k, v)));
+        }
+        if self.uid.is_none() && self.gid.is_none() && self.capabilities.is_empty() {
+            return;
+        }
+        let (uid, gid, groups, caps) = (
+            self.uid,
+            self.gid,
+            self.groups.iter().map(|g| g.as_raw()).collect::<Vec<libc::gid_t>>(),
+            self.capabilities.to_owned(),
+        );
+        // SAFETY: switch() only makes syscalls, which is all that's allowed
+        // between fork and exec
+        unsafe {
+            cmd.pre_exec(move || switch(uid, gid, &groups, &caps));
+        }
+    }
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+fn check(ret: libc::c_long) -> io::Result<()> {
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// runs in the child, between fork and exec: change groups, then the gid,
+/// then the uid (keeping our capabilities through that), then make `caps`
+/// ambient so they survive the exec. std's Command::uid() happens before
+/// pre_exec, too late to hang on to anything, so this does all of it.
+fn switch(uid: Option<Uid>, gid: Option<Gid>, groups: &[libc::gid_t], caps: &[u32]) -> io::Result<()> {
+    unsafe {
+        if let Some(g) = gid {
+            check(libc::setgroups(groups.len(), groups.as_ptr()) as libc::c_long)?;
+            check(libc::setgid(g.as_raw()) as libc::c_long)?;
+        }
+        if !caps.is_empty() {
+            check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) as libc::c_long)?;
+        }
+        if let Some(u) = uid {
+            check(libc::setuid(u.as_raw()) as libc::c_long)?;
+        }
+        if caps.is_empty() {
+            return Ok(());
+        }
+
+        let mut header = CapHeader {
+            version: LINUX_CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapData::default(); 2];
+        for c in caps {
+            let d = &mut data[(*c / 32) as usize];
+            let bit = 1 << (c % 32);
+            d.effective |= bit;
+            d.permitted |= bit;
+            d.inheritable |= bit;
+        }
+        check(libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapHeader,
+            data.as_ptr(),
+        ))?;
+        for c in caps {
+            check(libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE,
+                *c as libc::c_ulong,
+                0,
+                0,
+            ) as libc::c_long)?;
+        }
+    }
+    Ok(())
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{test_vars, CommandSpec, Runner};
+    use std::time::Duration;
+
+    async fn output(exec: Exec, script: &str) -> String {
+        let runner = Runner::new(1, Duration::from_secs(5)).with_exec(exec);
+        let out = runner
+            .run(
+                &CommandSpec::Argv(vec!["sh".into(), "-c".into(), script.into()]),
+                &test_vars("10.1.2.3", 5),
+            )
+            .await
+            .unwrap();
+        String::from_utf8_lossy(&out.stdout).trim().to_string()
+    }
+
+    #[test]
+    fn capabilities() {
+        assert_eq!(capability("CAP_NET_ADMIN"), Some(12));
+        assert_eq!(capability("net_admin"), Some(12));
+        assert_eq!(capability("CAP_CHOWN"), Some(0));
+        assert_eq!(capability("CAP_NOPE"), None);
+    }
+
+    #[test]
+    fn settings() {
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                run_as_user = "nobody"
+                clear_env = true
+                ambient_capabilities = [ "CAP_NET_ADMIN" ]
+                command = "ignored here"
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let top = settings.try_deserialize::<RunAs>().unwrap();
+        let door = RunAs {
+            run_as_user: Some("root".to_string()),
+            ..Default::default()
+        }
+        .or(&top);
+
+        assert_eq!(door.run_as_user.as_deref(), Some("root"));
+        assert_eq!(door.clear_env, Some(true));
+        assert_eq!(door.ambient_capabilities, top.ambient_capabilities);
+
+        let e = Exec::new(&door).unwrap();
+        assert_eq!(e.uid, Some(Uid::from_raw(0)));
+        assert_eq!(e.gid, Some(Gid::from_raw(0)));
+        assert_eq!(e.capabilities, vec![12]);
+
+        // switching users clears the environment unless it's told not to
+        let mut r = RunAs {
+            run_as_user: Some("root".to_string()),
+            ..Default::default()
+        };
+        let env = Exec::new(&r).unwrap().env.unwrap();
+        assert!(std::env::var("CARGO_MANIFEST_DIR").is_ok());
+        assert!(!env.iter().any(|(k, _)| k == "CARGO_MANIFEST_DIR"));
+        r.clear_env = Some(false);
+        assert_eq!(Exec::new(&r).unwrap().env, None);
+
+        assert!(Exec::new(&RunAs {
+            run_as_user: Some("definitely-not-a-user".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Exec::new(&RunAs {
+            ambient_capabilities: Some(vec!["CAP_NOPE".to_string()]),
+            ..Default::default()
+        })
+        .is_err());
+        assert_eq!(Exec::new(&RunAs::default()).unwrap(), Exec::default());
+    }
+
+    #[tokio::test]
+    async fn environment() {
+        let e = Exec::new(&RunAs {
+            clear_env: Some(true),
+            env_allow: Some(vec!["HOME".to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+        let home = std::env::var("HOME").unwrap_or_else(|_| "unset".to_string());
+
+        assert_eq!(
+            output(e.to_owned(), "echo ${{HOME-unset}} $PATH $KNOCK_IP").await,
+            format!("{home} {DEFAULT_PATH} 10.1.2.3")
+        );
+        // cargo sets this for the tests, but it's not on the list
+        assert!(std::env::var("CARGO_MANIFEST_DIR").is_ok());
+        assert_eq!(output(e, "echo ${{CARGO_MANIFEST_DIR-gone}}").await, "gone");
+    }
+
+    #[tokio::test]
+    async fn users() {
+        if !Uid::effective().is_root() {
+            return; // can't switch without root
+        }
+        let e = Exec::new(&RunAs {
+            run_as_user: Some("nobody".to_string()),
+            ambient_capabilities: Some(vec!["CAP_NET_ADMIN".to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+        let nobody = User::from_name("nobody").unwrap().unwrap();
+
+        assert_eq!(output(e.to_owned(), "id -u").await, nobody.uid.to_string());
+        assert_eq!(output(e.to_owned(), "echo ${{CARGO_MANIFEST_DIR-gone}}").await, "gone");
+        assert_eq!(
+            output(e, "grep ^CapAmb /proc/self/status").await,
+            "CapAmb:\t0000000000001000"
+        );
+    }
+}
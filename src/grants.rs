@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use crate::command::KnockVars;
+
+/// An IP that door has opened up (or is in the middle of opening up).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub src: SocketAddr,
+    pub vars: KnockVars,
+    /// unix seconds
+    pub granted: u64,
+    pub refreshed: u64,
+    pub expires: u64,
+}
+
+/// What to do about a verified knock, given the grants we already have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// no active grant; run the pipeline
+    New,
+    /// there's an active grant that was refreshed less than `grace` seconds
+    /// ago; nothing to do
+    Duplicate { expires: u64 },
+    /// there's an active grant, push its expiry out and run the pipeline
+    /// again so the firewall (or whatever) agrees; `previous` is the expiry
+    /// to go back to if that fails
+    Extend { previous: u64, expires: u64 },
+}
+
+/// Active grants by door and IP.
+#[derive(Debug, Default)]
+pub struct Grants {
+    map: HashMap<(String, IpAddr), Grant>,
+    grace: u64,
+}
+
+pub type SharedGrants = Arc<Mutex<Grants>>;
+
+impl Grants {
+    pub fn new(grace: u64) -> Self {
+        Grants {
+            map: HashMap::new(),
+            grace,
+        }
+    }
+
+    pub fn set_grace(&mut self, grace: u64) {
+        self.grace = grace;
+    }
+
+    pub fn shared(grace: u64) -> SharedGrants {
+        Arc::new(Mutex::new(Grants::new(grace)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn get(&self, door: &str, ip: IpAddr) -> Option<&Grant> {
+        self.map.get(&(door.to_string(), ip))
+    }
+
+    /// all the grants, soonest to expire first
+    pub fn list(&self) -> Vec<Grant> {
+        let mut ret = self.map.values().cloned().collect::<Vec<Grant>>();
+        ret.sort_by_key(|g| (g.expires, g.vars.door.to_owned(), g.vars.ip.to_owned()));
+        ret
+    }
+
+    /// record the knock and decide what to do about it
+    pub fn decide(&mut self, src: &SocketAddr, vars: &KnockVars, now: u64) -> Decision {
+        let key = (vars.door.to_owned(), src.ip());
+        let expires = now + vars.duration;
+
+        match self.map.get_mut(&key) {
+            Some(g) if g.expires > now => {
+                if now < g.refreshed + self.grace {
+                    return Decision::Duplicate { expires: g.expires };
+                }
+                let previous = g.expires;
+                g.expires = g.expires.max(expires);
+                g.refreshed = now;
+                Decision::Extend {
+                    previous,
+                    expires: g.expires,
+                }
+            }
+            _ => {
+                self.map.insert(
+                    key,
+                    Grant {
+                        src: *src,
+                        vars: vars.to_owned(),
+                        granted: now,
+                        refreshed: now,
+                        expires,
+                    },
+                );
+                Decision::New
+            }
+        }
+    }
+
+    /// undo what decide() did, because the pipeline didn't work out
+    pub fn revert(&mut self, door: &str, ip: IpAddr, decision: Decision) {
+        let key = (door.to_string(), ip);
+        match decision {
+            Decision::New => {
+                self.map.remove(&key);
+            }
+            Decision::Extend { previous, .. } => {
+                if let Some(g) = self.map.get_mut(&key) {
+                    g.expires = previous;
+                }
+            }
+            Decision::Duplicate { .. } => (),
+        }
+    }
+
+    /// the grants that run out before `when`
+    pub fn expiring(&self, when: u64) -> Vec<Grant> {
+        self.list().into_iter().filter(|g| g.expires < when).collect()
+    }
+
+    /// push a grant's expiry out to `expires` (it's never pulled in); the
+    /// decision can be handed to revert() if the pipeline doesn't agree
+    pub fn renew(&mut self, door: &str, ip: IpAddr, expires: u64, now: u64) -> Option<Decision> {
+        let g = self.map.get_mut(&(door.to_string(), ip))?;
+        let previous = g.expires;
+        g.expires = g.expires.max(expires);
+        g.refreshed = now;
+        Some(Decision::Extend {
+            previous,
+            expires: g.expires,
+        })
+    }
+
+    pub fn remove(&mut self, door: &str, ip: IpAddr) -> Option<Grant> {
+        self.map.remove(&(door.to_string(), ip))
+    }
+
+    /// remove and return the grants that have run out
+    pub fn expire(&mut self, now: u64) -> Vec<Grant> {
+        let done = self
+            .map
+            .iter()
+            .filter(|(_, g)| g.expires <= now)
+            .map(|(k, _)| k.to_owned())
+            .collect::<Vec<(String, IpAddr)>>();
+        done.iter().filter_map(|k| self.map.remove(k)).collect()
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn knock(ip: &str, duration: u64) -> (SocketAddr, KnockVars) {
+        let src: SocketAddr = format!("{ip}:5555").parse().unwrap();
+        let local: SocketAddr = "0.0.0.0:20022".parse().unwrap();
+        (src, KnockVars::new(&src, &local, duration, 1000))
+    }
+
+    #[test]
+    fn dedup_and_extend() {
+        let mut g = Grants::new(2);
+        let (src, vars) = knock("10.1.2.3", 5);
+
+        assert_eq!(g.decide(&src, &vars, 1000), Decision::New);
+        assert_eq!(g.decide(&src, &vars, 1001), Decision::Duplicate { expires: 1005 });
+        assert_eq!(
+            g.decide(&src, &vars, 1003),
+            Decision::Extend {
+                previous: 1005,
+                expires: 1008
+            }
+        );
+        assert_eq!(g.decide(&src, &vars, 1004), Decision::Duplicate { expires: 1008 });
+
+        let (src2, vars2) = knock("10.1.2.4", 5);
+        assert_eq!(g.decide(&src2, &vars2, 1004), Decision::New);
+        assert_eq!(g.len(), 2);
+
+        assert!(g.expire(1008).iter().any(|x| x.vars.ip == "10.1.2.3"));
+        assert_eq!(g.len(), 1);
+        assert_eq!(g.decide(&src, &vars, 1009), Decision::New);
+        assert_eq!(g.list()[0].vars.ip, "10.1.2.4");
+    }
+
+    #[test]
+    fn revert() {
+        let mut g = Grants::new(0);
+        let (src, vars) = knock("10.1.2.3", 5);
+
+        let d = g.decide(&src, &vars, 1000);
+        g.revert(&vars.door, src.ip(), d);
+        assert!(g.is_empty());
+
+        g.decide(&src, &vars, 1000);
+        let d = g.decide(&src, &vars, 1002);
+        assert_eq!(g.get(&vars.door, src.ip()).unwrap().expires, 1007);
+        g.revert(&vars.door, src.ip(), d);
+        assert_eq!(g.get(&vars.door, src.ip()).unwrap().expires, 1005);
+    }
+
+    #[test]
+    fn renew() {
+        let mut g = Grants::new(2);
+        let (src, vars) = knock("10.1.2.3", 5);
+        let (src2, vars2) = knock("10.1.2.4", 60);
+
+        g.decide(&src, &vars, 1000);
+        g.decide(&src2, &vars2, 1000);
+        let soon = g.expiring(1010);
+        assert_eq!(soon.len(), 1);
+        assert_eq!(soon[0].vars.ip, "10.1.2.3");
+
+        let d = g.renew(&vars.door, src.ip(), 1015, 1004).unwrap();
+        assert_eq!(
+            d,
+            Decision::Extend {
+                previous: 1005,
+                expires: 1015
+            }
+        );
+        assert!(g.expiring(1010).is_empty());
+        g.revert(&vars.door, src.ip(), d);
+        assert_eq!(g.get(&vars.door, src.ip()).unwrap().expires, 1005);
+
+        assert_eq!(g.renew(&vars.door, "10.9.9.9".parse().unwrap(), 1015, 1004), None);
+    }
+}
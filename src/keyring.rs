@@ -0,0 +1,320 @@
+use serde::Deserialize;
+
+use crate::events::RejectReason;
+use crate::payload::valid_token;
+use crate::HMACFrobnicator;
+
+/// the id of the only key in a keyring made from a plain secret
+pub const SECRET_KEY_ID: &str = "secret";
+
+/// what the secret is if nobody says otherwise; door won't start with it
+pub const DEFAULT_SECRET: &str = "secret";
+
+/// A `[[key]]` in a keyring file. The times are unix timestamps; a key with
+/// neither is always valid.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct KeySpec {
+    pub id: String,
+    pub secret: String,
+    #[serde(default)]
+    pub not_before: Option<u64>,
+    #[serde(default)]
+    pub not_after: Option<u64>,
+    /// the identities knocks signed with this key may claim; empty means
+    /// it's shared, and they can claim anything
+    #[serde(default)]
+    pub identities: Vec<String>,
+}
+
+/// One generation of the shared secret (or one client's secret).
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key {
+    pub id: String,
+    pub hf: HMACFrobnicator,
+    pub not_before: Option<u64>,
+    pub not_after: Option<u64>,
+    pub identities: Vec<String>,
+}
+
+// no secrets in the logs
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key")
+            .field("id", &self.id)
+            .field("not_before", &self.not_before)
+            .field("not_after", &self.not_after)
+            .field("identities", &self.identities)
+            .finish()
+    }
+}
+
+impl Key {
+    pub fn valid_at(&self, now: u64) -> bool {
+        self.not_before.is_none_or(|t| t <= now) && self.not_after.is_none_or(|t| now < t)
+    }
+
+    /// whether a knock signed with this key can be from `identity`; whoever
+    /// has a shared key can be whoever they like
+    pub fn signs_for(&self, identity: Option<&str>) -> bool {
+        self.identities.is_empty() || identity.is_some_and(|i| self.identities.iter().any(|v| v == i))
+    }
+}
+
+/// where a keyring's keys came from, so it can read them again
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    /// a secret given in the config (or on the command line)
+    Inline,
+    /// an @file with a secret in it
+    SecretFile(String),
+    /// a keyring file
+    KeyringFile(String),
+}
+
+/// The keys a door accepts (or a knock picks from).
+///
+/// Rotating a key means adding the new one with a `not_before` a little in
+/// the future and giving the old one a `not_after` a little after that. While
+/// both are valid, doors take either and knocks use the new one, so the
+/// clients and doors don't all have to change over at the same moment.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Keyring {
+    /// newest (by not_before) first
+    keys: Vec<Key>,
+    source: Source,
+}
+
+impl Keyring {
+    /// a keyring with just the one always-valid key, from a secret (or an
+    /// @file with one in it)
+    pub fn secret(secret: &str) -> Result<Self, String> {
+        let hf = HMACFrobnicator::try_new(secret).map_err(|e| e.to_string())?;
+        Ok(Keyring {
+            keys: vec![Key {
+                id: SECRET_KEY_ID.to_string(),
+                hf,
+                not_before: None,
+                not_after: None,
+                identities: vec![],
+            }],
+            source: match secret.strip_prefix('@') {
+                Some(f) => Source::SecretFile(f.to_string()),
+                None => Source::Inline,
+            },
+        })
+    }
+
+    /// the keys from `specs`, which have to make sense together
+    pub fn from_specs(specs: &[KeySpec]) -> Result<Self, String> {
+        if specs.is_empty() {
+            return Err("there are no keys".to_string());
+        }
+        let mut keys: Vec<Key> = vec![];
+        for s in specs {
+            if !valid_token(&s.id) {
+                return Err(format!("{:?} isn't a valid key id", s.id));
+            }
+            if keys.iter().any(|k| k.id == s.id) {
+                return Err(format!("there's more than one key {}", s.id));
+            }
+            if s.secret.is_empty() {
+                return Err(format!("key {} has no secret", s.id));
+            }
+            if let Some(i) = s.identities.iter().find(|i| !valid_token(i)) {
+                return Err(format!("key {} has {:?}, which isn't a valid identity", s.id, i));
+            }
+            if let (Some(b), Some(a)) = (s.not_before, s.not_after) {
+                if a <= b {
+                    return Err(format!("key {} is never valid (not_after is before not_before)", s.id));
+                }
+            }
+            keys.push(Key {
+                id: s.id.to_owned(),
+                // the secrets are right there in the file, no @files
+                hf: HMACFrobnicator::with_key(&s.secret),
+                not_before: s.not_before,
+                not_after: s.not_after,
+                identities: s.identities.to_owned(),
+            });
+        }
+        keys.sort_by_key(|k| std::cmp::Reverse(k.not_before.unwrap_or(0)));
+        Ok(Keyring {
+            keys,
+            source: Source::Inline,
+        })
+    }
+
+    /// the `[[key]]`s in a keyring file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let specs = config::Config::builder()
+            .add_source(config::File::new(path, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| c.get::<Vec<KeySpec>>("key"))
+            .map_err(|e| format!("{path}: {e}"))?;
+        let mut ret = Keyring::from_specs(&specs).map_err(|e| format!("{path}: {e}"))?;
+        ret.source = Source::KeyringFile(path.to_string());
+        Ok(ret)
+    }
+
+    /// whether every key belongs to particular identities, so the identity
+    /// in a knock is as good as the key that signed it
+    pub fn per_identity(&self) -> bool {
+        self.keys.iter().all(|k| !k.identities.is_empty())
+    }
+
+    /// whether any of the keys is `secret`
+    pub fn has_secret(&self, secret: &str) -> bool {
+        let hf = HMACFrobnicator::with_key(secret);
+        self.keys.iter().any(|k| k.hf == hf)
+    }
+
+    /// the file the keys came from, if they came from one
+    pub fn file(&self) -> Option<&str> {
+        match &self.source {
+            Source::Inline => None,
+            Source::SecretFile(f) | Source::KeyringFile(f) => Some(f),
+        }
+    }
+
+    /// read the keys again from wherever they came from
+    pub fn reload(&self) -> Result<Self, String> {
+        let ret = match &self.source {
+            Source::Inline => return Ok(self.to_owned()),
+            Source::SecretFile(f) => Keyring::secret(&format!("@{f}"))?,
+            Source::KeyringFile(f) => Keyring::load(f)?,
+        };
+        if ret.keys.iter().any(|k| k.hf.is_empty()) {
+            return Err(format!("{} is empty", self.file().unwrap_or_default()));
+        }
+        Ok(ret)
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// the key a knock should use: the newest valid one
+    pub fn current(&self, now: u64) -> Option<&Key> {
+        self.keys.iter().find(|k| k.valid_at(now))
+    }
+
+    /// check msg's signature against every key that's valid `now`; gives
+    /// back the signed part and the key that signed it
+    pub fn verify(&self, msg: &str, now: u64) -> Result<(String, &Key), RejectReason> {
+        let mut ret = Err(RejectReason::BadSignature);
+        for k in self.keys.iter() {
+            match k.hf.to_owned().verify(msg) {
+                Ok(v) if k.valid_at(now) => return Ok((v, k)),
+                Ok(_) => ret = Err(RejectReason::ExpiredKey),
+                Err(RejectReason::BadSignature) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        ret
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(id: &str, not_before: Option<u64>, not_after: Option<u64>) -> KeySpec {
+        KeySpec {
+            id: id.to_string(),
+            secret: format!("{id} secret"),
+            not_before,
+            not_after,
+            identities: vec![],
+        }
+    }
+
+    #[test]
+    fn rotation() {
+        let ring = Keyring::from_specs(&[spec("old", None, Some(2000)), spec("new", Some(1000), None)]).unwrap();
+        let sign = |secret: &str| HMACFrobnicator::new(secret).sign("7");
+
+        assert_eq!(ring.current(500).unwrap().id, "old");
+        assert_eq!(ring.current(1500).unwrap().id, "new");
+        assert_eq!(ring.current(2500).unwrap().id, "new");
+
+        assert_eq!(ring.verify(&sign("old secret"), 500).unwrap().1.id, "old");
+        assert_eq!(
+            ring.verify(&sign("new secret"), 500).unwrap_err(),
+            RejectReason::ExpiredKey
+        );
+        assert_eq!(ring.verify(&sign("old secret"), 1500).unwrap().1.id, "old");
+        assert_eq!(ring.verify(&sign("new secret"), 1500).unwrap().1.id, "new");
+        assert_eq!(
+            ring.verify(&sign("old secret"), 2000).unwrap_err(),
+            RejectReason::ExpiredKey
+        );
+        assert_eq!(
+            ring.verify(&sign("other"), 1500).unwrap_err(),
+            RejectReason::BadSignature
+        );
+        assert_eq!(ring.verify("nonsense", 1500).unwrap_err(), RejectReason::BadFormat);
+    }
+
+    #[test]
+    fn bad_keyrings() {
+        assert!(Keyring::from_specs(&[]).is_err());
+        assert!(Keyring::from_specs(&[spec("a b", None, None)]).is_err());
+        assert!(Keyring::from_specs(&[spec("a", None, None), spec("a", Some(5), None)]).is_err());
+        assert!(Keyring::from_specs(&[spec("a", Some(5), Some(5))]).is_err());
+        let mut s = spec("a", None, None);
+        s.secret = "".to_string();
+        assert!(Keyring::from_specs(&[s]).is_err());
+        let mut s = spec("a", None, None);
+        s.identities = vec!["a b".to_string()];
+        assert!(Keyring::from_specs(&[s]).is_err());
+    }
+
+    #[test]
+    fn files() {
+        let dir = std::env::temp_dir().join(format!("rknock-keyring-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keyring.toml").to_string_lossy().to_string();
+        std::fs::write(
+            &path,
+            r#"
+            [[key]]
+            id = "2026-01"
+            secret = "@not-a-file"
+            not_after = 2000
+
+            [[key]]
+            id = "2026-07"
+            secret = "newer"
+            not_before = 1000
+            "#,
+        )
+        .unwrap();
+
+        let ring = Keyring::load(&path).unwrap();
+        assert_eq!(ring.file(), Some(path.as_str()));
+        assert_eq!(
+            ring.keys().iter().map(|k| k.id.as_str()).collect::<Vec<&str>>(),
+            vec!["2026-07", "2026-01"]
+        );
+        let signed = HMACFrobnicator::with_key("@not-a-file").sign("7");
+        assert_eq!(ring.verify(&signed, 1500).unwrap().1.id, "2026-01");
+        assert!(ring.reload().unwrap() == ring);
+
+        std::fs::write(&path, "[[key]]\nid = \"x\"\n").unwrap();
+        assert!(ring.reload().is_err());
+        assert!(Keyring::load("/nonexistent/keyring.toml").is_err());
+
+        let secret = dir.join("secret").to_string_lossy().to_string();
+        std::fs::write(&secret, "spooky\n").unwrap();
+        let ring = Keyring::secret(&format!("@{secret}")).unwrap();
+        assert_eq!(ring.file(), Some(secret.as_str()));
+        assert_eq!(ring.current(0).unwrap().id, SECRET_KEY_ID);
+        assert!(ring.has_secret("spooky"));
+        assert!(!ring.has_secret(DEFAULT_SECRET));
+        assert!(Keyring::secret(DEFAULT_SECRET).unwrap().has_secret(DEFAULT_SECRET));
+        std::fs::write(&secret, "").unwrap();
+        assert!(ring.reload().is_err());
+        assert!(Keyring::secret("spooky").unwrap().file().is_none());
+    }
+}
@@ -4,7 +4,7 @@ use std::env;
 use std::error::Error;
 use std::net::{Ipv4Addr, UdpSocket};
 use std::process::ExitCode;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
@@ -12,6 +12,8 @@ use rand::{thread_rng, Rng};
 use clap::{arg, crate_authors, crate_version, value_parser, App, ArgAction, ArgMatches, ValueSource};
 use config::Config;
 
+use rlib::keyring::Keyring;
+use rlib::payload::{parse_duration, valid_token, Ack, Payload};
 use rlib::{config_filez, grok_setting, is_default, HMACFrobnicator};
 
 trait Pfft {
@@ -34,7 +36,21 @@ impl Pfft for App<'_> {
     }
 }
 
-fn get_args() -> Result<(bool, bool, String, String, bool, u64), Box<dyn Error>> {
+struct Settings {
+    verbose: bool,
+    go: bool,
+    key: String,
+    keyring: String,
+    target: String,
+    disable_salt: bool,
+    time_code: u64,
+    duration: Option<u64>,
+    identity: Option<String>,
+    door: Option<String>,
+    ack: bool,
+}
+
+fn get_args() -> Result<Settings, Box<dyn Error>> {
     let matches = App::new("knock") .version(crate_version!()) .author(crate_authors!(", "))
         .about("Knocks on doors")
         .arg(arg!(verbose: -v --verbose "say what's happening on stdout").action(ArgAction::SetTrue))
@@ -67,6 +83,14 @@ fn get_args() -> Result<(bool, bool, String, String, bool, u64), Box<dyn Error>>
             .required(false)
             .default_value("secret")
         )
+        .arg(
+            arg!(keyring: -K --keyring <FILE> "use the newest valid key from this keyring file (TOML [[key]] \
+                 tables with an id, a secret and optionally not_before and not_after, as unix times) instead of \
+                 the secret")
+            .value_parser(value_parser!(String))
+            .required(false)
+            .default_value("")
+        )
         .arg(
             arg!(time_code: --"time-code" <TIMESTAMP> "use this timestamp instead of the current time")
                 .value_parser(value_parser!(u64))
@@ -78,6 +102,30 @@ fn get_args() -> Result<(bool, bool, String, String, bool, u64), Box<dyn Error>>
                 .action(ArgAction::SetTrue)
                 .required(false)
         )
+        .arg(
+            arg!(for: -f --for <DURATION> "ask the door to stay open this long (e.g. 90s, 2m, 1h); the door \
+                 decides how long it's actually willing to stay open")
+                .value_parser(value_parser!(String))
+                .required(false)
+                .default_value("")
+        )
+        .arg(
+            arg!(identity: -i --identity <NAME> "tell the door who's knocking (letters, digits and -_.@ only)")
+                .value_parser(value_parser!(String))
+                .required(false)
+                .default_value("")
+        )
+        .arg(
+            arg!(door: -d --door <NAME> "which door to knock on, when the door process guards more than one")
+                .value_parser(value_parser!(String))
+                .required(false)
+                .default_value("")
+        )
+        .arg(
+            arg!(ack: -a --ack "ask the door to say whether (and for how long) it opened, and wait a moment \
+                 for the answer (the door has to have acks enabled)")
+                .action(ArgAction::SetTrue)
+        )
         .my_get_matches();
 
     let filez = matches.get_many::<String>("config").expect("defaulted by clap");
@@ -92,10 +140,27 @@ fn get_args() -> Result<(bool, bool, String, String, bool, u64), Box<dyn Error>>
 
     let target: String = grok_setting!(matches, settings, "target", String);
     let key: String = grok_setting!(matches, settings, "secret", String);
+    let keyring: String = grok_setting!(matches, settings, "keyring", String);
     let verbose: bool = grok_setting!(matches, settings, "verbose", bool);
     let go: bool = grok_setting!(matches, settings, "go", bool);
     let disable_salt: bool = grok_setting!(matches, settings, "no_salt", bool);
     let time_code: u64 = grok_setting!(matches, settings, "time_code", u64);
+    let ack: bool = grok_setting!(matches, settings, "ack", bool);
+
+    let duration = match grok_setting!(matches, settings, "for", String).as_str() {
+        "" => None,
+        v => Some(parse_duration(v)?),
+    };
+    let identity = match grok_setting!(matches, settings, "identity", String).as_str() {
+        "" => None,
+        v if valid_token(v) => Some(v.to_string()),
+        v => return Err(format!("{v:?} isn't a valid identity").into()),
+    };
+    let door = match grok_setting!(matches, settings, "door", String).as_str() {
+        "" => None,
+        v if valid_token(v) => Some(v.to_string()),
+        v => return Err(format!("{v:?} isn't a valid door name").into()),
+    };
 
     // if verbose {
     //     println!("options:");
@@ -107,7 +172,56 @@ fn get_args() -> Result<(bool, bool, String, String, bool, u64), Box<dyn Error>>
     //     println!("  time-code: {time_code:?}");
     // }
 
-    Ok((verbose, go, key, target, disable_salt, time_code))
+    Ok(Settings {
+        verbose,
+        go,
+        key,
+        keyring,
+        target,
+        disable_salt,
+        time_code,
+        duration,
+        identity,
+        door,
+        ack,
+    })
+}
+
+/// wait (briefly) for the door to tell us how it went
+fn wait_for_ack(socket: &UdpSocket, hf: &mut HMACFrobnicator, nonce: &str, verbose: bool) -> ExitCode {
+    let mut buf = [0; 512];
+    socket
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .expect("non-zero timeouts are fine");
+
+    loop {
+        let amt = match socket.recv(&mut buf) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("no acknowledgement from the door: {e}");
+                return ExitCode::from(4);
+            }
+        };
+        let msg = String::from_utf8_lossy(&buf[..amt]);
+        if verbose {
+            println!("recv(\"{}\")", msg);
+        }
+        let ack = match hf.verify(&msg).ok().and_then(|m| Ack::parse(&m, nonce)) {
+            Some(v) => v,
+            None => continue, // not ours, or not real
+        };
+
+        return match ack.granted {
+            Some(d) => {
+                println!("door open for {d}s");
+                ExitCode::from(0)
+            }
+            None => {
+                eprintln!("the door heard the knock, but didn't open");
+                ExitCode::from(5)
+            }
+        };
+    }
 }
 
 macro_rules! my_sock_err {
@@ -130,33 +244,63 @@ macro_rules! my_sock_err {
 }
 
 fn main() -> ExitCode {
-    let (verbose, go, key_str, mut target, disable_salt, time_code) = match get_args() {
+    let settings = match get_args() {
         Ok(v) => v,
         Err(error) => {
             eprintln!("error building config: {error:?}");
             return ExitCode::from(27);
         }
     };
-    let mut hf = HMACFrobnicator::new(&key_str);
-    let now = if time_code > 0 {
-        time_code
+    let verbose = settings.verbose;
+    let mut target = settings.target.to_owned();
+    let now = if settings.time_code > 0 {
+        settings.time_code
     } else {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("systemtime fucked")
             .as_secs()
     };
+    let mut hf = match settings.keyring.as_str() {
+        "" => HMACFrobnicator::new(&settings.key),
+        k => {
+            let ring = match Keyring::load(k) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("error reading keyring: {e}");
+                    return ExitCode::from(27);
+                }
+            };
+            match ring.current(now) {
+                Some(key) => {
+                    if verbose {
+                        println!("using key {}", key.id);
+                    }
+                    key.hf.to_owned()
+                }
+                None => {
+                    eprintln!("none of the keys in {k} is valid right now");
+                    return ExitCode::from(27);
+                }
+            }
+        }
+    };
 
-    let nonce = if disable_salt {
-        format!("{}", now)
+    let salt: String = if settings.disable_salt {
+        "".to_string()
     } else {
-        let salt: String = thread_rng()
+        thread_rng()
             .sample_iter(&Alphanumeric)
             .take(13)
             .map(char::from)
-            .collect();
-        format!("{}${}", now, salt)
+            .collect()
     };
+    let mut payload = Payload::new(now, &salt);
+    payload.duration = settings.duration;
+    payload.identity = settings.identity.to_owned();
+    payload.door = settings.door.to_owned();
+    payload.ack = settings.ack;
+    let nonce = payload.encode();
 
     let msg = hf.sign(&nonce);
 
@@ -179,13 +323,19 @@ fn main() -> ExitCode {
     match socket.connect(&target) {
         Ok(_) => match socket.send(msg.as_bytes()) {
             Ok(_) => {
-                if go {
+                if settings.ack {
+                    let code = wait_for_ack(&socket, &mut hf, &nonce, verbose);
+                    if code != ExitCode::from(0) {
+                        return code;
+                    }
+                }
+                if settings.go {
                     let target_parts: Vec<&str> = target.splitn(2, ':').collect();
                     let host_part: &str = target_parts[0];
                     if verbose {
                         println!("execvp(ssh {host_part})");
                     }
-                    let err = execvp("ssh", &["ssh", &host_part]);
+                    let err = execvp("ssh", &["ssh", host_part]);
                     eprintln!("execvp(ssh {host_part}) error: {err:?}");
                     return ExitCode::from(1);
                 }
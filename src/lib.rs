@@ -1,24 +1,60 @@
+pub mod allowlist;
+pub mod audit;
+pub mod command;
+pub mod conntrack;
+pub mod control;
+pub mod doors;
+pub mod events;
+pub mod exec;
+pub mod grants;
+pub mod keyring;
+pub mod listeners;
+pub mod metrics;
+pub mod nft;
+pub mod payload;
+pub mod pipeline;
+pub mod policy;
+pub mod preflight;
+pub mod privsep;
+pub mod ratelimit;
+pub mod revocation;
+pub mod stream;
+pub mod trace;
+pub mod watch;
+
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use data_encoding::BASE64;
 use sha2::{Digest, Sha256};
 
+use events::RejectReason;
+
 pub fn read_from_file_sometimes(blah: &str) -> String {
-    let blah_str: String = blah.to_string();
+    try_read_from_file_sometimes(blah).expect("couldn't read file")
+}
 
-    if blah_str.starts_with('@') {
-        let fname = &blah_str[1..];
-        return fs::read_to_string(fname)
-            .expect("couldn't read file")
-            .trim()
-            .to_string();
+/// like read_from_file_sometimes(), but a file that can't be read is an error
+/// rather than a panic, for things (like a config reload) that shouldn't die
+pub fn try_read_from_file_sometimes(blah: &str) -> std::io::Result<String> {
+    match blah.strip_prefix('@') {
+        Some(fname) => fs::read_to_string(fname)
+            .map(|v| v.trim().to_string())
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{fname}: {e}"))),
+        None => Ok(blah.to_string()),
     }
+}
 
-    blah_str
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("systemtime fucked")
+        .as_secs()
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub struct HMACFrobnicator {
     key: String,
 }
@@ -30,6 +66,21 @@ impl HMACFrobnicator {
         }
     }
 
+    pub fn try_new(key: &str) -> std::io::Result<Self> {
+        Ok(HMACFrobnicator {
+            key: try_read_from_file_sometimes(key)?,
+        })
+    }
+
+    /// `key` as it is, even if it starts with an @
+    pub fn with_key(key: &str) -> Self {
+        HMACFrobnicator { key: key.to_string() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key.is_empty()
+    }
+
     pub fn signature(&mut self, msg: &str) -> String {
         let internal = format!("{}:{}", msg, self.key);
         let mut hasher = Sha256::new();
@@ -42,7 +93,7 @@ impl HMACFrobnicator {
         format!("{}:{}", msg, self.signature(msg))
     }
 
-    pub fn verify(&mut self, msg: &str) -> Result<String, String> {
+    pub fn verify(&mut self, msg: &str) -> Result<String, RejectReason> {
         let buf = msg.as_bytes();
         let mut mpart: Option<&[u8]> = None;
         let mut spart: Option<&[u8]> = None;
@@ -61,10 +112,10 @@ impl HMACFrobnicator {
                 if self.signature(&lhs) == rhs {
                     Ok(lhs.to_string())
                 } else {
-                    Err("invalid signature".to_owned())
+                    Err(RejectReason::BadSignature)
                 }
             }
-            _ => Err("invalid message format".to_owned()),
+            _ => Err(RejectReason::BadFormat),
         }
     }
 }
@@ -132,8 +183,8 @@ mod tests {
          | xxd -r -p | uuencode -m supz | head -n 2 | tail -n 1
        1234:iKC5sOqv+cjt3IG3qfQ/B4Xwyvz7069Zl7hGN+7ea2E=
     */
-    static KNOWN: &'static str = "1234:iKC5sOqv+cjt3IG3qfQ/B4Xwyvz7069Zl7hGN+7ea2E=";
-    static K_BAD: &'static str = "1234:iKC6sOqv+cjt3IG3qfQ/B4Xwyvz7069Zl7hGN+7ea2E=";
+    static KNOWN: &str = "1234:iKC5sOqv+cjt3IG3qfQ/B4Xwyvz7069Zl7hGN+7ea2E=";
+    static K_BAD: &str = "1234:iKC6sOqv+cjt3IG3qfQ/B4Xwyvz7069Zl7hGN+7ea2E=";
 
     #[test]
     fn sign_something() {
@@ -182,5 +233,7 @@ mod tests {
     fn test_files_sometimes() {
         assert_eq!(read_from_file_sometimes("Makefile"), "Makefile");
         assert!(read_from_file_sometimes("@Makefile").trim().starts_with("VERSION"));
+        assert!(try_read_from_file_sometimes("@/nonexistent/secret").is_err());
+        assert!(HMACFrobnicator::try_new("@/nonexistent/secret").is_err());
     }
 }
@@ -0,0 +1,239 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::payload::valid_token;
+
+/// untagged listeners get this tag
+pub const DEFAULT_TAG: &str = "default";
+
+/// One address door listens on.
+///
+/// In the config this is either a string, `"{addr}:{port}"` or
+/// `"{addr}:{port}@{interface}"`, or a table with the same `addr` and
+/// `interface` plus a `tag`, a timestamp `window` and the `doors` it serves.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "ListenRepr")]
+pub struct Listen {
+    pub addr: String,
+    /// only take knocks that arrive on this interface (SO_BINDTODEVICE)
+    pub interface: Option<String>,
+    /// passed to commands as {listener}, e.g. "wan" or "vpn"
+    pub tag: String,
+    /// how many seconds old a knock's timestamp can be
+    pub window: u64,
+    /// the doors knocks on this listener may use; empty means all of them
+    pub doors: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListenRepr {
+    Short(String),
+    Table {
+        addr: String,
+        interface: Option<String>,
+        tag: Option<String>,
+        window: Option<u64>,
+        #[serde(default)]
+        doors: Vec<String>,
+    },
+}
+
+impl TryFrom<ListenRepr> for Listen {
+    type Error = String;
+
+    fn try_from(r: ListenRepr) -> Result<Self, Self::Error> {
+        match r {
+            ListenRepr::Short(s) => Listen::parse(&s),
+            ListenRepr::Table {
+                addr,
+                interface,
+                tag,
+                window,
+                doors,
+            } => {
+                let mut ret = Listen::parse(&addr)?;
+                if interface.is_some() {
+                    ret.interface = interface;
+                }
+                if let Some(t) = tag {
+                    if !valid_token(&t) {
+                        return Err(format!("{t:?} isn't a valid listener tag"));
+                    }
+                    ret.tag = t;
+                }
+                if let Some(w) = window {
+                    ret.window = w;
+                }
+                ret.doors = doors;
+                Ok(ret)
+            }
+        }
+    }
+}
+
+impl Listen {
+    /// `"{addr}:{port}"` or `"{addr}:{port}@{interface}"`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (addr, interface) = match s.rsplit_once('@') {
+            Some((a, i)) if !i.is_empty() => (a, Some(i.to_string())),
+            Some(_) => return Err(format!("{s:?} names an empty interface")),
+            None => (s, None),
+        };
+        if addr.is_empty() {
+            return Err(format!("{s:?} doesn't have an address"));
+        }
+        Ok(Listen {
+            addr: addr.to_string(),
+            interface,
+            tag: DEFAULT_TAG.to_string(),
+            window: 1,
+            doors: vec![],
+        })
+    }
+
+    /// whether a knock from `timestamp` is recent enough (and not from the future)
+    pub fn fresh(&self, timestamp: u64, now: u64) -> bool {
+        timestamp <= now && now - timestamp <= self.window
+    }
+
+    pub fn serves(&self, door: &str) -> bool {
+        self.doors.is_empty() || self.doors.iter().any(|d| d == door)
+    }
+
+    pub fn resolve(&self) -> io::Result<SocketAddr> {
+        self.addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't resolve", self.addr)))
+    }
+
+    /// bind a (non-blocking) socket for this listener; `v6only` keeps an IPv6
+    /// wildcard from also grabbing the IPv4 side of the port, which another
+    /// listener might want
+    pub fn bind(&self, v6only: bool) -> io::Result<std::net::UdpSocket> {
+        let addr = self.resolve()?;
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(v6only)?;
+        }
+        if let Some(i) = &self.interface {
+            socket.bind_device(Some(i.as_bytes()))?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        Ok(socket.into())
+    }
+
+    /// a name for the logs
+    pub fn describe(&self) -> String {
+        match &self.interface {
+            Some(i) => format!("{}@{} ({})", self.addr, i, self.tag),
+            None => format!("{} ({})", self.addr, self.tag),
+        }
+    }
+}
+
+/// whether an IPv6 listener should leave the IPv4 side of its port alone,
+/// i.e., whether some other listener wants an IPv4 address on that port
+pub fn wants_v6only(listen: &[Listen], idx: usize) -> bool {
+    let me = match listen[idx].resolve() {
+        Ok(a) if a.is_ipv6() => a,
+        _ => return false,
+    };
+    listen
+        .iter()
+        .filter_map(|l| l.resolve().ok())
+        .any(|a| a.is_ipv4() && a.port() == me.port())
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_forms() {
+        let l = Listen::parse("0.0.0.0:20022").unwrap();
+        assert_eq!(l.addr, "0.0.0.0:20022");
+        assert_eq!(l.interface, None);
+        assert_eq!(l.tag, DEFAULT_TAG);
+
+        let l = Listen::parse("[::]:20022@wg0").unwrap();
+        assert_eq!(l.addr, "[::]:20022");
+        assert_eq!(l.interface.as_deref(), Some("wg0"));
+
+        assert!(Listen::parse("0.0.0.0:20022@").is_err());
+        assert!(Listen::parse("@eth0").is_err());
+    }
+
+    #[test]
+    fn config_forms() {
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                listen = [
+                    "127.0.0.1:20022",
+                    { addr = "0.0.0.0:20023", interface = "eth0", tag = "wan", window = 0, doors = [ "web" ] },
+                ]
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let l = settings.get::<Vec<Listen>>("listen").unwrap();
+
+        assert_eq!(l[0], Listen::parse("127.0.0.1:20022").unwrap());
+        assert_eq!(l[1].interface.as_deref(), Some("eth0"));
+        assert_eq!(l[1].tag, "wan");
+        assert_eq!(l[1].window, 0);
+        assert!(l[1].serves("web"));
+        assert!(!l[1].serves("git"));
+        assert!(l[0].serves("git"));
+
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"listen = { addr = "0.0.0.0:1", tag = "no spaces" }"#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        assert!(settings.get::<Listen>("listen").is_err());
+    }
+
+    #[test]
+    fn windows() {
+        let mut l = Listen::parse("0.0.0.0:20022").unwrap();
+
+        assert!(l.fresh(100, 100));
+        assert!(l.fresh(99, 100));
+        assert!(!l.fresh(98, 100));
+        assert!(!l.fresh(101, 100));
+
+        l.window = 0;
+        assert!(l.fresh(100, 100));
+        assert!(!l.fresh(99, 100));
+    }
+
+    #[test]
+    fn dual_stack() {
+        let l = vec![
+            Listen::parse("0.0.0.0:20022").unwrap(),
+            Listen::parse("[::]:20022").unwrap(),
+            Listen::parse("[::]:20023").unwrap(),
+        ];
+
+        assert!(!wants_v6only(&l, 0));
+        assert!(wants_v6only(&l, 1));
+        assert!(!wants_v6only(&l, 2));
+
+        let a = Listen::parse("127.0.0.1:0").unwrap().bind(false).unwrap();
+        let port = a.local_addr().unwrap().port();
+        let b = Listen::parse(&format!("[::1]:{port}")).unwrap().bind(true).unwrap();
+        assert_eq!(b.local_addr().unwrap().port(), port);
+    }
+}
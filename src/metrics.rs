@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::events::RejectReason;
+use crate::pipeline::StepResult;
+use crate::unix_now;
+
+/// The `[metrics]` section of door's config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// where to serve /metrics and /healthz, e.g. 127.0.0.1:9120; empty
+    /// means nowhere
+    pub listen: String,
+    /// /healthz fails if door's main loop hasn't been round in this many
+    /// seconds
+    pub stale_after: u64,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings {
+            listen: String::new(),
+            stale_after: 10,
+        }
+    }
+}
+
+/// upper bounds (seconds) of the command latency histogram's buckets
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// per bucket, not cumulative; the last one is +Inf
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let idx = BUCKETS.iter().position(|b| secs <= *b).unwrap_or(BUCKETS.len());
+        self.counts[idx] += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let mut total = 0;
+        for (idx, count) in self.counts.iter().enumerate() {
+            total += count;
+            let le = BUCKETS.get(idx).map_or("+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {total}");
+        }
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {total}");
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    received: u64,
+    verified: u64,
+    rejected: BTreeMap<&'static str, u64>,
+    dropped: BTreeMap<&'static str, u64>,
+    commands: u64,
+    failed: u64,
+    /// commands refused because the runner's queue was full
+    refused: u64,
+    /// audit records dropped because the writer was behind
+    audit_dropped: u64,
+    latency: Histogram,
+    grants: u64,
+    nonces: u64,
+    sources: u64,
+    banned: u64,
+    /// when the main loop last said it was alive
+    heartbeat: u64,
+    started: u64,
+}
+
+/// Counters and gauges for door, shared between the main loop, the tasks
+/// running commands and the HTTP listener. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let ret = Metrics::default();
+        ret.with(|m| m.started = unix_now());
+        ret.heartbeat();
+        ret
+    }
+
+    fn with<F: FnOnce(&mut Inner)>(&self, f: F) {
+        f(&mut self.inner.lock().expect("metrics lock"))
+    }
+
+    pub fn received(&self) {
+        self.with(|m| m.received += 1)
+    }
+
+    pub fn verified(&self) {
+        self.with(|m| m.verified += 1)
+    }
+
+    pub fn rejected(&self, reason: RejectReason) {
+        self.with(|m| *m.rejected.entry(reason.as_str()).or_default() += 1)
+    }
+
+    /// rate limited or banned, before door looked at it
+    pub fn dropped(&self, reason: RejectReason) {
+        self.with(|m| *m.dropped.entry(reason.as_str()).or_default() += 1)
+    }
+
+    /// the commands a pipeline ran
+    pub fn commands(&self, steps: &[StepResult]) {
+        self.with(|m| {
+            for s in steps {
+                m.commands += 1;
+                if !s.ok() {
+                    m.failed += 1;
+                }
+                m.latency.observe(s.elapsed);
+            }
+        })
+    }
+
+    /// how many commands the runners have refused so far, queues being full
+    pub fn refused(&self, refused: u64) {
+        self.with(|m| m.refused = refused)
+    }
+
+    /// how many audit records have been dropped so far, the log being behind
+    pub fn audit_dropped(&self, dropped: u64) {
+        self.with(|m| m.audit_dropped = dropped)
+    }
+
+    /// the things that are counted elsewhere, as they are now
+    pub fn gauges(&self, grants: usize, nonces: usize, sources: usize, banned: usize) {
+        self.with(|m| {
+            m.grants = grants as u64;
+            m.nonces = nonces as u64;
+            m.sources = sources as u64;
+            m.banned = banned as u64;
+        })
+    }
+
+    pub fn heartbeat(&self) {
+        self.with(|m| m.heartbeat = unix_now())
+    }
+
+    /// whether the main loop has been round in the last `stale_after` seconds
+    pub fn healthy(&self, stale_after: u64, now: u64) -> bool {
+        let m = self.inner.lock().expect("metrics lock");
+        now.saturating_sub(m.heartbeat) <= stale_after
+    }
+
+    /// the counters, for `door ctl stats`
+    pub fn counters(&self) -> BTreeMap<String, u64> {
+        let m = self.inner.lock().expect("metrics lock");
+        let mut ret = BTreeMap::from([
+            ("start_time".to_string(), m.started),
+            ("received".to_string(), m.received),
+            ("verified".to_string(), m.verified),
+            ("rejected".to_string(), m.rejected.values().sum()),
+            ("dropped".to_string(), m.dropped.values().sum()),
+            ("commands".to_string(), m.commands),
+            ("commands_failed".to_string(), m.failed),
+            ("commands_refused".to_string(), m.refused),
+            ("audit_dropped".to_string(), m.audit_dropped),
+        ]);
+        for (what, counts) in [("rejected", &m.rejected), ("dropped", &m.dropped)] {
+            for (reason, v) in counts.iter() {
+                ret.insert(format!("{what}.{reason}"), *v);
+            }
+        }
+        ret
+    }
+
+    /// everything, in the Prometheus text format
+    pub fn render(&self) -> String {
+        let m = self.inner.lock().expect("metrics lock");
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, u64)>| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, v) in values {
+                let _ = writeln!(out, "{name}{labels} {v}");
+            }
+        };
+        let labelled = |label: &str, counts: &BTreeMap<&'static str, u64>| {
+            counts
+                .iter()
+                .map(|(k, v)| (format!("{{{label}=\"{k}\"}}"), *v))
+                .collect::<Vec<(String, u64)>>()
+        };
+        let plain = |v: u64| vec![(String::new(), v)];
+
+        metric(
+            "door_packets_received_total",
+            "counter",
+            "Datagrams received.",
+            plain(m.received),
+        );
+        metric(
+            "door_packets_verified_total",
+            "counter",
+            "Knocks that verified, whether or not anything ran.",
+            plain(m.verified),
+        );
+        metric(
+            "door_packets_rejected_total",
+            "counter",
+            "Datagrams rejected, by reason.",
+            labelled("reason", &m.rejected),
+        );
+        metric(
+            "door_packets_dropped_total",
+            "counter",
+            "Datagrams dropped unread by the rate limiter, by reason.",
+            labelled("reason", &m.dropped),
+        );
+        metric(
+            "door_commands_total",
+            "counter",
+            "Pipeline commands run.",
+            plain(m.commands),
+        );
+        metric(
+            "door_commands_failed_total",
+            "counter",
+            "Pipeline commands that failed.",
+            plain(m.failed),
+        );
+        metric(
+            "door_commands_refused_total",
+            "counter",
+            "Commands and hooks not run because too many were already waiting.",
+            plain(m.refused),
+        );
+        metric(
+            "door_audit_dropped_total",
+            "counter",
+            "Audit records not written because too many were already waiting.",
+            plain(m.audit_dropped),
+        );
+        metric(
+            "door_active_grants",
+            "gauge",
+            "Grants that haven't run out.",
+            plain(m.grants),
+        );
+        metric(
+            "door_replay_cache_size",
+            "gauge",
+            "Nonces remembered to catch replays.",
+            plain(m.nonces),
+        );
+        metric(
+            "door_rate_limited_sources",
+            "gauge",
+            "Sources (prefixes) the rate limiter is tracking.",
+            plain(m.sources),
+        );
+        metric(
+            "door_start_time_seconds",
+            "gauge",
+            "When door started, in unix seconds.",
+            plain(m.started),
+        );
+        metric(
+            "door_banned_sources",
+            "gauge",
+            "Sources (prefixes) banned for too many invalid knocks.",
+            plain(m.banned),
+        );
+
+        let name = "door_command_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} How long pipeline commands took.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        m.latency.render(&mut out, name);
+        out
+    }
+}
+
+/// the status line and body for a request line
+fn respond(request: &str, metrics: &Metrics, stale_after: u64) -> (&'static str, String) {
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    if method != "GET" {
+        return ("405 Method Not Allowed", "only GET\n".to_string());
+    }
+    match path.split('?').next().unwrap_or_default() {
+        "/metrics" => ("200 OK", metrics.render()),
+        "/healthz" => match metrics.healthy(stale_after, unix_now()) {
+            true => ("200 OK", "ok\n".to_string()),
+            false => ("503 Service Unavailable", "door's main loop is stuck\n".to_string()),
+        },
+        _ => ("404 Not Found", "try /metrics or /healthz\n".to_string()),
+    }
+}
+
+async fn handle(mut stream: TcpStream, metrics: &Metrics, stale_after: u64) -> std::io::Result<()> {
+    // the request line is all we need, and it'll be in the first read
+    let mut buf = [0u8; 1024];
+    let amt = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no request"))??;
+    let request = String::from_utf8_lossy(&buf[..amt]);
+    let (status, body) = respond(request.lines().next().unwrap_or_default(), metrics, stale_after);
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// serve /metrics and /healthz on `listener` until the task's dropped
+pub async fn serve(listener: TcpListener, metrics: Metrics, stale_after: u64) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("metrics: couldn't accept: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &metrics, stale_after).await {
+                debug!("metrics: {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(ms: u64, ok: bool) -> StepResult {
+        StepResult {
+            name: "action-1".to_string(),
+            command: "true".to_string(),
+            rollback: false,
+            status: Some(if ok { 0 } else { 1 }),
+            error: if ok { None } else { Some("exit status 1".to_string()) },
+            elapsed: Duration::from_millis(ms),
+        }
+    }
+
+    #[test]
+    fn rendering() {
+        let m = Metrics::new();
+        m.received();
+        m.received();
+        m.verified();
+        m.rejected(RejectReason::BadSignature);
+        m.dropped(RejectReason::Banned);
+        m.commands(&[step(3, true), step(70, false)]);
+        m.gauges(1, 2, 3, 4);
+        m.refused(5);
+        m.audit_dropped(6);
+
+        let counters = m.counters();
+        assert_eq!(counters["rejected"], 1);
+        assert_eq!(counters["dropped.banned"], 1);
+        assert_eq!(counters["commands_failed"], 1);
+
+        let out = m.render();
+        for line in [
+            "door_packets_received_total 2",
+            "door_packets_verified_total 1",
+            "door_packets_rejected_total{reason=\"bad_signature\"} 1",
+            "door_packets_dropped_total{reason=\"banned\"} 1",
+            "door_commands_total 2",
+            "door_commands_failed_total 1",
+            "door_commands_refused_total 5",
+            "door_audit_dropped_total 6",
+            "door_active_grants 1",
+            "door_replay_cache_size 2",
+            "door_rate_limited_sources 3",
+            "door_banned_sources 4",
+            "door_command_duration_seconds_bucket{le=\"0.005\"} 1",
+            "door_command_duration_seconds_bucket{le=\"0.05\"} 1",
+            "door_command_duration_seconds_bucket{le=\"0.1\"} 2",
+            "door_command_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "door_command_duration_seconds_count 2",
+            "# TYPE door_command_duration_seconds histogram",
+        ] {
+            assert!(out.lines().any(|l| l == line), "no {line:?} in\n{out}");
+        }
+    }
+
+    #[test]
+    fn requests() {
+        let m = Metrics::new();
+        assert_eq!(respond("GET /metrics HTTP/1.1", &m, 10).0, "200 OK");
+        assert_eq!(respond("GET /healthz HTTP/1.1", &m, 10).0, "200 OK");
+        assert_eq!(respond("GET /nope HTTP/1.1", &m, 10).0, "404 Not Found");
+        assert_eq!(respond("POST /metrics HTTP/1.1", &m, 10).0, "405 Method Not Allowed");
+        assert!(m.healthy(10, unix_now() + 10));
+        assert!(!m.healthy(10, unix_now() + 11));
+    }
+}
@@ -0,0 +1,179 @@
+use serde::Deserialize;
+
+use crate::command::CommandSpec;
+
+/// The `[nft]` section of door's config.
+///
+/// With `bootstrap` on, door creates an nftables table of its own at startup
+/// (and, with `teardown`, deletes it on the way out) instead of assuming
+/// someone already made one. The table holds a timeout set per address family
+/// and a chain that accepts knocked sources on the protected ports and drops
+/// everyone else there.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct NftSettings {
+    pub bootstrap: bool,
+    pub teardown: bool,
+    /// the nft binary
+    pub nft: String,
+    /// door's own table (in the inet family), which it'll replace wholesale
+    pub table: String,
+    /// the sets are named {set}_ipv4 and {set}_ipv6
+    pub set: String,
+    pub chain: String,
+    /// the ports only knocked sources get to
+    pub ports: Vec<u16>,
+    pub protocol: String,
+    /// the chain's priority relative to the filter priority (0), so it can go
+    /// before or after other tables' input chains
+    pub priority: i32,
+}
+
+impl Default for NftSettings {
+    fn default() -> Self {
+        NftSettings {
+            bootstrap: false,
+            teardown: true,
+            nft: "nft".to_string(),
+            table: "rknock".to_string(),
+            set: "knock".to_string(),
+            chain: "input".to_string(),
+            ports: vec![22],
+            protocol: "tcp".to_string(),
+            priority: -10,
+        }
+    }
+}
+
+impl NftSettings {
+    /// an nft script (for `nft -f -`) that (re)creates door's table
+    pub fn ruleset(&self) -> String {
+        let ports = self
+            .ports
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        let priority = match self.priority {
+            0 => "filter".to_string(),
+            p if p < 0 => format!("filter - {}", -(p as i64)),
+            p => format!("filter + {p}"),
+        };
+        let (t, s, c, proto) = (&self.table, &self.set, &self.chain, &self.protocol);
+
+        // creating the table before deleting it makes the delete work even
+        // when there's nothing there yet. Connections a knock let in stay up
+        // after its set element times out.
+        format!(
+            "table inet {t}\n\
+             delete table inet {t}\n\
+             table inet {t} {{\n\
+             \tset {s}_ipv4 {{\n\
+             \t\ttype ipv4_addr\n\
+             \t\tflags timeout\n\
+             \t}}\n\
+             \tset {s}_ipv6 {{\n\
+             \t\ttype ipv6_addr\n\
+             \t\tflags timeout\n\
+             \t}}\n\
+             \tchain {c} {{\n\
+             \t\ttype filter hook input priority {priority}; policy accept;\n\
+             \t\tct state established,related accept\n\
+             \t\t{proto} dport {{ {ports} }} ip saddr @{s}_ipv4 accept\n\
+             \t\t{proto} dport {{ {ports} }} ip6 saddr @{s}_ipv6 accept\n\
+             \t\t{proto} dport {{ {ports} }} drop\n\
+             \t}}\n\
+             }}\n"
+        )
+    }
+
+    /// an nft script that removes door's table
+    pub fn teardown_script(&self) -> String {
+        format!("delete table inet {}\n", self.table)
+    }
+
+    /// what door runs to load a script
+    pub fn load_command(&self) -> CommandSpec {
+        CommandSpec::Argv(vec![self.nft.to_owned(), "-f".to_string(), "-".to_string()])
+    }
+
+    /// what preflight runs (with the ruleset on stdin) to see if nft would take it
+    pub fn check_command(&self) -> CommandSpec {
+        CommandSpec::Argv(vec![
+            self.nft.to_owned(),
+            "-c".to_string(),
+            "-f".to_string(),
+            "-".to_string(),
+        ])
+    }
+
+    /// the command that adds a knock to the right set, for when the config
+    /// doesn't have one of its own
+    pub fn command(&self) -> CommandSpec {
+        CommandSpec::Argv(vec![
+            self.nft.to_owned(),
+            "add".to_string(),
+            "element".to_string(),
+            "inet".to_string(),
+            self.table.to_owned(),
+            format!("{}_{{family}}", self.set),
+            "{{ {ip} timeout {duration}s }}".to_string(),
+        ])
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::KnockVars;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn rulesets() {
+        let n = NftSettings {
+            ports: vec![22, 2222],
+            ..Default::default()
+        };
+        let r = n.ruleset();
+
+        assert!(r.starts_with("table inet rknock\ndelete table inet rknock\ntable inet rknock {\n"));
+        assert!(r.contains("\tset knock_ipv4 {\n\t\ttype ipv4_addr\n\t\tflags timeout\n\t}\n"));
+        assert!(r.contains("\tset knock_ipv6 {\n\t\ttype ipv6_addr\n\t\tflags timeout\n\t}\n"));
+        assert!(r.contains("type filter hook input priority filter - 10; policy accept;"));
+        assert!(r.contains("tcp dport { 22, 2222 } ip saddr @knock_ipv4 accept\n"));
+        let established = r.find("\t\tct state established,related accept\n").unwrap();
+        assert!(established > r.find("policy accept;").unwrap());
+        assert!(established < r.find("@knock_ipv4 accept").unwrap());
+        assert!(r.contains("tcp dport { 22, 2222 } ip6 saddr @knock_ipv6 accept\n"));
+        assert!(r.contains("tcp dport { 22, 2222 } drop\n"));
+
+        let n = NftSettings {
+            priority: 5,
+            ..Default::default()
+        };
+        assert!(n.ruleset().contains("priority filter + 5;"));
+        assert_eq!(n.teardown_script(), "delete table inet rknock\n");
+    }
+
+    #[test]
+    fn commands() {
+        let n = NftSettings::default();
+        let local: SocketAddr = "0.0.0.0:20022".parse().unwrap();
+        let v4 = KnockVars::new(&"10.1.2.3:5555".parse().unwrap(), &local, 5, 1234);
+        let v6 = KnockVars::new(&"[2001:db8::7]:5555".parse().unwrap(), &local, 5, 1234);
+
+        assert_eq!(
+            n.command().render(&v4).unwrap().1,
+            vec![
+                "add",
+                "element",
+                "inet",
+                "rknock",
+                "knock_ipv4",
+                "{ 10.1.2.3 timeout 5s }"
+            ]
+        );
+        assert_eq!(n.command().render(&v6).unwrap().1[4], "knock_ipv6");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::events::RejectReason;
+
+/// The signed part of a knock.
+///
+/// On the wire it's `{timestamp}${salt}` followed by any number of
+/// `${key}={value}` fields, e.g. `1660000000$UfO3kMG2PXxrS$for=120`. Doors
+/// that don't know about a field ignore it, and a knock with no fields looks
+/// exactly like it always did.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Payload {
+    pub timestamp: u64,
+    pub salt: String,
+    /// requested grant duration (seconds)
+    pub duration: Option<u64>,
+    pub identity: Option<String>,
+    /// which of the door process's doors this knock is for
+    pub door: Option<String>,
+    /// the knock would like to hear back about how it went
+    pub ack: bool,
+    /// fields we don't know about
+    pub extra: Vec<(String, String)>,
+}
+
+/// identities and such go in the payload, so they can't have our separators in them
+pub fn valid_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 64
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || c == '@')
+}
+
+/// "90" or "90s" is 90 seconds, and then there's "2m", "1h" and "1d"
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        Some('d') => (&s[..s.len() - 1], 86400),
+        _ => (s, 1),
+    };
+    match num.parse::<u64>() {
+        Ok(v) => v.checked_mul(mult).ok_or_else(|| format!("{s:?} is too long")),
+        Err(_) => Err(format!("{s:?} isn't a duration (try 30s, 2m, 1h)")),
+    }
+}
+
+impl Payload {
+    pub fn new(timestamp: u64, salt: &str) -> Self {
+        Payload {
+            timestamp,
+            salt: salt.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut fields: Vec<String> = vec![];
+        if let Some(d) = self.duration {
+            fields.push(format!("for={d}"));
+        }
+        if let Some(i) = &self.identity {
+            fields.push(format!("id={i}"));
+        }
+        if let Some(d) = &self.door {
+            fields.push(format!("door={d}"));
+        }
+        if self.ack {
+            fields.push("ack=1".to_string());
+        }
+        for (k, v) in &self.extra {
+            fields.push(format!("{k}={v}"));
+        }
+
+        let mut ret = self.timestamp.to_string();
+        if !self.salt.is_empty() || !fields.is_empty() {
+            ret = format!("{}${}", ret, self.salt);
+        }
+        for f in fields {
+            ret = format!("{ret}${f}");
+        }
+        ret
+    }
+
+    pub fn parse(s: &str) -> Result<Self, RejectReason> {
+        let mut parts = s.split('$');
+        let timestamp = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<u64>()
+            .map_err(|_| RejectReason::BadTimestamp)?;
+        let mut ret = Payload::new(timestamp, parts.next().unwrap_or_default());
+
+        for field in parts {
+            let (k, v) = field.split_once('=').ok_or(RejectReason::BadFormat)?;
+            match k {
+                "for" => ret.duration = Some(v.parse::<u64>().map_err(|_| RejectReason::BadFormat)?),
+                "id" if valid_token(v) => ret.identity = Some(v.to_string()),
+                "id" => return Err(RejectReason::BadFormat),
+                "door" if valid_token(v) => ret.door = Some(v.to_string()),
+                "door" => return Err(RejectReason::BadFormat),
+                "ack" => ret.ack = v == "1",
+                _ => ret.extra.push((k.to_string(), v.to_string())),
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+/// What door tells a knock (that asked) about its grant: `ack${nonce}$for={seconds}`
+/// if the door is open, or `ack${nonce}$fail` if it isn't.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ack {
+    pub nonce: String,
+    pub granted: Option<u64>,
+}
+
+impl Ack {
+    pub fn encode(&self) -> String {
+        match self.granted {
+            Some(d) => format!("ack${}$for={}", self.nonce, d),
+            None => format!("ack${}$fail", self.nonce),
+        }
+    }
+
+    /// parse an ack, but only if it's about `nonce`
+    pub fn parse(s: &str, nonce: &str) -> Option<Self> {
+        let rest = s.strip_prefix("ack$")?.strip_prefix(nonce)?.strip_prefix('$')?;
+        let granted = match rest {
+            "fail" => None,
+            _ => Some(rest.strip_prefix("for=")?.parse::<u64>().ok()?),
+        };
+        Some(Ack {
+            nonce: nonce.to_string(),
+            granted,
+        })
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_style_payloads() {
+        assert_eq!(Payload::new(7, "").encode(), "7");
+        assert_eq!(Payload::new(7, "abc").encode(), "7$abc");
+        assert_eq!(Payload::parse("7").unwrap(), Payload::new(7, ""));
+        assert_eq!(Payload::parse("7$abc").unwrap(), Payload::new(7, "abc"));
+        assert_eq!(Payload::parse("x$abc"), Err(RejectReason::BadTimestamp));
+    }
+
+    #[test]
+    fn fields() {
+        let mut p = Payload::new(7, "");
+        p.duration = Some(120);
+        p.identity = Some("alice".to_string());
+        p.ack = true;
+        p.door = Some("git".to_string());
+
+        assert_eq!(p.encode(), "7$$for=120$id=alice$door=git$ack=1");
+        assert_eq!(Payload::parse(&p.encode()).unwrap(), p);
+
+        let q = Payload::parse("7$abc$for=3$future=thing").unwrap();
+        assert_eq!(q.duration, Some(3));
+        assert_eq!(q.extra, vec![("future".to_string(), "thing".to_string())]);
+
+        assert_eq!(Payload::parse("7$abc$for=soon"), Err(RejectReason::BadFormat));
+        assert_eq!(Payload::parse("7$abc$nope"), Err(RejectReason::BadFormat));
+        assert_eq!(Payload::parse("7$abc$id=a b"), Err(RejectReason::BadFormat));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("90s"), Ok(90));
+        assert_eq!(parse_duration("2m"), Ok(120));
+        assert_eq!(parse_duration("1h"), Ok(3600));
+        assert_eq!(parse_duration("1d"), Ok(86400));
+        assert!(parse_duration("2 fortnights").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn acks() {
+        let a = Ack {
+            nonce: "7$abc$for=120".to_string(),
+            granted: Some(60),
+        };
+
+        assert_eq!(a.encode(), "ack$7$abc$for=120$for=60");
+        assert_eq!(Ack::parse(&a.encode(), "7$abc$for=120"), Some(a));
+        assert_eq!(Ack::parse("ack$7$abc$fail", "7$abc").unwrap().granted, None);
+        assert_eq!(Ack::parse("ack$7$abd$fail", "7$abc"), None);
+        assert_eq!(Ack::parse("ack$7$abc$for=x", "7$abc"), None);
+    }
+}
@@ -0,0 +1,344 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{error, info, warn};
+
+use crate::command::{CommandSpec, KnockVars, RunError, Runner};
+
+/// What to do when an action in a pipeline fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// stop here, leave the earlier actions as they are
+    #[default]
+    Abort,
+    /// log it and keep going with the next action
+    Continue,
+    /// stop here and run the `undo` of every earlier action that worked, newest first
+    Rollback,
+}
+
+/// One step in a door's pipeline, e.g. "add the IP to the nft set".
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Action {
+    pub name: Option<String>,
+    pub command: CommandSpec,
+    /// how to take this action back if a later one fails with on_failure = "rollback"
+    pub undo: Option<CommandSpec>,
+    /// what to run instead of `command` when an existing grant is extended
+    pub extend: Option<CommandSpec>,
+    /// a check-mode version of `command` (e.g. with `nft -c`) for preflight to run
+    pub check: Option<CommandSpec>,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+}
+
+impl Action {
+    pub fn new(command: CommandSpec) -> Self {
+        Action {
+            name: None,
+            command,
+            undo: None,
+            extend: None,
+            check: None,
+            on_failure: OnFailure::default(),
+        }
+    }
+
+    pub fn label(&self, idx: usize) -> String {
+        match &self.name {
+            Some(n) => n.to_owned(),
+            None => format!("action-{}", idx + 1),
+        }
+    }
+}
+
+fn as_millis<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u128(d.as_millis())
+}
+
+fn from_millis<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    Ok(Duration::from_millis(u64::deserialize(d)?))
+}
+
+/// How one step went.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepResult {
+    pub name: String,
+    pub command: String,
+    pub rollback: bool,
+    pub status: Option<i32>,
+    pub error: Option<String>,
+    #[serde(
+        rename = "elapsed_ms",
+        serialize_with = "as_millis",
+        deserialize_with = "from_millis"
+    )]
+    pub elapsed: Duration,
+}
+
+impl StepResult {
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// How the whole pipeline went.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PipelineResult {
+    pub steps: Vec<StepResult>,
+    pub success: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Pipeline {
+    pub actions: Vec<Action>,
+}
+
+impl Pipeline {
+    pub fn new(actions: Vec<Action>) -> Self {
+        Pipeline { actions }
+    }
+
+    async fn step(
+        name: String,
+        rollback: bool,
+        command: &CommandSpec,
+        vars: &KnockVars,
+        runner: &Runner,
+    ) -> StepResult {
+        let start = Instant::now();
+        let (status, error) = match runner.run(command, vars).await {
+            Ok(o) => (o.status.code(), None),
+            Err(e) => (
+                match &e {
+                    RunError::Failed(o) => o.status.code(),
+                    _ => None,
+                },
+                Some(e.to_string()),
+            ),
+        };
+        StepResult {
+            name,
+            command: command.describe(vars),
+            rollback,
+            status,
+            error,
+            elapsed: start.elapsed(),
+        }
+    }
+
+    fn log_step(ip: &str, step: &StepResult) {
+        let what = if step.rollback { "undo" } else { "step" };
+        match &step.error {
+            None => info!(
+                "{} {}({}) ip={} ok in {}ms",
+                what,
+                step.name,
+                step.command,
+                ip,
+                step.elapsed.as_millis()
+            ),
+            Some(e) => error!("{} {}({}) ip={} fail {}", what, step.name, step.command, ip, e),
+        }
+    }
+
+    /// run each action in order, honoring each one's on_failure
+    pub async fn run(&self, vars: &KnockVars, runner: &Runner) -> PipelineResult {
+        self.run_inner(vars, runner, false).await
+    }
+
+    /// like run(), but for an IP that already has a grant; actions with an
+    /// `extend` command run that instead of their `command`
+    pub async fn extend(&self, vars: &KnockVars, runner: &Runner) -> PipelineResult {
+        self.run_inner(vars, runner, true).await
+    }
+
+    /// take a grant back: run the `undo` of every action that has one, newest
+    /// first, carrying on past failures so as much as possible gets undone
+    pub async fn revoke(&self, vars: &KnockVars, runner: &Runner) -> PipelineResult {
+        let mut ret = PipelineResult {
+            steps: vec![],
+            success: true,
+        };
+        for (idx, action) in self.actions.iter().enumerate().rev() {
+            if let Some(undo) = &action.undo {
+                let step = Self::step(action.label(idx), true, undo, vars, runner).await;
+                Self::log_step(&vars.ip, &step);
+                ret.success &= step.ok();
+                ret.steps.push(step);
+            }
+        }
+        ret
+    }
+
+    async fn run_inner(&self, vars: &KnockVars, runner: &Runner, extending: bool) -> PipelineResult {
+        let mut ret = PipelineResult {
+            steps: vec![],
+            success: true,
+        };
+        let mut done: Vec<(usize, &Action)> = vec![];
+
+        for (idx, action) in self.actions.iter().enumerate() {
+            let command = match (&action.extend, extending) {
+                (Some(e), true) => e,
+                _ => &action.command,
+            };
+            let step = Self::step(action.label(idx), false, command, vars, runner).await;
+            Self::log_step(&vars.ip, &step);
+            let ok = step.ok();
+            ret.steps.push(step);
+
+            if ok {
+                done.push((idx, action));
+                continue;
+            }
+
+            // a failed "continue" action is only in the steps; it doesn't
+            // fail the pipeline
+            if action.on_failure != OnFailure::Continue {
+                ret.success = false;
+            }
+            match action.on_failure {
+                OnFailure::Continue => continue,
+                OnFailure::Abort => {
+                    warn!("pipeline aborted at {} ip={}", action.label(idx), vars.ip);
+                    break;
+                }
+                OnFailure::Rollback => {
+                    warn!("pipeline rolling back from {} ip={}", action.label(idx), vars.ip);
+                    for (pidx, prev) in done.iter().rev() {
+                        if let Some(undo) = &prev.undo {
+                            let step = Self::step(prev.label(*pidx), true, undo, vars, runner).await;
+                            Self::log_step(&vars.ip, &step);
+                            ret.steps.push(step);
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::test_vars;
+
+    fn sh(name: &str, cmd: &str, undo: Option<&str>, on_failure: OnFailure) -> Action {
+        Action {
+            name: Some(name.to_string()),
+            command: CommandSpec::Shell(cmd.to_string()),
+            undo: undo.map(|u| CommandSpec::Shell(u.to_string())),
+            extend: None,
+            check: None,
+            on_failure,
+        }
+    }
+
+    fn names(res: &PipelineResult) -> Vec<String> {
+        res.steps
+            .iter()
+            .map(|s| {
+                format!(
+                    "{}{}{}",
+                    if s.rollback { "-" } else { "" },
+                    s.name,
+                    if s.ok() { "" } else { "!" }
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn pipeline_policies() {
+        let runner = Runner::new(1, Duration::from_secs(5));
+
+        let p = Pipeline::new(vec![
+            sh("a", "true", Some("true"), OnFailure::Abort),
+            sh("b", "false", None, OnFailure::Continue),
+            sh("c", "true", Some("true"), OnFailure::Abort),
+            sh("d", "exit 2", None, OnFailure::Rollback),
+            sh("e", "true", None, OnFailure::Abort),
+        ]);
+        let res = p.run(&test_vars("10.1.2.3", 5), &runner).await;
+
+        assert!(!res.success);
+        assert_eq!(names(&res), vec!["a", "b!", "c", "d!", "-c", "-a"]);
+        assert_eq!(res.steps[3].status, Some(2));
+
+        let p = Pipeline::new(vec![
+            sh("a", "true", None, OnFailure::Abort),
+            sh("b", "false", None, OnFailure::Abort),
+            sh("c", "true", None, OnFailure::Abort),
+        ]);
+        let res = p.run(&test_vars("10.1.2.3", 5), &runner).await;
+
+        assert!(!res.success);
+        assert_eq!(names(&res), vec!["a", "b!"]);
+
+        let p = Pipeline::new(vec![
+            sh("a", "true", Some("true"), OnFailure::Abort),
+            sh("b", "false", None, OnFailure::Continue),
+            sh("c", "true", None, OnFailure::Abort),
+        ]);
+        let res = p.run(&test_vars("10.1.2.3", 5), &runner).await;
+
+        assert!(res.success);
+        assert_eq!(names(&res), vec!["a", "b!", "c"]);
+
+        let p = Pipeline::new(vec![Action::new(CommandSpec::Shell("true".to_string()))]);
+        let res = p.run(&test_vars("10.1.2.3", 5), &runner).await;
+
+        assert!(res.success);
+        assert_eq!(names(&res), vec!["action-1"]);
+
+        let mut a = sh("a", "false", None, OnFailure::Abort);
+        a.extend = Some(CommandSpec::Shell("true".to_string()));
+        let p = Pipeline::new(vec![a]);
+
+        assert!(!p.run(&test_vars("10.1.2.3", 5), &runner).await.success);
+        assert!(p.extend(&test_vars("10.1.2.3", 5), &runner).await.success);
+
+        let p = Pipeline::new(vec![
+            sh("a", "true", Some("true"), OnFailure::Abort),
+            sh("b", "true", None, OnFailure::Abort),
+            sh("c", "true", Some("false"), OnFailure::Abort),
+        ]);
+        let res = p.revoke(&test_vars("10.1.2.3", 5), &runner).await;
+
+        assert!(!res.success);
+        assert_eq!(names(&res), vec!["-c!", "-a"]);
+    }
+
+    #[test]
+    fn actions_from_config() {
+        let settings = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                [[actions]]
+                name = "firewall"
+                command = [ "nft", "add", "element", "inet", "firewall", "knock", "{{ {ip} }}" ]
+                undo = [ "nft", "delete", "element", "inet", "firewall", "knock", "{{ {ip} }}" ]
+
+                [[actions]]
+                command = "echo {ip} >> /tmp/allowed"
+                on_failure = "rollback"
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let actions = settings.get::<Vec<Action>>("actions").unwrap();
+
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].on_failure, OnFailure::Abort);
+        assert!(matches!(actions[0].undo, Some(CommandSpec::Argv(_))));
+        assert_eq!(actions[1].label(1), "action-2");
+        assert_eq!(actions[1].on_failure, OnFailure::Rollback);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+/// The rules a door applies to a verified knock.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(default)]
+pub struct DoorPolicy {
+    /// seconds to grant when the knock doesn't ask for anything in particular
+    pub duration: u64,
+    /// the most a knock can ask for; 0 means "same as duration"
+    pub max_duration: u64,
+    /// per-identity overrides of max_duration
+    pub identity_max_duration: HashMap<String, u64>,
+    /// only these identities may knock; empty means anyone with the secret
+    pub identities: Vec<String>,
+}
+
+impl DoorPolicy {
+    pub fn allows(&self, identity: Option<&str>) -> bool {
+        match identity {
+            _ if self.identities.is_empty() => true,
+            Some(i) => self.identities.iter().any(|x| x == i),
+            None => false,
+        }
+    }
+
+    pub fn max_for(&self, identity: Option<&str>) -> u64 {
+        match identity.and_then(|i| self.identity_max_duration.get(i)) {
+            Some(m) => *m,
+            None if self.max_duration == 0 => self.duration,
+            None => self.max_duration,
+        }
+    }
+
+    /// how long to grant for, given what was asked for
+    pub fn grant_duration(&self, requested: Option<u64>, identity: Option<&str>) -> u64 {
+        let want = match requested {
+            Some(0) | None => self.duration,
+            Some(r) => r,
+        };
+        want.min(self.max_for(identity))
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamping() {
+        let mut p = DoorPolicy {
+            duration: 5,
+            ..Default::default()
+        };
+
+        assert_eq!(p.grant_duration(None, None), 5);
+        assert_eq!(p.grant_duration(Some(120), None), 5);
+        assert_eq!(p.grant_duration(Some(2), None), 2);
+
+        p.max_duration = 60;
+        p.identity_max_duration.insert("alice".to_string(), 3600);
+        p.identity_max_duration.insert("mallory".to_string(), 1);
+
+        assert_eq!(p.grant_duration(Some(120), None), 60);
+        assert_eq!(p.grant_duration(Some(120), Some("bob")), 60);
+        assert_eq!(p.grant_duration(Some(120), Some("alice")), 120);
+        assert_eq!(p.grant_duration(Some(7200), Some("alice")), 3600);
+        assert_eq!(p.grant_duration(None, Some("mallory")), 1);
+        assert_eq!(p.grant_duration(Some(0), None), 5);
+    }
+
+    #[test]
+    fn identities() {
+        let mut p = DoorPolicy::default();
+
+        assert!(p.allows(None));
+        assert!(p.allows(Some("mallory")));
+
+        p.identities = vec!["alice".to_string(), "bob".to_string()];
+
+        assert!(!p.allows(None));
+        assert!(!p.allows(Some("mallory")));
+        assert!(p.allows(Some("bob")));
+    }
+}
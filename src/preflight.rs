@@ -0,0 +1,249 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use strfmt::strfmt;
+
+use crate::command::{CommandSpec, KnockVars, RunError, Runner};
+use crate::unix_now;
+
+/// words a shell command might start with that aren't programs we can look for
+const SHELL_WORDS: &[&str] = &[
+    "if", "for", "while", "until", "case", "exec", "set", "export", "cd", "echo", "printf", "test", "true",
+    "false", "read", "eval", "command", "[", "{", "(", "!", ":", ".",
+];
+
+/// What preflight found out about one thing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub what: String,
+    pub problem: Option<String>,
+}
+
+impl Finding {
+    pub fn ok(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+/// Checks door's commands before any real knock depends on them: that their
+/// templates only use variables we have, that the programs exist, and (for
+/// the ones that have a check mode) that they'd actually work.
+#[derive(Debug, Default)]
+pub struct Preflight {
+    pub findings: Vec<Finding>,
+}
+
+/// the vars preflight formats commands with; the addresses are from the
+/// documentation ranges (RFC 5737 and RFC 3849), so nothing real gets let in
+pub fn sample_vars(v6: bool, door: &str) -> KnockVars {
+    let src: SocketAddr = match v6 {
+        true => "[2001:db8::1]:40000",
+        false => "192.0.2.1:40000",
+    }
+    .parse()
+    .expect("that's an address");
+    let local: SocketAddr = "0.0.0.0:20022".parse().expect("that's an address");
+    let mut ret = KnockVars::new(&src, &local, 5, unix_now());
+    ret.door = door.to_string();
+    ret.identity = "preflight".to_string();
+    ret
+}
+
+/// where `prog` is, looking in $PATH if it doesn't have a slash in it
+pub fn find_program(prog: &str) -> Option<PathBuf> {
+    let executable = |p: &Path| {
+        use std::os::unix::fs::PermissionsExt;
+        p.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    if prog.contains('/') {
+        let p = PathBuf::from(prog);
+        return executable(&p).then_some(p);
+    }
+    let path = std::env::var("PATH").unwrap_or_else(|_| "/usr/local/bin:/usr/bin:/bin".to_string());
+    std::env::split_paths(&path)
+        .map(|d| d.join(prog))
+        .find(|p| executable(p))
+}
+
+impl Preflight {
+    pub fn new() -> Self {
+        Preflight::default()
+    }
+
+    pub fn ok(&self) -> bool {
+        self.findings.iter().all(|f| f.ok())
+    }
+
+    pub fn problems(&self) -> Vec<&Finding> {
+        self.findings.iter().filter(|f| !f.ok()).collect()
+    }
+
+    fn note(&mut self, what: &str, problem: Option<String>) {
+        self.findings.push(Finding {
+            what: what.to_string(),
+            problem,
+        });
+    }
+
+    /// make sure `cmd` formats (for both address families) and that whatever
+    /// it runs is installed
+    pub fn command(&mut self, what: &str, cmd: &CommandSpec, door: &str) {
+        let mut rendered = None;
+        for v6 in [false, true] {
+            match cmd.render(&sample_vars(v6, door)) {
+                Ok(v) => rendered = Some(v),
+                Err(e) => return self.note(what, Some(format!("bad template: {e}"))),
+            }
+        }
+        let (prog, args) = rendered.expect("rendered at least once");
+
+        // for sh -c, have a go at the first word of the script too
+        let mut progs = vec![prog.to_owned()];
+        if let (CommandSpec::Shell(_), Some(script)) = (cmd, args.last()) {
+            if let Some(w) = script.split_whitespace().next() {
+                let plain = w.chars().all(|c| c.is_ascii_alphanumeric() || "-_./+".contains(c));
+                if plain && !SHELL_WORDS.contains(&w) {
+                    progs.push(w.to_string());
+                }
+            }
+        }
+        match progs.iter().find(|p| find_program(p).is_none()) {
+            Some(p) => self.note(what, Some(format!("{p} isn't installed (or isn't in $PATH)"))),
+            None => self.note(what, None),
+        }
+    }
+
+    /// make sure a template (e.g. an allowlist line) only uses variables we
+    /// have, plus `extra`
+    pub fn template(&mut self, what: &str, template: &str, extra: &[&str]) {
+        let mut map = sample_vars(false, "default").to_map();
+        for k in extra {
+            map.insert(k.to_string(), "0".to_string());
+        }
+        match strfmt(template, &map) {
+            Ok(_) => self.note(what, None),
+            Err(e) => self.note(what, Some(format!("bad template: {e}"))),
+        }
+    }
+
+    /// actually run a check-mode command (e.g. `nft -c ...`), with `input` on
+    /// its stdin if there is any
+    pub async fn check(
+        &mut self,
+        what: &str,
+        cmd: &CommandSpec,
+        input: Option<&[u8]>,
+        door: &str,
+        runner: &Runner,
+    ) {
+        let vars = sample_vars(false, door);
+        let problem = match runner.run_with_input(cmd, &vars, input).await {
+            Ok(_) => None,
+            Err(RunError::Failed(o)) => Some(format!(
+                "{} exited {}: {}",
+                cmd.describe(&vars),
+                o.status.code().map_or("on a signal".to_string(), |c| c.to_string()),
+                String::from_utf8_lossy(&o.stderr).trim()
+            )),
+            Err(e) => Some(format!("{}: {}", cmd.describe(&vars), e)),
+        };
+        self.note(what, problem)
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn argv(v: &[&str]) -> CommandSpec {
+        CommandSpec::Argv(v.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn programs() {
+        assert!(find_program("sh").is_some());
+        assert!(find_program("/bin/sh").is_some());
+        assert!(find_program("/etc/passwd").is_none());
+        assert!(find_program("definitely-not-a-real-program").is_none());
+    }
+
+    #[test]
+    fn commands() {
+        let mut p = Preflight::new();
+
+        p.command("good argv", &argv(&["sh", "-c", "echo {ip} {family}"]), "default");
+        p.command(
+            "good shell",
+            &CommandSpec::Shell("echo {{ {ip} }}".to_string()),
+            "default",
+        );
+        p.command(
+            "builtin",
+            &CommandSpec::Shell("if true; then :; fi".to_string()),
+            "default",
+        );
+        assert!(p.ok());
+
+        p.command("bad var", &argv(&["sh", "-c", "echo {address}"]), "default");
+        p.command(
+            "no such program",
+            &argv(&["definitely-not-a-real-program", "{ip}"]),
+            "default",
+        );
+        p.command(
+            "no such program in sh",
+            &CommandSpec::Shell("nope-not-here {ip}".to_string()),
+            "default",
+        );
+        p.template("bad line", "allow {ip}; # {nope}", &["expires"]);
+        p.template("good line", "allow {ip}; # {expires}", &["expires"]);
+
+        let problems = p.problems().iter().map(|f| f.what.to_owned()).collect::<Vec<String>>();
+        assert_eq!(
+            problems,
+            vec!["bad var", "no such program", "no such program in sh", "bad line"]
+        );
+        assert!(p.problems()[0].problem.as_ref().unwrap().starts_with("bad template"));
+    }
+
+    #[tokio::test]
+    async fn checks() {
+        let mut p = Preflight::new();
+        let r = Runner::new(1, Duration::from_secs(5));
+
+        p.check(
+            "fine",
+            &argv(&["sh", "-c", "test {ip} = 192.0.2.1"]),
+            None,
+            "default",
+            &r,
+        )
+        .await;
+        p.check(
+            "stdin",
+            &argv(&["grep", "-q", "table"]),
+            Some(b"table inet x\n"),
+            "default",
+            &r,
+        )
+        .await;
+        assert!(p.ok());
+
+        p.check(
+            "broken",
+            &argv(&["sh", "-c", "echo no >&2; exit 1"]),
+            None,
+            "default",
+            &r,
+        )
+        .await;
+        assert_eq!(
+            p.problems()[0].problem.as_deref(),
+            Some("[\"sh\", \"-c\", \"echo no >&2; exit 1\"] exited 1: no")
+        );
+    }
+}
@@ -0,0 +1,716 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, ABI,
+};
+use nix::unistd::{getgid, getuid, setgid, setgroups, setuid, Gid, Group, Uid, User};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tracing::{debug, error, info, warn};
+
+use crate::command::{CommandSpec, KnockVars, Runner};
+use crate::doors::{Door, Doors};
+use crate::payload::valid_token;
+use crate::pipeline::{PipelineResult, StepResult};
+use crate::ratelimit::{prefix_of, RateLimitSettings};
+use crate::unix_now;
+
+/// The `[privsep]` section of door's config.
+///
+/// With privsep on, door forks at startup. The child stays root and runs the
+/// door pipelines and ban commands, but only when asked to over a socketpair,
+/// and only for requests that make sense for the config (a door that exists,
+/// a real IP, a duration the door allows, ...). The parent, which is the part
+/// that reads knocks off the network, drops to `user` and `group` and locks
+/// itself down with Landlock and seccomp before it hears its first knock.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct PrivsepSettings {
+    pub enabled: bool,
+    pub user: String,
+    /// defaults to the user's primary group
+    pub group: Option<String>,
+    pub landlock: bool,
+    pub seccomp: bool,
+    /// extra paths the listener may write to (e.g., for hooks); the
+    /// allowlist files' directories are always writable
+    pub paths: Vec<String>,
+}
+
+impl Default for PrivsepSettings {
+    fn default() -> Self {
+        PrivsepSettings {
+            enabled: false,
+            user: "nobody".to_string(),
+            group: None,
+            landlock: true,
+            seccomp: true,
+            paths: vec![],
+        }
+    }
+}
+
+/// What the listener can ask the helper to do.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    /// run vars.door's pipeline
+    Grant { vars: KnockVars },
+    /// run vars.door's pipeline in extend mode
+    Extend { vars: KnockVars },
+    /// run the undo commands of vars.door's pipeline
+    Revoke { vars: KnockVars },
+    /// run the rate limiter's ban_command
+    Ban { prefix: IpAddr, port: u16, duration: u64 },
+    /// run the rate limiter's unban_command
+    Unban { prefix: IpAddr, port: u16 },
+    /// re-read the config and use its doors and rate limits from now on
+    Reload,
+}
+
+impl Request {
+    fn op(&self) -> &'static str {
+        match self {
+            Request::Grant { .. } => "grant",
+            Request::Extend { .. } => "extend",
+            Request::Revoke { .. } => "revoke",
+            Request::Ban { .. } => "ban",
+            Request::Unban { .. } => "unban",
+            Request::Reload => "reload",
+        }
+    }
+}
+
+/// one line on the socket, listener → helper
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    id: u64,
+    #[serde(flatten)]
+    request: Request,
+}
+
+/// one line on the socket, helper → listener
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Reply {
+    pub id: u64,
+    pub success: bool,
+    /// why the helper wouldn't (or couldn't) do it at all
+    pub error: Option<String>,
+    pub steps: Vec<StepResult>,
+}
+
+impl Reply {
+    fn refused(id: u64, error: String) -> Self {
+        Reply {
+            id,
+            success: false,
+            error: Some(error),
+            steps: vec![],
+        }
+    }
+
+    pub fn into_result(self) -> PipelineResult {
+        PipelineResult {
+            steps: self.steps,
+            success: self.success,
+        }
+    }
+}
+
+/// What the helper checks requests against; a reload swaps in a new one.
+#[derive(Clone)]
+pub struct Rules {
+    pub doors: Doors,
+    pub rate_limit: RateLimitSettings,
+    /// keepalive can extend a grant by this much even when the door's own
+    /// max duration is shorter
+    pub max_extend: u64,
+}
+
+/// how the helper gets new rules (or why it couldn't) for Request::Reload
+pub type Reloader = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<Rules, String>> + Send>> + Send + Sync>;
+
+/// The privileged side: runs what the listener asks for, if it checks out.
+#[derive(Clone)]
+pub struct Helper {
+    rules: Arc<RwLock<Arc<Rules>>>,
+    reloader: Option<Reloader>,
+    runner: Runner,
+}
+
+impl Helper {
+    pub fn new(doors: Doors, rate_limit: RateLimitSettings, max_extend: u64, runner: Runner) -> Self {
+        Helper {
+            rules: Arc::new(RwLock::new(Arc::new(Rules {
+                doors,
+                rate_limit,
+                max_extend,
+            }))),
+            reloader: None,
+            runner,
+        }
+    }
+
+    /// without one of these, the helper refuses to reload
+    pub fn with_reloader(mut self, reloader: Reloader) -> Self {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    fn rules(&self) -> Arc<Rules> {
+        self.rules.read().expect("rules lock").clone()
+    }
+
+    /// check everything from now on against `rules`; requests already being
+    /// handled finish with the old ones
+    pub fn replace(&self, rules: Rules) {
+        *self.rules.write().expect("rules lock") = Arc::new(rules);
+    }
+
+    /// the door these vars are for, if everything in them is something the
+    /// listener could have come up with from a verified knock
+    fn door_for(&self, vars: &KnockVars, max: impl Fn(&Door) -> u64) -> Result<Arc<Door>, String> {
+        let door = self
+            .rules()
+            .doors
+            .get(&vars.door)
+            .cloned()
+            .ok_or_else(|| format!("there's no door {:?}", vars.door))?;
+        let ip = vars
+            .ip
+            .parse::<IpAddr>()
+            .map_err(|_| format!("{:?} isn't an IP", vars.ip))?;
+        if ip.to_string() != vars.ip {
+            return Err(format!("{:?} isn't written the usual way", vars.ip));
+        }
+        let family = if ip.is_ipv4() { "ipv4" } else { "ipv6" };
+        if vars.family != family {
+            return Err(format!("{} isn't {}", vars.ip, vars.family));
+        }
+        if vars.prefix != format!("{}/{}", ip, if ip.is_ipv4() { 32 } else { 128 }) {
+            return Err(format!("{:?} isn't {}'s prefix", vars.prefix, vars.ip));
+        }
+        for (what, v) in [("identity", &vars.identity), ("listener", &vars.listener)] {
+            if !valid_token(v) {
+                return Err(format!("{v:?} isn't a valid {what}"));
+            }
+        }
+        if !door.policy.allows(Some(&vars.identity)) {
+            return Err(format!("{} can't use door {}", vars.identity, door.name));
+        }
+        if vars.duration > max(&door) {
+            return Err(format!("{}s is longer than door {} allows", vars.duration, door.name));
+        }
+        Ok(door)
+    }
+
+    /// the vars for a ban command, if the limiter could have asked for it
+    fn ban_vars(&self, prefix: IpAddr, port: u16, duration: u64, unban: bool) -> Result<KnockVars, String> {
+        let rl = &self.rules().rate_limit;
+        let (command, name) = match unban {
+            false => (&rl.ban_command, "ban_command"),
+            true => (&rl.unban_command, "unban_command"),
+        };
+        if command.is_none() {
+            return Err(format!("there's no {name}"));
+        }
+        if duration > rl.ban_time {
+            return Err(format!("{duration}s is longer than ban_time"));
+        }
+        // door only ever bans whole networks, so anything else is made up
+        if prefix_of(prefix, rl.ipv4_prefix, rl.ipv6_prefix) != prefix {
+            return Err(format!("{prefix} isn't a /{} network", rl.prefix_len(prefix)));
+        }
+        let local = SocketAddr::new(IpAddr::from([0, 0, 0, 0]), port);
+        Ok(rl.ban_vars(prefix, &local, duration, unix_now()))
+    }
+
+    /// check a request and, if it's alright, do it
+    pub async fn handle(&self, request: &Request) -> Result<PipelineResult, String> {
+        let identity_max = |d: &Door, vars: &KnockVars| d.policy.max_for(Some(&vars.identity));
+        match request {
+            Request::Grant { vars } => {
+                let door = self.door_for(vars, |d| identity_max(d, vars))?;
+                Ok(door.pipeline.run(vars, &door.runner(&self.runner)).await)
+            }
+            Request::Extend { vars } => {
+                let max_extend = self.rules().max_extend;
+                let door = self.door_for(vars, |d| identity_max(d, vars).max(max_extend))?;
+                Ok(door.pipeline.extend(vars, &door.runner(&self.runner)).await)
+            }
+            Request::Revoke { vars } => {
+                let door = self.door_for(vars, |_| u64::MAX)?;
+                Ok(door.pipeline.revoke(vars, &door.runner(&self.runner)).await)
+            }
+            Request::Ban { prefix, port, duration } => {
+                let vars = self.ban_vars(*prefix, *port, *duration, false)?;
+                let rules = self.rules();
+                let command = rules.rate_limit.ban_command.as_ref().expect("checked by ban_vars");
+                Ok(self.run_one("ban_command", command, &vars).await)
+            }
+            Request::Unban { prefix, port } => {
+                let vars = self.ban_vars(*prefix, *port, 0, true)?;
+                let rules = self.rules();
+                let command = rules.rate_limit.unban_command.as_ref().expect("checked by ban_vars");
+                Ok(self.run_one("unban_command", command, &vars).await)
+            }
+            Request::Reload => {
+                let reloader = self.reloader.as_ref().ok_or("reloading isn't set up")?;
+                self.replace(reloader().await?);
+                info!("helper: reloaded");
+                Ok(PipelineResult {
+                    steps: vec![],
+                    success: true,
+                })
+            }
+        }
+    }
+
+    async fn run_one(&self, name: &str, command: &CommandSpec, vars: &KnockVars) -> PipelineResult {
+        let start = std::time::Instant::now();
+        let error = self.runner.run(command, vars).await.err();
+        if let Some(e) = &error {
+            error!("fail({}) {}", command.describe(vars), e);
+        }
+        PipelineResult {
+            success: error.is_none(),
+            steps: vec![StepResult {
+                name: name.to_string(),
+                command: command.describe(vars),
+                rollback: false,
+                status: None,
+                error: error.map(|e| e.to_string()),
+                elapsed: start.elapsed(),
+            }],
+        }
+    }
+
+    /// answer requests until the listener goes away; requests are handled
+    /// concurrently (the runner still limits how many commands run at once),
+    /// and the ones in flight when it goes are finished before this returns
+    pub async fn serve(self: Arc<Self>, stream: UnixStream) {
+        let (rd, wr) = stream.into_split();
+        let (tx, mut rx) = mpsc::channel::<Reply>(64);
+        let writer = task::spawn(async move {
+            let mut wr = wr;
+            while let Some(reply) = rx.recv().await {
+                if let Err(e) = write_line(&mut wr, &reply).await {
+                    warn!("helper: couldn't reply: {}", e);
+                }
+            }
+        });
+
+        let mut lines = BufReader::new(rd).lines();
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(v)) => v,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("helper: couldn't read a request: {}", e);
+                    break;
+                }
+            };
+            let envelope = match serde_json::from_str::<Envelope>(&line) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("helper: garbled request: {}", e);
+                    continue;
+                }
+            };
+            let (me, tx) = (self.clone(), tx.clone());
+            task::spawn(async move {
+                let Envelope { id, request } = envelope;
+                let reply = match me.handle(&request).await {
+                    Ok(res) => Reply {
+                        id,
+                        success: res.success,
+                        error: None,
+                        steps: res.steps,
+                    },
+                    Err(e) => {
+                        warn!("helper: refused {} {:?}: {}", request.op(), request, e);
+                        Reply::refused(id, e)
+                    }
+                };
+                let _ = tx.send(reply).await;
+            });
+        }
+        debug!("helper: listener's gone");
+        drop(tx);
+        let _ = writer.await;
+    }
+}
+
+async fn write_line<T: Serialize>(wr: &mut OwnedWriteHalf, v: &T) -> io::Result<()> {
+    let mut line = serde_json::to_vec(v)?;
+    line.push(b'\n');
+    wr.write_all(&line).await
+}
+
+/// The unprivileged side's handle on the helper.
+#[derive(Debug, Clone)]
+pub struct HelperClient {
+    writer: Arc<tokio::sync::Mutex<OwnedWriteHalf>>,
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Reply>>>>,
+    next: Arc<AtomicU64>,
+}
+
+impl HelperClient {
+    /// take over our end of the socketpair; must be called from within the runtime
+    pub fn new(stream: UnixStream) -> Self {
+        let (rd, wr) = stream.into_split();
+        let pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Reply>>>> = Default::default();
+
+        let writer = Arc::new(tokio::sync::Mutex::new(wr));
+        let (waiting, hung_up) = (pending.clone(), Arc::downgrade(&writer));
+        task::spawn(async move {
+            let mut lines = BufReader::new(rd).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                match serde_json::from_str::<Reply>(&line) {
+                    Ok(reply) => {
+                        if let Some(tx) = waiting.lock().expect("pending lock").remove(&reply.id) {
+                            let _ = tx.send(reply);
+                        }
+                    }
+                    Err(e) => error!("garbled reply from the helper: {}", e),
+                }
+            }
+            // it's only news if we weren't the ones who hung up
+            match hung_up.upgrade() {
+                Some(_) => error!("the helper's gone"),
+                None => debug!("closed the helper"),
+            }
+            // dropping the senders fails whatever's still waiting
+            waiting.lock().expect("pending lock").clear();
+        });
+
+        HelperClient {
+            writer,
+            pending,
+            next: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    /// ask the helper to do something and wait for it to be done (or refused)
+    pub async fn request(&self, request: Request) -> Reply {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().expect("pending lock").insert(id, tx);
+
+        let sent = write_line(&mut *self.writer.lock().await, &Envelope { id, request }).await;
+        if let Err(e) = sent {
+            self.pending.lock().expect("pending lock").remove(&id);
+            return Reply::refused(id, format!("couldn't reach the helper: {e}"));
+        }
+        match rx.await {
+            Ok(reply) => {
+                if let Some(e) = &reply.error {
+                    error!("the helper refused: {}", e);
+                }
+                reply
+            }
+            Err(_) => Reply::refused(id, "the helper went away".to_string()),
+        }
+    }
+}
+
+/// give up root for `user` (and `group`, or the user's primary group), along
+/// with every supplementary group
+pub fn drop_privileges(user: &str, group: Option<&str>) -> Result<(), String> {
+    let u = User::from_name(user)
+        .map_err(|e| format!("couldn't look up user {user}: {e}"))?
+        .ok_or_else(|| format!("there's no user {user}"))?;
+    let gid = match group {
+        Some(g) => {
+            Group::from_name(g)
+                .map_err(|e| format!("couldn't look up group {g}: {e}"))?
+                .ok_or_else(|| format!("there's no group {g}"))?
+                .gid
+        }
+        None => u.gid,
+    };
+
+    setgroups(&[gid]).map_err(|e| format!("setgroups: {e}"))?;
+    setgid(gid).map_err(|e| format!("setgid({gid}): {e}"))?;
+    setuid(u.uid).map_err(|e| format!("setuid({}): {}", u.uid, e))?;
+
+    // make sure there's no going back
+    if getuid() != u.uid || getgid() != gid {
+        return Err("uid or gid didn't stick".to_string());
+    }
+    if !u.uid.is_root() && (setuid(Uid::from_raw(0)).is_ok() || setgid(Gid::from_raw(0)).is_ok()) {
+        return Err("could still get root back".to_string());
+    }
+    Ok(())
+}
+
+/// limit this process (and whatever it runs) to reading and running things
+/// from the usual system directories (and `readable`), and writing only to
+/// `writable`
+pub fn landlock(readable: &[String], writable: &[String]) -> Result<RulesetStatus, String> {
+    let abi = ABI::V3;
+    let mut ro = ["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc"]
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<String>>();
+    ro.extend(readable.iter().cloned());
+    let mut rw = vec!["/dev/null".to_string()];
+    rw.extend(writable.iter().cloned());
+
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))
+        .and_then(|r| r.create())
+        .and_then(|r| r.add_rules(path_beneath_rules(ro.iter().map(Path::new), AccessFs::from_read(abi))))
+        .and_then(|r| r.add_rules(path_beneath_rules(rw.iter().map(Path::new), AccessFs::from_all(abi))))
+        .and_then(|r| r.restrict_self())
+        .map_err(|e| e.to_string())?;
+    Ok(status.ruleset)
+}
+
+/// syscalls the listener (and whatever it runs) has no business making
+const DENIED_SYSCALLS: &[i64] = &[
+    nix::libc::SYS_ptrace,
+    nix::libc::SYS_process_vm_readv,
+    nix::libc::SYS_process_vm_writev,
+    nix::libc::SYS_mount,
+    nix::libc::SYS_umount2,
+    nix::libc::SYS_pivot_root,
+    nix::libc::SYS_chroot,
+    nix::libc::SYS_unshare,
+    nix::libc::SYS_setns,
+    nix::libc::SYS_setuid,
+    nix::libc::SYS_setgid,
+    nix::libc::SYS_setreuid,
+    nix::libc::SYS_setregid,
+    nix::libc::SYS_setresuid,
+    nix::libc::SYS_setresgid,
+    nix::libc::SYS_setfsuid,
+    nix::libc::SYS_setfsgid,
+    nix::libc::SYS_setgroups,
+    nix::libc::SYS_init_module,
+    nix::libc::SYS_finit_module,
+    nix::libc::SYS_delete_module,
+    nix::libc::SYS_kexec_load,
+    nix::libc::SYS_reboot,
+    nix::libc::SYS_swapon,
+    nix::libc::SYS_swapoff,
+    nix::libc::SYS_bpf,
+    nix::libc::SYS_perf_event_open,
+    nix::libc::SYS_userfaultfd,
+    nix::libc::SYS_keyctl,
+    nix::libc::SYS_add_key,
+    nix::libc::SYS_request_key,
+];
+
+/// make the syscalls in DENIED_SYSCALLS fail with EPERM, for this thread and
+/// any it (or its children) start later
+pub fn seccomp() -> Result<(), String> {
+    use seccompiler::{apply_filter, BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(|e| e.to_string())?;
+    let filter = SeccompFilter::new(
+        DENIED_SYSCALLS.iter().map(|s| (*s, vec![])).collect(),
+        SeccompAction::Allow,
+        SeccompAction::Errno(nix::libc::EPERM as u32),
+        arch,
+    )
+    .map_err(|e| e.to_string())?;
+    let program = BpfProgram::try_from(filter).map_err(|e| e.to_string())?;
+    apply_filter(&program).map_err(|e| e.to_string())
+}
+
+/// everything the listener needs to do after it's forked off the helper:
+/// drop root, then Landlock, then seccomp; `readable` is for what a config
+/// reload reads (the config files, @secret files, ...)
+pub fn lock_down(settings: &PrivsepSettings, readable: &[String], writable: &[String]) -> Result<(), String> {
+    drop_privileges(&settings.user, settings.group.as_deref())?;
+    info!(
+        "privsep: running as {}:{}",
+        settings.user,
+        settings.group.as_deref().unwrap_or("(primary group)")
+    );
+
+    if settings.landlock {
+        let mut paths = settings.paths.to_owned();
+        paths.extend(writable.iter().cloned());
+        match landlock(readable, &paths)? {
+            RulesetStatus::FullyEnforced => info!("privsep: landlock enforced"),
+            RulesetStatus::PartiallyEnforced => warn!("privsep: landlock only partially enforced (old kernel?)"),
+            RulesetStatus::NotEnforced => warn!("privsep: landlock isn't supported here, carrying on without"),
+        }
+    }
+    if settings.seccomp {
+        seccomp()?;
+        info!("privsep: seccomp filter installed");
+    }
+    Ok(())
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::test_vars;
+    use crate::doors::DEFAULT_DOOR;
+    use crate::keyring::Keyring;
+    use crate::pipeline::{Action, Pipeline};
+    use crate::policy::DoorPolicy;
+    use std::time::Duration;
+
+    fn helper() -> Helper {
+        let mut action = Action::new(CommandSpec::Shell("test {ip} = 10.1.2.3".to_string()));
+        action.undo = Some(CommandSpec::Shell("true".to_string()));
+        let door = Door {
+            name: DEFAULT_DOOR.to_string(),
+            keys: Keyring::secret("secret").unwrap(),
+            pipeline: Pipeline::new(vec![action]),
+            policy: DoorPolicy {
+                duration: 5,
+                max_duration: 60,
+                ..Default::default()
+            },
+            run_as: Default::default(),
+            exec: Default::default(),
+        };
+        let rate_limit = RateLimitSettings {
+            ban_time: 300,
+            ban_command: Some(CommandSpec::Shell("true".to_string())),
+            ipv4_prefix: 24,
+            ..Default::default()
+        };
+        Helper::new(
+            Doors::from([(DEFAULT_DOOR.to_string(), Arc::new(door))]),
+            rate_limit,
+            120,
+            Runner::new(1, Duration::from_secs(5)),
+        )
+    }
+
+    #[tokio::test]
+    async fn validation() {
+        let h = helper();
+        let grant = |vars| Request::Grant { vars };
+
+        assert!(h.handle(&grant(test_vars("10.1.2.3", 60))).await.unwrap().success);
+        assert!(!h.handle(&grant(test_vars("10.9.9.9", 5))).await.unwrap().success);
+        assert!(h.handle(&grant(test_vars("10.1.2.3", 61))).await.is_err());
+        assert!(h
+            .handle(&Request::Extend {
+                vars: test_vars("10.1.2.3", 120)
+            })
+            .await
+            .is_ok());
+
+        let mut v = test_vars("10.1.2.3", 5);
+        v.door = "nope".to_string();
+        assert!(h.handle(&grant(v)).await.unwrap_err().contains("no door"));
+
+        for ip in ["10.1.2.3; rm -rf /", "010.1.2.3", "2001:DB8::1"] {
+            let mut v = test_vars("10.1.2.3", 5);
+            v.ip = ip.to_string();
+            assert!(h.handle(&grant(v)).await.is_err(), "{ip}");
+        }
+        let mut v = test_vars("10.1.2.3", 5);
+        v.family = "ipv6".to_string();
+        assert!(h.handle(&grant(v)).await.is_err());
+        let mut v = test_vars("10.1.2.3", 5);
+        v.identity = "$(reboot)".to_string();
+        assert!(h.handle(&grant(v)).await.is_err());
+        let mut v = test_vars("10.1.2.3", 5);
+        v.prefix = "0.0.0.0/0".to_string();
+        assert!(h.handle(&grant(v)).await.is_err());
+
+        let prefix: IpAddr = "10.1.2.0".parse().unwrap();
+        let ban = |duration| Request::Ban {
+            prefix,
+            port: 20022,
+            duration,
+        };
+        assert!(h.handle(&ban(300)).await.unwrap().success);
+        assert!(h.handle(&ban(301)).await.is_err());
+        let host = Request::Ban {
+            prefix: "10.1.2.3".parse().unwrap(),
+            port: 20022,
+            duration: 300,
+        };
+        assert!(h.handle(&host).await.unwrap_err().contains("isn't a /24"));
+        assert!(h.handle(&Request::Unban { prefix, port: 20022 }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reloading() {
+        let prefix: IpAddr = "10.1.2.0".parse().unwrap();
+        let ban = Request::Ban {
+            prefix,
+            port: 20022,
+            duration: 300,
+        };
+        let h = helper();
+        assert!(h.handle(&Request::Reload).await.is_err());
+
+        let rules = h.rules();
+        let h = h.with_reloader(Arc::new(move || {
+            let rules = Rules {
+                rate_limit: Default::default(),
+                ..(*rules).clone()
+            };
+            Box::pin(async move { Ok(rules) })
+        }));
+        assert!(h.handle(&ban).await.unwrap().success);
+        assert!(h.handle(&Request::Reload).await.unwrap().success);
+        assert!(h.handle(&ban).await.unwrap_err().contains("no ban_command"));
+        assert!(
+            h.handle(&Request::Grant {
+                vars: test_vars("10.1.2.3", 5)
+            })
+            .await
+            .unwrap()
+            .success
+        );
+
+        let h = h.with_reloader(Arc::new(|| Box::pin(async { Err("bad config".to_string()) })));
+        assert_eq!(h.handle(&Request::Reload).await.unwrap_err(), "bad config");
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let helper = task::spawn(Arc::new(helper()).serve(theirs));
+        let client = HelperClient::new(ours);
+
+        let (granted, revoked, refused) = tokio::join!(
+            client.request(Request::Grant {
+                vars: test_vars("10.1.2.3", 5)
+            }),
+            client.request(Request::Revoke {
+                vars: test_vars("10.1.2.3", 5)
+            }),
+            client.request(Request::Grant {
+                vars: test_vars("10.1.2.3", 3600)
+            }),
+        );
+        assert!(granted.success);
+        assert_eq!(granted.steps.len(), 1);
+        assert!(revoked.success);
+        assert!(revoked.steps[0].rollback);
+        assert!(!refused.success);
+        assert!(refused.error.unwrap().contains("longer than"));
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), helper)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
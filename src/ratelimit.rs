@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use serde::Deserialize;
+
+use crate::command::{CommandSpec, KnockVars};
+
+/// The `[rate_limit]` section of door's config.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    /// tokens added to each source's bucket per second
+    pub rate: f64,
+    /// the size of each bucket
+    pub burst: f64,
+    /// sources are grouped by prefix, so a whole /64 shares one bucket
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// this many invalid knocks within ban_window seconds gets the source
+    /// banned; 0 (the default) never bans
+    pub ban_threshold: u32,
+    pub ban_window: u64,
+    /// seconds
+    pub ban_time: u64,
+    /// run when a source is banned, e.g. to push it to the firewall; {prefix}
+    /// is the banned prefix (e.g. 10.1.2.0/24), {ip} its address and
+    /// {duration} the ban_time
+    pub ban_command: Option<CommandSpec>,
+    pub unban_command: Option<CommandSpec>,
+    /// how many sources we'll remember at once
+    pub max_sources: usize,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            enabled: false,
+            rate: 2.0,
+            burst: 10.0,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            ban_threshold: 0,
+            ban_window: 60,
+            ban_time: 600,
+            ban_command: None,
+            unban_command: None,
+            max_sources: 10_000,
+        }
+    }
+}
+
+impl RateLimitSettings {
+    /// how long the prefixes `ip`'s family is grouped by are
+    pub fn prefix_len(&self, ip: IpAddr) -> u8 {
+        match ip {
+            IpAddr::V4(_) => self.ipv4_prefix.min(32),
+            IpAddr::V6(_) => self.ipv6_prefix.min(128),
+        }
+    }
+
+    /// the vars for ban_command and unban_command, `prefix` being the
+    /// (already masked) network that's banned
+    pub fn ban_vars(&self, prefix: IpAddr, local: &SocketAddr, duration: u64, now: u64) -> KnockVars {
+        let mut ret = KnockVars::new(&SocketAddr::new(prefix, 0), local, duration, now);
+        ret.prefix = format!("{}/{}", prefix, self.prefix_len(prefix));
+        ret
+    }
+}
+
+/// the network part of `ip`, according to the given prefix lengths
+pub fn prefix_of(ip: IpAddr, v4: u8, v6: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(a) => {
+            let mask = u32::MAX.checked_shl(32 - v4.min(32) as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(a) & mask))
+        }
+        IpAddr::V6(a) => {
+            let mask = u128::MAX.checked_shl(128 - v6.min(128) as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(a) & mask))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Limited,
+    Banned,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Strikes {
+    count: u32,
+    since: Instant,
+}
+
+/// Token buckets and strike counts per source prefix, plus the ban list.
+pub struct Limiter {
+    settings: RateLimitSettings,
+    buckets: LruCache<IpAddr, Bucket>,
+    strikes: LruCache<IpAddr, Strikes>,
+    bans: HashMap<IpAddr, Instant>,
+}
+
+impl Limiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        let size = settings.max_sources.max(1);
+        Limiter {
+            settings,
+            buckets: LruCache::new(size),
+            strikes: LruCache::new(size),
+            bans: HashMap::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &RateLimitSettings {
+        &self.settings
+    }
+
+    /// switch to new settings (e.g., after a config reload); the bans stay,
+    /// and so do the buckets and strikes unless they'd be keyed differently
+    pub fn reconfigure(&mut self, settings: RateLimitSettings) {
+        let old = &self.settings;
+        if (old.ipv4_prefix, old.ipv6_prefix, old.max_sources)
+            != (settings.ipv4_prefix, settings.ipv6_prefix, settings.max_sources)
+        {
+            let size = settings.max_sources.max(1);
+            self.buckets = LruCache::new(size);
+            self.strikes = LruCache::new(size);
+        }
+        self.settings = settings;
+    }
+
+    pub fn key(&self, ip: IpAddr) -> IpAddr {
+        prefix_of(ip, self.settings.ipv4_prefix, self.settings.ipv6_prefix)
+    }
+
+    /// how many sources currently have a bucket
+    pub fn tracked(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn banned(&self) -> usize {
+        self.bans.len()
+    }
+
+    /// should we even look at a datagram from `ip`?
+    pub fn check(&mut self, ip: IpAddr, now: Instant) -> Verdict {
+        if !self.settings.enabled {
+            return Verdict::Allow;
+        }
+
+        let key = self.key(ip);
+        if let Some(until) = self.bans.get(&key) {
+            if *until > now {
+                return Verdict::Banned;
+            }
+        }
+
+        let (rate, burst) = (self.settings.rate, self.settings.burst);
+        let bucket = match self.buckets.get_mut(&key) {
+            Some(b) => b,
+            None => {
+                self.buckets.put(
+                    key,
+                    Bucket {
+                        tokens: burst,
+                        last: now,
+                    },
+                );
+                self.buckets.get_mut(&key).expect("we just put it there")
+            }
+        };
+
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last = now;
+
+        if bucket.tokens < 1.0 {
+            return Verdict::Limited;
+        }
+        bucket.tokens -= 1.0;
+        Verdict::Allow
+    }
+
+    /// count an invalid knock against `ip`; returns the banned prefix if this
+    /// was the one that pushed it over the threshold
+    pub fn strike(&mut self, ip: IpAddr, now: Instant) -> Option<IpAddr> {
+        if !self.settings.enabled || self.settings.ban_threshold == 0 {
+            return None;
+        }
+
+        let key = self.key(ip);
+        let window = Duration::from_secs(self.settings.ban_window);
+        let s = match self.strikes.get_mut(&key) {
+            Some(s) if now.saturating_duration_since(s.since) <= window => s,
+            _ => {
+                self.strikes.put(key, Strikes { count: 0, since: now });
+                self.strikes.get_mut(&key).expect("we just put it there")
+            }
+        };
+        s.count += 1;
+
+        if s.count < self.settings.ban_threshold {
+            return None;
+        }
+        self.strikes.pop(&key);
+        self.bans.insert(key, now + Duration::from_secs(self.settings.ban_time));
+        Some(key)
+    }
+
+    /// forget bans that have run out, returning their prefixes
+    pub fn expire(&mut self, now: Instant) -> Vec<IpAddr> {
+        let done = self
+            .bans
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(k, _)| *k)
+            .collect::<Vec<IpAddr>>();
+        for k in &done {
+            self.bans.remove(k);
+        }
+        done
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(prefix_of(ip("10.1.2.3"), 24, 64), ip("10.1.2.0"));
+        assert_eq!(prefix_of(ip("10.1.2.3"), 32, 64), ip("10.1.2.3"));
+        assert_eq!(prefix_of(ip("10.1.2.3"), 0, 64), ip("0.0.0.0"));
+        assert_eq!(prefix_of(ip("2001:db8:1:2:3:4:5:6"), 32, 64), ip("2001:db8:1:2::"));
+        assert_eq!(
+            prefix_of(ip("2001:db8:1:2:3:4:5:6"), 32, 128),
+            ip("2001:db8:1:2:3:4:5:6")
+        );
+    }
+
+    #[test]
+    fn ban_commands_get_the_prefix() {
+        let rl = RateLimitSettings {
+            ipv4_prefix: 24,
+            ..Default::default()
+        };
+        let local: SocketAddr = "0.0.0.0:20022".parse().unwrap();
+        let ban = CommandSpec::Argv(vec![
+            "nft".to_string(),
+            "add element inet firewall knock_ban {{ {prefix} timeout {duration}s }}".to_string(),
+        ]);
+
+        let vars = rl.ban_vars(ip("2001:db8:1:2::"), &local, 600, 1234);
+        assert_eq!(vars.ip, "2001:db8:1:2::");
+        let (_, args) = ban.render(&vars).unwrap();
+        assert_eq!(
+            args,
+            vec!["add element inet firewall knock_ban { 2001:db8:1:2::/64 timeout 600s }"]
+        );
+
+        let vars = rl.ban_vars(ip("10.1.2.0"), &local, 600, 1234);
+        assert_eq!(vars.prefix, "10.1.2.0/24");
+    }
+
+    #[test]
+    fn token_bucket() {
+        let mut l = Limiter::new(RateLimitSettings {
+            enabled: true,
+            rate: 1.0,
+            burst: 3.0,
+            ..Default::default()
+        });
+        let t0 = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(l.check(ip("10.1.2.3"), t0), Verdict::Allow);
+        }
+        assert_eq!(l.check(ip("10.1.2.3"), t0), Verdict::Limited);
+        assert_eq!(l.check(ip("10.1.2.4"), t0), Verdict::Allow);
+        assert_eq!(
+            l.check(ip("10.1.2.3"), t0 + Duration::from_millis(1100)),
+            Verdict::Allow
+        );
+        assert_eq!(
+            l.check(ip("10.1.2.3"), t0 + Duration::from_millis(1200)),
+            Verdict::Limited
+        );
+        assert_eq!(l.tracked(), 2);
+    }
+
+    #[test]
+    fn bans() {
+        let mut l = Limiter::new(RateLimitSettings {
+            enabled: true,
+            ipv4_prefix: 24,
+            ban_threshold: 3,
+            ban_window: 10,
+            ban_time: 60,
+            ..Default::default()
+        });
+        let t0 = Instant::now();
+
+        assert_eq!(l.strike(ip("10.1.2.3"), t0), None);
+        assert_eq!(l.strike(ip("10.1.2.4"), t0), None);
+        // outside the window, so it starts over
+        assert_eq!(l.strike(ip("10.1.2.5"), t0 + Duration::from_secs(11)), None);
+        assert_eq!(l.strike(ip("10.1.2.5"), t0 + Duration::from_secs(12)), None);
+        assert_eq!(
+            l.strike(ip("10.1.2.5"), t0 + Duration::from_secs(13)),
+            Some(ip("10.1.2.0"))
+        );
+
+        assert_eq!(l.check(ip("10.1.2.99"), t0 + Duration::from_secs(14)), Verdict::Banned);
+        assert_eq!(l.check(ip("10.1.3.99"), t0 + Duration::from_secs(14)), Verdict::Allow);
+        assert!(l.expire(t0 + Duration::from_secs(60)).is_empty());
+        assert_eq!(l.expire(t0 + Duration::from_secs(73)), vec![ip("10.1.2.0")]);
+        assert_eq!(l.check(ip("10.1.2.99"), t0 + Duration::from_secs(74)), Verdict::Allow);
+    }
+
+    #[test]
+    fn reconfiguring() {
+        let settings = RateLimitSettings {
+            enabled: true,
+            ban_threshold: 1,
+            ban_time: 60,
+            ..Default::default()
+        };
+        let mut l = Limiter::new(settings.to_owned());
+        let t0 = Instant::now();
+
+        assert_eq!(l.strike(ip("10.1.2.3"), t0), Some(ip("10.1.2.3")));
+        l.check(ip("10.1.2.4"), t0);
+        assert_eq!(l.tracked(), 1);
+
+        l.reconfigure(RateLimitSettings {
+            ban_time: 120,
+            ..settings.to_owned()
+        });
+        assert_eq!(l.settings().ban_time, 120);
+        assert_eq!(l.tracked(), 1);
+        assert_eq!(l.check(ip("10.1.2.3"), t0), Verdict::Banned);
+
+        l.reconfigure(RateLimitSettings {
+            ipv4_prefix: 24,
+            ..settings
+        });
+        assert_eq!(l.tracked(), 0);
+        assert_eq!(l.banned(), 1);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut l = Limiter::new(RateLimitSettings {
+            enabled: false,
+            burst: 1.0,
+            ban_threshold: 1,
+            ..Default::default()
+        });
+        let t0 = Instant::now();
+
+        assert_eq!(l.strike(ip("10.1.2.3"), t0), None);
+        for _ in 0..10 {
+            assert_eq!(l.check(ip("10.1.2.3"), t0), Verdict::Allow);
+        }
+    }
+}
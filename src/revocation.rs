@@ -0,0 +1,226 @@
+use serde::Deserialize;
+
+use crate::keyring::Keyring;
+use crate::payload::valid_token;
+
+/// A `[[revoked]]` entry in the revocation list: a key id or an identity
+/// (or both, for just that identity's use of that key) that's locked out,
+/// optionally only for one door and only from `at` on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Revocation {
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub door: Option<String>,
+    #[serde(default)]
+    pub reason: String,
+    /// when it was revoked (a unix time); leave it out for "always"
+    #[serde(default)]
+    pub at: Option<u64>,
+}
+
+impl Revocation {
+    fn applies(&self, door: &str, key: &str, identity: Option<&str>, now: u64) -> bool {
+        self.at.is_none_or(|t| t <= now)
+            && self.door.as_deref().is_none_or(|d| d == door)
+            && self.key.as_deref().is_none_or(|k| k == key)
+            && self.identity.as_deref().is_none_or(|i| Some(i) == identity)
+    }
+}
+
+/// The revocation list, which door checks every verified knock against. It
+/// lives in its own file so it can be changed (it's watched) without
+/// touching the config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Revocations {
+    list: Vec<Revocation>,
+    file: Option<String>,
+}
+
+impl Revocations {
+    pub fn new(list: Vec<Revocation>) -> Result<Self, String> {
+        for r in list.iter() {
+            if r.key.is_none() && r.identity.is_none() {
+                return Err("every revocation needs a key or an identity".to_string());
+            }
+            for v in [&r.key, &r.identity, &r.door].into_iter().flatten() {
+                if !valid_token(v) {
+                    return Err(format!("{v:?} can't be in a knock, so there's no revoking it"));
+                }
+            }
+        }
+        Ok(Revocations { list, file: None })
+    }
+
+    /// the `[[revoked]]` entries in `path`
+    pub fn load(path: &str) -> Result<Self, String> {
+        let list = config::Config::builder()
+            .add_source(config::File::new(path, config::FileFormat::Toml))
+            .build()
+            .and_then(|c| match c.get::<Vec<Revocation>>("revoked") {
+                Err(config::ConfigError::NotFound(_)) => Ok(vec![]),
+                v => v,
+            })
+            .map_err(|e| format!("{path}: {e}"))?;
+        let mut ret = Revocations::new(list).map_err(|e| format!("{path}: {e}"))?;
+        ret.file = Some(path.to_string());
+        Ok(ret)
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// read the list again, if it came from a file
+    pub fn reload(&self) -> Result<Self, String> {
+        match &self.file {
+            Some(f) => Revocations::load(f),
+            None => Ok(self.to_owned()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Knocks only say who they're from, so revoking an identity only keeps
+    /// it out of doors where every key belongs to particular identities:
+    /// anywhere else, whoever had the shared secret can just claim to be
+    /// someone else. Those revocations are refused rather than trusted.
+    pub fn enforceable<'a>(&self, doors: impl IntoIterator<Item = (&'a str, &'a Keyring)>) -> Result<(), String> {
+        let doors = doors.into_iter().collect::<Vec<(&str, &Keyring)>>();
+        for r in self.list.iter().filter(|r| r.key.is_none()) {
+            let identity = r.identity.as_deref().unwrap_or_default();
+            let shared = doors
+                .iter()
+                .filter(|(name, _)| r.door.as_deref().is_none_or(|d| d == *name))
+                .find(|(_, keys)| !keys.per_identity());
+            if let Some((name, _)) = shared {
+                return Err(format!(
+                    "door {name} has shared keys, so revoking identity {identity} won't keep it out; \
+                     revoke the key, or give every key its identities"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// the revocation that locks out a knock on `door`, signed with `key`, from
+    /// `identity`, if there is one
+    pub fn check(&self, door: &str, key: &str, identity: Option<&str>, now: u64) -> Option<&Revocation> {
+        self.list.iter().find(|r| r.applies(door, key, identity, now))
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::KeySpec;
+    use crate::HMACFrobnicator;
+
+    fn revoked(key: Option<&str>, identity: Option<&str>, door: Option<&str>, at: Option<u64>) -> Revocation {
+        Revocation {
+            key: key.map(|v| v.to_string()),
+            identity: identity.map(|v| v.to_string()),
+            door: door.map(|v| v.to_string()),
+            reason: "stolen laptop".to_string(),
+            at,
+        }
+    }
+
+    #[test]
+    fn checks() {
+        let r = Revocations::new(vec![
+            revoked(None, Some("alice"), None, Some(1000)),
+            revoked(Some("gen1"), None, Some("git"), None),
+            revoked(Some("gen2"), Some("bob"), None, None),
+        ])
+        .unwrap();
+
+        assert!(r.check("default", "gen2", Some("alice"), 999).is_none());
+        assert!(r.check("default", "gen2", Some("alice"), 1000).is_some());
+        assert!(r.check("default", "gen1", None, 0).is_none());
+        assert!(r.check("git", "gen1", None, 0).is_some());
+        assert!(r.check("default", "gen2", Some("bob"), 0).is_some());
+        assert!(r.check("default", "gen3", Some("bob"), 0).is_none());
+        assert!(r.check("default", "gen2", None, 0).is_none());
+        assert_eq!(r.check("git", "gen1", None, 0).unwrap().reason, "stolen laptop");
+
+        assert!(Revocations::new(vec![revoked(None, None, Some("git"), None)]).is_err());
+        assert!(Revocations::new(vec![revoked(None, Some("a b"), None, None)]).is_err());
+    }
+
+    #[test]
+    fn identities_need_their_own_keys() {
+        let spec = |id: &str, identities: &[&str]| KeySpec {
+            id: id.to_string(),
+            secret: format!("{id} secret"),
+            not_before: None,
+            not_after: None,
+            identities: identities.iter().map(|v| v.to_string()).collect(),
+        };
+        let shared = Keyring::secret("shared secret").unwrap();
+        let own = Keyring::from_specs(&[spec("alice-laptop", &["alice"]), spec("bob-phone", &["bob"])]).unwrap();
+        let alice = Revocations::new(vec![revoked(None, Some("alice"), None, None)]).unwrap();
+
+        // with a shared secret, alice's stolen laptop can knock as bob, and
+        // her revocation never comes into it
+        let key = shared.keys()[0].to_owned();
+        assert!(key.signs_for(Some("bob")));
+        assert!(alice.check("default", &key.id, Some("bob"), 0).is_none());
+        assert!(alice.enforceable([("default", &shared)]).is_err());
+        assert!(alice.enforceable([("default", &own), ("other", &shared)]).is_err());
+
+        // with keys of their own, it can only be alice, who's revoked
+        let signed = HMACFrobnicator::new("alice-laptop secret").sign("7");
+        let (_, key) = own.verify(&signed, 0).unwrap();
+        assert!(!key.signs_for(Some("bob")));
+        assert!(!key.signs_for(None));
+        assert!(key.signs_for(Some("alice")));
+        assert!(alice.check("default", &key.id, Some("alice"), 0).is_some());
+        assert!(alice.enforceable([("default", &own)]).is_ok());
+
+        // a door of their own, or a key, is fine too
+        let git = Revocations::new(vec![revoked(None, Some("alice"), Some("git"), None)]).unwrap();
+        assert!(git.enforceable([("default", &shared), ("git", &own)]).is_ok());
+        let gen1 = Revocations::new(vec![revoked(Some("gen1"), None, None, None)]).unwrap();
+        assert!(gen1.enforceable([("default", &shared)]).is_ok());
+    }
+
+    #[test]
+    fn files() {
+        let dir = std::env::temp_dir().join(format!("rknock-revoked-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("revoked.toml").to_string_lossy().to_string();
+
+        std::fs::write(&path, "").unwrap();
+        let r = Revocations::load(&path).unwrap();
+        assert!(r.is_empty());
+        assert_eq!(r.file(), Some(path.as_str()));
+
+        std::fs::write(
+            &path,
+            r#"
+            [[revoked]]
+            identity = "alice"
+            reason = "laptop stolen"
+            at = 1790000000
+            "#,
+        )
+        .unwrap();
+        let r = r.reload().unwrap();
+        assert_eq!(r.len(), 1);
+        assert!(r.check("default", "secret", Some("alice"), 1790000000).is_some());
+
+        std::fs::write(&path, "[[revoked]]\nreason = \"nobody\"\n").unwrap();
+        assert!(r.reload().is_err());
+        assert!(Revocations::load("/nonexistent/revoked.toml").is_err());
+    }
+}
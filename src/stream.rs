@@ -0,0 +1,208 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::control::{bind_socket, Access};
+use crate::events::Event;
+
+/// where the event stream goes unless the config says otherwise
+pub const DEFAULT_SOCKET: &str = "/run/rknock/events.sock";
+
+/// a subscriber that can't take a line in this long is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The `[events]` section of door's config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct StreamSettings {
+    pub enabled: bool,
+    pub socket: String,
+    /// the socket's permissions
+    pub mode: u32,
+    /// the socket's group; its members may subscribe
+    pub group: Option<String>,
+    /// other users who may subscribe (root, and whoever started door, always can)
+    pub users: Vec<String>,
+    /// how many events a subscriber can fall behind before it's dropped
+    pub buffer: usize,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        StreamSettings {
+            enabled: false,
+            socket: DEFAULT_SOCKET.to_string(),
+            mode: 0o600,
+            group: None,
+            users: vec![],
+            buffer: 256,
+        }
+    }
+}
+
+/// Where door publishes its events (the same documents the hooks get) for
+/// whoever's subscribed. Publishing never waits: a subscriber that falls
+/// `buffer` events behind is dropped. Cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct EventStream {
+    tx: Option<broadcast::Sender<Arc<String>>>,
+}
+
+impl EventStream {
+    pub fn new(buffer: usize) -> Self {
+        let (tx, _) = broadcast::channel(buffer.max(1));
+        EventStream { tx: Some(tx) }
+    }
+
+    pub fn publish(&self, event: &Event) {
+        match &self.tx {
+            Some(tx) if tx.receiver_count() > 0 => {
+                let line = serde_json::to_string(event).expect("events serialize") + "\n";
+                // the only error is that everyone's gone since we checked
+                let _ = tx.send(Arc::new(line));
+            }
+            _ => (),
+        }
+    }
+
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<Arc<String>>> {
+        self.tx.as_ref().map(|tx| tx.subscribe())
+    }
+}
+
+/// set up the event socket and who's allowed to subscribe on it
+pub fn bind(settings: &StreamSettings) -> Result<(std::os::unix::net::UnixListener, Access), String> {
+    let access = Access::new(settings.group.as_deref(), &settings.users)?;
+    let listener = bind_socket(&settings.socket, settings.mode, settings.group.as_deref())?;
+    Ok((listener, access))
+}
+
+/// send a subscriber events until it goes away or can't keep up
+async fn subscriber(mut stream: UnixStream, mut rx: broadcast::Receiver<Arc<String>>) -> Result<(), String> {
+    loop {
+        let line = match rx.recv().await {
+            Ok(v) => v,
+            Err(broadcast::error::RecvError::Lagged(n)) => return Err(format!("fell {n} events behind")),
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        match tokio::time::timeout(WRITE_TIMEOUT, stream.write_all(line.as_bytes())).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err(format!("took more than {}s to take an event", WRITE_TIMEOUT.as_secs())),
+        }
+    }
+}
+
+/// take subscribers on `listener` and send each of them every event from now on
+pub async fn serve(listener: UnixListener, access: Access, events: EventStream) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((v, _)) => v,
+            Err(e) => {
+                warn!("events: couldn't accept: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let cred = match stream.peer_cred() {
+            Ok(v) => v,
+            Err(e) => {
+                debug!("events: {}", e);
+                continue;
+            }
+        };
+        if !access.allows(cred.uid(), cred.gid()) {
+            warn!(
+                "events: uid {} (pid {:?}) isn't allowed to subscribe",
+                cred.uid(),
+                cred.pid()
+            );
+            continue;
+        }
+        let rx = match events.subscribe() {
+            Some(v) => v,
+            None => return,
+        };
+        info!("events: uid {} subscribed", cred.uid());
+        tokio::spawn(async move {
+            match subscriber(stream, rx).await {
+                Ok(()) => (),
+                Err(e) => info!("events: dropped uid {}'s subscription: {}", cred.uid(), e),
+            }
+        });
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventKind;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn subscribing() {
+        let dir = std::env::temp_dir().join(format!("rknock-stream-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("events.sock").to_string_lossy().to_string();
+
+        let events = EventStream::new(4);
+        // nobody's listening, nothing to do
+        events.publish(&Event::new(EventKind::Verify, &"192.0.2.1:1".parse().unwrap()));
+        assert!(EventStream::default().subscribe().is_none());
+
+        let l = bind_socket(&socket, 0o600, None).unwrap();
+        l.set_nonblocking(true).unwrap();
+        let l = UnixListener::from_std(l).unwrap();
+        tokio::spawn(serve(l, Access::new(None, &[]).unwrap(), events.clone()));
+
+        let fast = UnixStream::connect(&socket).await.unwrap();
+        let slow = UnixStream::connect(&socket).await.unwrap();
+        let mut fast = BufReader::new(fast).lines();
+        // give serve() a moment to subscribe them
+        while events.tx.as_ref().unwrap().receiver_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let timeout = Duration::from_secs(5);
+        for port in 1..=20 {
+            let src = format!("192.0.2.1:{port}").parse().unwrap();
+            events.publish(&Event::new(EventKind::Grant, &src));
+            let line = tokio::time::timeout(timeout, fast.next_line())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            let v: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(v["event"], "grant");
+            assert_eq!(v["src_port"], port);
+        }
+
+        // one that's gone is dropped at the next event
+        drop(slow);
+        while events.tx.as_ref().unwrap().receiver_count() > 1 {
+            events.publish(&Event::new(EventKind::Expire, &"192.0.2.1:1".parse().unwrap()));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(tokio::time::timeout(timeout, fast.next_line()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn lagging() {
+        let events = EventStream::new(2);
+        let rx = events.subscribe().unwrap();
+        for port in 1..=5 {
+            events.publish(&Event::new(
+                EventKind::Verify,
+                &format!("192.0.2.1:{port}").parse().unwrap(),
+            ));
+        }
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        assert_eq!(subscriber(ours, rx).await, Err("fell 3 events behind".to_string()));
+    }
+}
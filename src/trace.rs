@@ -0,0 +1,252 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{BatchConfigBuilder, BatchSpanProcessor, SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use serde::Deserialize;
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+use tracing::{Level, Metadata};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::{reload, Registry};
+
+/// a collector that takes longer than this to take a batch doesn't get it
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `[tracing]` section of door's config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct TracingSettings {
+    /// where to send spans (OTLP over HTTP), e.g.
+    /// http://127.0.0.1:4318/v1/traces; empty means nowhere
+    pub otlp: String,
+    pub service_name: String,
+    /// how many finished spans can wait to be sent before new ones are dropped
+    pub queue: usize,
+    /// the most spans sent in one request
+    pub batch: usize,
+    /// how often to send whatever's waiting, in ms
+    pub flush_ms: u64,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        TracingSettings {
+            otlp: String::new(),
+            service_name: "rknock-door".to_string(),
+            queue: 2048,
+            batch: 512,
+            flush_ms: 1000,
+        }
+    }
+}
+
+impl TracingSettings {
+    /// otlp, with the usual /v1/traces on the end if it's just a host
+    pub fn endpoint(&self) -> Result<String, String> {
+        let rest = match self.otlp.split_once("://") {
+            Some(("http" | "https", rest)) if !rest.is_empty() && !rest.starts_with('/') => rest,
+            _ => return Err(format!("{:?} isn't an http:// or https:// URL", self.otlp)),
+        };
+        Ok(match rest.contains('/') {
+            true => self.otlp.to_owned(),
+            false => format!("{}/v1/traces", self.otlp),
+        })
+    }
+
+    /// Start sending spans to the collector, a batch at a time from a thread
+    /// of the exporter's own. If the collector's down or behind and the queue
+    /// fills up, spans are dropped rather than held onto.
+    pub fn provider(&self) -> Result<SdkTracerProvider, String> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(self.endpoint()?)
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let batches = BatchConfigBuilder::default()
+            .with_max_queue_size(self.queue.max(1))
+            .with_max_export_batch_size(self.batch.max(1))
+            .with_scheduled_delay(Duration::from_millis(self.flush_ms.max(1)))
+            .build();
+        Ok(SdkTracerProvider::builder()
+            .with_span_processor(BatchSpanProcessor::builder(exporter).with_batch_config(batches).build())
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(self.service_name.to_owned())
+                    .build(),
+            )
+            .build())
+    }
+}
+
+/// the OTLP layer, once there's somewhere to send spans
+pub type Otlp = Option<OpenTelemetryLayer<Registry, SdkTracer>>;
+pub type OtlpHandle = reload::Handle<Otlp, Registry>;
+
+/// An empty place for the OTLP layer, to go on the registry first. It's
+/// filled in later by `plug_in`: the exporter has threads of its own, which
+/// can't be going yet when door forks off its privsep helper.
+pub fn slot() -> (reload::Layer<Otlp, Registry>, OtlpHandle) {
+    reload::Layer::new(None)
+}
+
+/// send spans through `provider` from now on
+pub fn plug_in(handle: &OtlpHandle, provider: &SdkTracerProvider) -> Result<(), String> {
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("rknock"));
+    handle.reload(Some(layer)).map_err(|e| e.to_string())
+}
+
+/// Where tracing-subscriber's fmt layer writes log lines with --syslog: each
+/// one goes to the daemon facility with the level of the event it's for.
+#[derive(Clone)]
+pub struct Syslog {
+    logger: Arc<Mutex<Logger<LoggerBackend, Formatter3164>>>,
+}
+
+impl Syslog {
+    pub fn new(process: &str) -> io::Result<Self> {
+        let formatter = Formatter3164 {
+            facility: Facility::LOG_DAEMON,
+            process: process.into(),
+            hostname: None,
+            pid: 0,
+        };
+        let logger = syslog::unix(formatter).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Syslog {
+            logger: Arc::new(Mutex::new(logger)),
+        })
+    }
+
+    fn line(&self, level: Level) -> SyslogLine {
+        SyslogLine {
+            logger: self.logger.clone(),
+            level,
+            buf: vec![],
+        }
+    }
+}
+
+/// one log line, sent to syslog once it's all been written
+pub struct SyslogLine {
+    logger: Arc<Mutex<Logger<LoggerBackend, Formatter3164>>>,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl Write for SyslogLine {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogLine {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.buf);
+        let line = line.trim_end();
+        if line.is_empty() {
+            return;
+        }
+        let mut logger = self.logger.lock().unwrap_or_else(|e| e.into_inner());
+        // nowhere to complain to if syslog itself is gone
+        let _ = match self.level {
+            Level::ERROR => logger.err(line),
+            Level::WARN => logger.warning(line),
+            Level::INFO => logger.info(line),
+            _ => logger.debug(line),
+        };
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogLine;
+
+    fn make_writer(&'a self) -> SyslogLine {
+        self.line(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> SyslogLine {
+        self.line(*meta.level())
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::Status;
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use tracing::field::Empty;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn endpoints() {
+        let at = |otlp: &str| {
+            TracingSettings {
+                otlp: otlp.to_string(),
+                ..Default::default()
+            }
+            .endpoint()
+        };
+        assert_eq!(at("http://127.0.0.1:4318").unwrap(), "http://127.0.0.1:4318/v1/traces");
+        assert_eq!(at("https://collector/x").unwrap(), "https://collector/x");
+        assert_eq!(at("http://[::1]:4318").unwrap(), "http://[::1]:4318/v1/traces");
+        assert!(at("ftp://collector").is_err());
+        assert!(at("http:///v1/traces").is_err());
+        assert!(at("collector:4318").is_err());
+        assert!(TracingSettings::default().provider().is_err());
+    }
+
+    #[test]
+    fn spans() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let (layer, handle) = slot();
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            // nothing's listening yet
+            drop(info_span!("nowhere"));
+            plug_in(&handle, &provider).unwrap();
+
+            let root = info_span!(
+                "datagram",
+                src = "192.0.2.1:1234",
+                identity = Empty,
+                otel.status_code = Empty
+            );
+            root.in_scope(|| drop(info_span!("verify")));
+            root.record("identity", "alice");
+            root.record("otel.status_code", "ERROR");
+        });
+        provider.force_flush().unwrap();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        assert_eq!(
+            spans.iter().map(|s| s.name.as_ref()).collect::<Vec<&str>>(),
+            vec!["verify", "datagram"]
+        );
+        let (verify, root) = (&spans[0], &spans[1]);
+        assert_eq!(verify.span_context.trace_id(), root.span_context.trace_id());
+        assert_eq!(verify.parent_span_id, root.span_context.span_id());
+        let attr = |k: &str| {
+            root.attributes
+                .iter()
+                .find(|a| a.key.as_str() == k)
+                .map(|a| a.value.to_string())
+        };
+        assert_eq!(attr("src").as_deref(), Some("192.0.2.1:1234"));
+        assert_eq!(attr("identity").as_deref(), Some("alice"));
+        assert!(matches!(root.status, Status::Error { .. }));
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use tokio::io::unix::AsyncFd;
+use tracing::{debug, warn};
+
+/// changes that land within this long of each other are reported together
+/// (e.g., an editor writing a file and then renaming it into place)
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// AsyncFd wants AsRawFd, which nix's Inotify doesn't have
+struct Fd(Inotify);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_fd().as_raw_fd()
+    }
+}
+
+/// Tells door when files it read at startup (secrets and the like) have
+/// changed. The directories are watched rather than the files, so a file
+/// that's replaced (renamed over, or deleted and written again) still counts.
+pub struct FileWatcher {
+    inotify: Option<AsyncFd<Fd>>,
+    /// per watched directory: the file names we care about, and the paths
+    /// they were given as
+    dirs: HashMap<WatchDescriptor, Vec<(OsString, String)>>,
+    /// changes read but not reported yet, which keeps changed() cancel safe
+    pending: Vec<String>,
+}
+
+impl FileWatcher {
+    /// watch `files`; with none, changed() never returns
+    pub fn new(files: &[String]) -> io::Result<Self> {
+        let mut ret = FileWatcher {
+            inotify: None,
+            dirs: HashMap::new(),
+            pending: vec![],
+        };
+        if files.is_empty() {
+            return Ok(ret);
+        }
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        for f in files {
+            let path = Path::new(f);
+            let name = match path.file_name() {
+                Some(v) => v.to_owned(),
+                None => {
+                    warn!("can't watch {}, it isn't a file", f);
+                    continue;
+                }
+            };
+            let dir = match path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p,
+                _ => Path::new("."),
+            };
+            let wd = inotify
+                .add_watch(
+                    dir,
+                    AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_DELETE,
+                )
+                .map_err(|e| io::Error::new(io::Error::from(e).kind(), format!("{}: {}", dir.display(), e)))?;
+            debug!("watching {}", f);
+            ret.dirs.entry(wd).or_default().push((name, f.to_owned()));
+        }
+        ret.inotify = Some(AsyncFd::new(Fd(inotify))?);
+        Ok(ret)
+    }
+
+    /// the watched files that changed since the last call, once they've
+    /// settled; this is cancel safe, so it can go in a select!
+    pub async fn changed(&mut self) -> Vec<String> {
+        let inotify = match &self.inotify {
+            Some(v) => v,
+            None => return std::future::pending().await,
+        };
+        loop {
+            if !self.pending.is_empty() {
+                // let the rest of the change land, then take whatever it set off
+                tokio::time::sleep(SETTLE).await;
+                if let Ok(events) = inotify.get_ref().0.read_events() {
+                    note(&self.dirs, &mut self.pending, events);
+                }
+                let mut ret = std::mem::take(&mut self.pending);
+                ret.sort();
+                return ret;
+            }
+
+            let mut guard = match inotify.readable().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("couldn't watch for file changes: {}", e);
+                    return std::future::pending().await;
+                }
+            };
+            let events = match guard.try_io(|fd| fd.get_ref().0.read_events().map_err(io::Error::from)) {
+                Ok(Ok(v)) => v,
+                Ok(Err(e)) => {
+                    warn!("couldn't read file changes: {}", e);
+                    continue;
+                }
+                Err(_would_block) => continue,
+            };
+            note(&self.dirs, &mut self.pending, events);
+        }
+    }
+}
+
+/// add the watched files `events` are about to `pending`
+fn note(
+    dirs: &HashMap<WatchDescriptor, Vec<(OsString, String)>>,
+    pending: &mut Vec<String>,
+    events: Vec<InotifyEvent>,
+) {
+    for ev in events {
+        let files = dirs.get(&ev.wd).into_iter().flatten();
+        for (_, path) in files.filter(|(n, _)| Some(n) == ev.name.as_ref()) {
+            if !pending.contains(path) {
+                pending.push(path.to_owned());
+            }
+        }
+    }
+}
+
+//---------=: TEST
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn changes() {
+        let dir = std::env::temp_dir().join(format!("rknock-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "one").unwrap();
+        fs::write(&b, "one").unwrap();
+        let (a, b) = (a.to_string_lossy().to_string(), b.to_string_lossy().to_string());
+
+        let mut w = FileWatcher::new(&[a.to_owned(), b.to_owned()]).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        fs::write(dir.join("unrelated"), "x").unwrap();
+        fs::write(&a, "two").unwrap();
+        assert_eq!(
+            tokio::time::timeout(timeout, w.changed()).await.unwrap(),
+            vec![a.to_owned()]
+        );
+
+        // replaced rather than written
+        fs::write(dir.join("b.new"), "two").unwrap();
+        fs::rename(dir.join("b.new"), &b).unwrap();
+        assert_eq!(
+            tokio::time::timeout(timeout, w.changed()).await.unwrap(),
+            vec![b.to_owned()]
+        );
+
+        let mut none = FileWatcher::new(&[]).unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(50), none.changed())
+            .await
+            .is_err());
+        assert!(FileWatcher::new(&["/nonexistent/dir/secret".to_string()]).is_err());
+    }
+}